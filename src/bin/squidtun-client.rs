@@ -0,0 +1,242 @@
+extern crate clap;
+extern crate futures;
+extern crate squidtun;
+extern crate tokio_core;
+extern crate tokio_signal;
+
+#[macro_use]
+extern crate log;
+extern crate simple_logger;
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg};
+use futures::{Future, Stream};
+use futures::future::Either;
+use futures::sync::oneshot;
+use log::Level;
+use squidtun::{ClientProfile, KeyPair, TunnelClient};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle, Interval};
+use tokio_signal::unix::{SIGINT, SIGTERM, Signal};
+
+/// How long to let open sessions finish when shutting down.
+const CLOSE_TIME: Duration = Duration::from_secs(5);
+
+fn main() {
+    simple_logger::init_with_level(Level::Info).unwrap();
+
+    let matches = App::new("squidtun-server")
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .value_name("FILE")
+            .help("Read profiles from a TOML file [default: ~/.config/squidtun/client.toml]")
+            .takes_value(true)
+            .requires("profile"))
+        .arg(Arg::with_name("profile")
+            .short("P")
+            .long("profile")
+            .value_name("NAME")
+            .help("Use the settings of a profile, which the other flags override")
+            .takes_value(true))
+        .arg(Arg::with_name("password")
+            .short("p")
+            .long("password")
+            .value_name("VALUE")
+            .help("Set the password to make connections")
+            .takes_value(true))
+        .arg(Arg::with_name("user")
+            .short("u")
+            .long("user")
+            .value_name("NAME")
+            .help("Set the user to log in as, if the server has per-user credentials")
+            .takes_value(true))
+        .arg(Arg::with_name("key")
+            .short("k")
+            .long("key")
+            .value_name("FILE")
+            .help("Log in with the private key in a file instead of a password")
+            .takes_value(true)
+            .requires("user"))
+        .arg(Arg::with_name("generate-key")
+            .long("generate-key")
+            .value_name("FILE")
+            .help("Write a new private key to a file and print the server's line for it")
+            .takes_value(true)
+            .requires("user"))
+        .arg(Arg::with_name("local-addr")
+            .short("l")
+            .long("local-address")
+            .value_name("IP:PORT")
+            .help("Set the local port to proxy")
+            .takes_value(true))
+        .arg(Arg::with_name("pipeline")
+            .long("pipeline")
+            .value_name("COUNT")
+            .help("Set the number of requests to keep in flight in each direction")
+            .takes_value(true))
+        .arg(Arg::with_name("stream-download")
+            .long("stream-download")
+            .help("Receive data through long-lived streaming responses"))
+        .arg(Arg::with_name("stream-upload")
+            .long("stream-upload")
+            .help("Send data through streaming requests when the proxy allows it"))
+        .arg(Arg::with_name("websocket")
+            .long("websocket")
+            .help("Carry sessions over WebSockets when the proxy allows it"))
+        .arg(Arg::with_name("connect-port")
+            .long("connect-port")
+            .value_name("PORT")
+            .help("Reach the server with CONNECT requests to this port when the proxy allows it")
+            .takes_value(true))
+        .arg(Arg::with_name("proxy-addr")
+            .help("Set the IP:PORT of the proxy")
+            .required_unless_one(&["generate-key", "profile"])
+            .index(1))
+        .arg(Arg::with_name("host")
+            .help("Set the hostname to query through the proxy")
+            .required_unless_one(&["generate-key", "profile"])
+            .index(2))
+        .get_matches();
+
+    if let Some(path) = matches.value_of("generate-key") {
+        let key = KeyPair::generate().expect("Failed to generate key.");
+        key.save(path).expect("Failed to save key.");
+        println!("{}", key.public_line(matches.value_of("user").unwrap()));
+        return;
+    }
+
+    let mut profile = match matches.value_of("profile") {
+        Some(name) => {
+            let path = matches.value_of("config").map(PathBuf::from).unwrap_or_else(config_path);
+            ClientProfile::load(&path, name).unwrap_or_else(|e| {
+                error!("failed to load profile {}: {}", name, e);
+                process::exit(1);
+            })
+        },
+        None => ClientProfile::default()
+    };
+
+    // Flags take precedence over the profile.
+    if let Some(addr) = matches.value_of("proxy-addr") {
+        profile.proxy = Some(addr.parse().unwrap());
+    }
+    if let Some(host) = matches.value_of("host") {
+        profile.host = Some(host.to_owned());
+    }
+    if let Some(password) = matches.value_of("password") {
+        profile.password = Some(password.to_owned());
+    }
+    if let Some(addr) = matches.value_of("local-addr") {
+        profile.forwards = vec![addr.parse().unwrap()];
+    } else if profile.forwards.is_empty() {
+        profile.forwards = vec!["127.0.0.1:2222".parse().unwrap()];
+    }
+    let options = &mut profile.options;
+    if let Some(pipeline) = matches.value_of("pipeline") {
+        options.pipeline = pipeline.parse().unwrap();
+    }
+    options.stream_download |= matches.is_present("stream-download");
+    options.stream_upload |= matches.is_present("stream-upload");
+    options.websocket |= matches.is_present("websocket");
+    if let Some(port) = matches.value_of("connect-port") {
+        options.connect_port = Some(port.parse().unwrap());
+    }
+    if let Some(user) = matches.value_of("user") {
+        options.user = Some(user.to_owned());
+    }
+    if let Some(path) = matches.value_of("key") {
+        options.key = Some(KeyPair::load(path).expect("Failed to load key."));
+    }
+
+    let (proxy_addr, host) = match (profile.proxy, profile.host) {
+        (Some(proxy_addr), Some(host)) => (proxy_addr, host),
+        _ => {
+            error!("the profile needs a proxy and a host");
+            process::exit(1);
+        }
+    };
+    if options.key.is_some() && options.user.is_none() {
+        error!("a key needs a user");
+        process::exit(1);
+    }
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let password = profile.password.unwrap_or_default();
+    let client = TunnelClient::new(&handle, proxy_addr, &host, &password, profile.options);
+    // Dropping the sender for a listener stops it.
+    let mut listeners = Vec::new();
+    for local_addr in profile.forwards {
+        let listener = TcpListener::bind(&local_addr, &handle).expect("Failed to bind listener.");
+        info!("forwarding {}", local_addr);
+        let client = client.clone();
+        let conn_handle = handle.clone();
+        let (stop, stopped) = oneshot::channel::<()>();
+        listeners.push(stop);
+        let accept = listener.incoming()
+            .map_err(|e| error!("listen error: {}", e))
+            .for_each(move |(conn, addr)| {
+                info!("got connection from {}", addr);
+                let conn_handler = client.connect()
+                    .and_then(move |stream| stream.relay(conn))
+                    .then(move |val| {
+                        if let Err(e) = val {
+                            warn!("{}: {}", addr, e);
+                        }
+                        info!("{}: session ended", addr);
+                        Ok(())
+                    });
+                conn_handle.spawn(conn_handler);
+                Ok(())
+            });
+        handle.spawn(accept.select(stopped.then(|_| Ok(()))).then(|_| Ok(())));
+    }
+
+    let stop_signals = Signal::new(SIGTERM, &handle).flatten_stream()
+        .select(Signal::new(SIGINT, &handle).flatten_stream())
+        .map_err(|e| error!("signal error: {}", e))
+        .into_future()
+        .map(|(_, rest)| rest)
+        .map_err(|_| ());
+    let stop_signals = core.run(stop_signals).unwrap_or_else(|_| process::exit(1));
+
+    // Stop taking connections, and tell the open sessions to finish up.
+    // Another signal exits right away.
+    listeners.clear();
+    let open = client.open_sessions();
+    if open > 0 {
+        info!("closing {} open sessions", open);
+    }
+    client.close();
+    let wait = wait_for_sessions(&client, Instant::now() + CLOSE_TIME, &handle);
+    if let Ok(Either::B(_)) = core.run(wait.select2(stop_signals.into_future())) {
+        info!("exiting without waiting for sessions");
+    } else if client.open_sessions() > 0 {
+        warn!("gave up on {} sessions", client.open_sessions());
+    }
+    info!("shut down");
+}
+
+/// Wait until no sessions are open, or the deadline has passed.
+fn wait_for_sessions(
+    client: &TunnelClient,
+    deadline: Instant,
+    handle: &Handle
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    let client = client.clone();
+    Box::new(Interval::new(Duration::from_millis(100), handle).unwrap()
+        .map_err(|_| ())
+        .take_while(move |_| Ok(client.open_sessions() > 0 && Instant::now() < deadline))
+        .for_each(|_| Ok(())))
+}
+
+/// The config file to read profiles from by default.
+fn config_path() -> PathBuf {
+    let home = env::var_os("HOME").unwrap_or_default();
+    PathBuf::from(home).join(".config/squidtun/client.toml")
+}
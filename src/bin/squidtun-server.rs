@@ -0,0 +1,406 @@
+extern crate clap;
+extern crate futures;
+extern crate net2;
+extern crate squidtun;
+extern crate tokio_core;
+extern crate tokio_signal;
+
+#[macro_use]
+extern crate log;
+extern crate simple_logger;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg, ArgMatches};
+use futures::{Future, Stream};
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use log::Level;
+use net2::TcpBuilder;
+use squidtun::{Authenticator, CommandAuth, CredentialsAuth, Limits, PasswordAuth,
+    Policies, PublicKeyAuth, RemoteAddr, ServerBuilder, ServerConfig, TunnelService};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle, Interval};
+use tokio_signal::unix::{SIGHUP, SIGINT, SIGTERM, Signal};
+
+/// The listeners which are accepting connections, by address. Dropping the
+/// sender for a listener stops it.
+type Listeners = HashMap<SocketAddr, oneshot::Sender<()>>;
+
+/// Serves a connection accepted by a listener.
+type Serve = fn(&TunnelService, TcpStream) -> Box<dyn Future<Item = (), Error = ()>>;
+
+fn main() {
+    simple_logger::init_with_level(Level::Info).unwrap();
+
+    let matches = App::new("squidtun-server")
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .value_name("FILE")
+            .help("Read the settings from a TOML file, which is read again on SIGHUP")
+            .takes_value(true)
+            .conflicts_with_all(&["password", "credentials", "keys", "auth-command",
+                "authorized-keys", "policy", "remote", "max-chunk", "max-sessions",
+                "login-rate", "global-login-rate", "max-login-failures", "ban-time",
+                "require-mac", "drain-time", "metrics", "addr"]))
+        .arg(Arg::with_name("password")
+            .short("p")
+            .long("password")
+            .value_name("VALUE")
+            .help("Set the password to make connections")
+            .takes_value(true))
+        .arg(Arg::with_name("credentials")
+            .long("credentials")
+            .value_name("FILE")
+            .help("Accept the users in a file made with --hash-credential instead of a password")
+            .takes_value(true)
+            .conflicts_with_all(&["password", "keys", "auth-command"]))
+        .arg(Arg::with_name("keys")
+            .long("keys")
+            .value_name("FILE")
+            .help("Accept the users in a file of <user>:<password> lines instead of a password")
+            .takes_value(true)
+            .conflicts_with_all(&["password", "auth-command"]))
+        .arg(Arg::with_name("auth-command")
+            .long("auth-command")
+            .value_name("PROGRAM")
+            .help("Run a program to decide whether to accept each login")
+            .takes_value(true)
+            .conflicts_with("password"))
+        .arg(Arg::with_name("authorized-keys")
+            .long("authorized-keys")
+            .value_name("FILE")
+            .help("Accept users who sign nonces with the keys in a file, alongside other logins")
+            .takes_value(true))
+        .arg(Arg::with_name("policy")
+            .long("policy")
+            .value_name("FILE")
+            .help("Restrict each user according to a policy file")
+            .takes_value(true))
+        .arg(Arg::with_name("hash-credential")
+            .long("hash-credential")
+            .value_name("USER")
+            .help("Print a credentials file line for a user, given their password on stdin")
+            .takes_value(true))
+        .arg(Arg::with_name("remote")
+            .short("r")
+            .long("remote")
+            .value_name("IP:ADDR")
+            .help("Set the IP address to connect to")
+            .takes_value(true))
+        .arg(Arg::with_name("max-chunk")
+            .long("max-chunk")
+            .value_name("BYTES")
+            .help("Set the largest chunk to accept or send per request")
+            .takes_value(true))
+        .arg(Arg::with_name("max-sessions")
+            .long("max-sessions")
+            .value_name("COUNT")
+            .help("Set the most sessions to have open at once, or 0 for no limit")
+            .takes_value(true))
+        .arg(Arg::with_name("login-rate")
+            .long("login-rate")
+            .value_name("COUNT")
+            .help("Set the most logins per minute from each address, or 0 for no limit")
+            .takes_value(true))
+        .arg(Arg::with_name("global-login-rate")
+            .long("global-login-rate")
+            .value_name("COUNT")
+            .help("Set the most logins per second from all addresses, or 0 for no limit")
+            .takes_value(true))
+        .arg(Arg::with_name("max-login-failures")
+            .long("max-login-failures")
+            .value_name("COUNT")
+            .help("Ban a user from an address after this many failed logins, or 0 to never ban")
+            .takes_value(true))
+        .arg(Arg::with_name("ban-time")
+            .long("ban-time")
+            .value_name("SECONDS")
+            .help("Set how long bans last")
+            .takes_value(true))
+        .arg(Arg::with_name("require-mac")
+            .long("require-mac")
+            .help("Refuse sessions whose requests aren't signed, such as those of old clients"))
+        .arg(Arg::with_name("drain-time")
+            .long("drain-time")
+            .value_name("SECONDS")
+            .help("Set how long to let open sessions finish on SIGTERM or SIGINT")
+            .takes_value(true))
+        .arg(Arg::with_name("metrics")
+            .long("metrics")
+            .value_name("ADDR")
+            .help("Serve Prometheus metrics at /metrics on a separate address")
+            .takes_value(true))
+        .arg(Arg::with_name("addr")
+            .help("Set the addresses to listen on, such as 0.0.0.0:80 [::]:80 0.0.0.0:8080")
+            .multiple(true)
+            .required_unless_one(&["hash-credential", "config"])
+            .index(1))
+        .get_matches();
+
+    if let Some(user) = matches.value_of("hash-credential") {
+        let mut password = String::new();
+        io::stdin().read_line(&mut password).unwrap();
+        println!("{}", CredentialsAuth::entry(user, password.trim_end_matches(&['\r', '\n'][..])));
+        return;
+    }
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = load_settings(&matches).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    let service = config.builder.build(&handle).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    let drain_time = Rc::new(Cell::new(config.drain_time));
+    let listeners = Rc::new(RefCell::new(Listeners::new()));
+    update_listeners(&mut listeners.borrow_mut(), &config.listen, "", &service,
+        TunnelService::serve, &handle);
+    if listeners.borrow().len() < config.listen.len() {
+        process::exit(1);
+    }
+    // The metrics stay up until the server exits, so that draining can be
+    // watched.
+    let metrics = Rc::new(RefCell::new(Listeners::new()));
+    let metrics_addrs = config.metrics.into_iter().collect::<Vec<_>>();
+    update_listeners(&mut metrics.borrow_mut(), &metrics_addrs, " for metrics", &service,
+        TunnelService::serve_metrics, &handle);
+    if metrics.borrow().len() < metrics_addrs.len() {
+        process::exit(1);
+    }
+
+    // Sessions which are already open keep going through a reload.
+    let (reload_service, reload_listeners) = (service.clone(), listeners.clone());
+    let reload_metrics = metrics.clone();
+    let reload_drain_time = drain_time.clone();
+    let reload_handle = handle.clone();
+    let reload = Signal::new(SIGHUP, &handle)
+        .flatten_stream()
+        .map_err(|e| error!("signal error: {}", e))
+        .for_each(move |_| {
+            let reconfigure = |config: ServerConfig| {
+                let ServerConfig{listen, drain_time, metrics, builder} = config;
+                reload_service.reconfigure(builder).map(|_| (listen, drain_time, metrics))
+            };
+            match load_settings(&matches).and_then(reconfigure) {
+                Ok((listen, drain_time, metrics_addr)) => {
+                    reload_drain_time.set(drain_time);
+                    let listeners = &mut reload_listeners.borrow_mut();
+                    update_listeners(listeners, &listen, "", &reload_service,
+                        TunnelService::serve, &reload_handle);
+                    let addrs = metrics_addr.into_iter().collect::<Vec<_>>();
+                    let metrics = &mut reload_metrics.borrow_mut();
+                    update_listeners(metrics, &addrs, " for metrics", &reload_service,
+                        TunnelService::serve_metrics, &reload_handle);
+                    info!("reloaded settings");
+                },
+                Err(e) => error!("kept the old settings: {}", e)
+            }
+            Ok(())
+        });
+    let stop_signals = Signal::new(SIGTERM, &handle).flatten_stream()
+        .select(Signal::new(SIGINT, &handle).flatten_stream())
+        .map_err(|e| error!("signal error: {}", e))
+        .into_future()
+        .map(|(_, rest)| rest)
+        .map_err(|_| ());
+    let stop_signals = match core.run(stop_signals.select2(reload)) {
+        Ok(Either::A((rest, _))) => rest,
+        _ => process::exit(1)
+    };
+
+    // Stop taking new sessions, and give the open ones a while to end on
+    // their own. Another signal cuts the wait short. The listeners stay open
+    // in the meantime, since polled sessions need them to carry on.
+    service.drain();
+    let open = service.open_sessions();
+    if open > 0 {
+        info!("waiting up to {}s for {} open sessions to end", drain_time.get().as_secs(), open);
+    }
+    let deadline = Instant::now() + drain_time.get();
+    let wait = wait_for_sessions(&service, deadline, &handle);
+    if let Ok(Either::B(_)) = core.run(wait.select2(stop_signals.into_future())) {
+        info!("closing open sessions early");
+    }
+    update_listeners(&mut listeners.borrow_mut(), &[], "", &service, TunnelService::serve, &handle);
+    core.run(future::lazy(|| {
+        service.close_sessions();
+        Ok::<_, ()>(())
+    })).ok();
+    let deadline = Instant::now() + Duration::from_secs(1);
+    core.run(wait_for_sessions(&service, deadline, &handle)).ok();
+    info!("shut down");
+}
+
+/// Wait until no sessions are open, or the deadline has passed.
+fn wait_for_sessions(
+    service: &TunnelService,
+    deadline: Instant,
+    handle: &Handle
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    let service = service.clone();
+    Box::new(Interval::new(Duration::from_millis(100), handle).unwrap()
+        .map_err(|_| ())
+        .take_while(move |_| Ok(service.open_sessions() > 0 && Instant::now() < deadline))
+        .for_each(|_| Ok(())))
+}
+
+/// Read the addresses to listen on and the service's settings, from either
+/// the config file or the command line.
+fn load_settings(matches: &ArgMatches) -> Result<ServerConfig, String> {
+    if let Some(path) = matches.value_of("config") {
+        return ServerConfig::load(path).map_err(|e| format!("failed to load config: {}", e));
+    }
+    let remote_addr: RemoteAddr = matches.value_of("remote")
+        .unwrap_or("127.0.0.1:22")
+        .parse()
+        .map_err(|e| format!("invalid remote: {}", e))?;
+    let listen_addrs = matches.values_of("addr").unwrap()
+        .map(|x| parse_arg("addr", x))
+        .collect::<Result<_, _>>()?;
+    let defaults = Limits::default();
+    let limit = |name, default| limit_arg(matches, name, default);
+    let limits = Limits{
+        max_chunk_size: parse_arg("max-chunk", matches.value_of("max-chunk").unwrap_or("65536"))?,
+        max_sessions: limit_arg(matches, "max-sessions", defaults.max_sessions)?,
+        max_logins_per_address: limit("login-rate", defaults.max_logins_per_address)?,
+        max_logins: limit("global-login-rate", defaults.max_logins)?,
+        max_login_failures: limit("max-login-failures", defaults.max_login_failures)?,
+        ban_duration: match matches.value_of("ban-time") {
+            Some(value) => Duration::from_secs(parse_arg("ban-time", value)?),
+            None => defaults.ban_duration
+        },
+        require_mac: matches.is_present("require-mac"),
+        ..defaults
+    };
+    limits.check()?;
+
+    let failed = |what| move |e| format!("failed to load {}: {}", what, e);
+    let auth: Box<dyn Authenticator> = if let Some(path) = matches.value_of("credentials") {
+        Box::new(CredentialsAuth::load(path).map_err(failed("credentials"))?)
+    } else if let Some(path) = matches.value_of("keys") {
+        Box::new(CredentialsAuth::load_passwords(path).map_err(failed("keys"))?)
+    } else if let Some(program) = matches.value_of("auth-command") {
+        Box::new(CommandAuth::new(program, &[]))
+    } else {
+        Box::new(PasswordAuth::new(matches.value_of("password").unwrap_or("")))
+    };
+    let policies = match matches.value_of("policy") {
+        Some(path) => Policies::load(path).map_err(failed("policies"))?,
+        None => Policies::new()
+    };
+    let builder = ServerBuilder::new().remote(remote_addr).policies(policies).limits(limits);
+    let builder = match matches.value_of("authorized-keys") {
+        Some(path) => {
+            let key_auth = PublicKeyAuth::load(path).map_err(failed("authorized keys"))?;
+            builder.auth(key_auth.fallback(auth))
+        },
+        None => builder.auth(auth)
+    };
+    let drain_time = parse_arg("drain-time", matches.value_of("drain-time").unwrap_or("30"))?;
+    let metrics = match matches.value_of("metrics") {
+        Some(addr) => Some(addr.parse().map_err(|e| format!("invalid metrics address: {}", e))?),
+        None => None
+    };
+    Ok(ServerConfig{
+        listen: listen_addrs,
+        drain_time: Duration::from_secs(drain_time),
+        metrics,
+        builder
+    })
+}
+
+/// Start listening on the addresses which are new, and stop listening on the
+/// ones which are gone. `what` is added to the log messages, to tell the
+/// kinds of listener apart.
+///
+/// Connections which were accepted by a listener outlive it.
+fn update_listeners(
+    listeners: &mut Listeners,
+    addrs: &[SocketAddr],
+    what: &str,
+    service: &TunnelService,
+    serve: Serve,
+    handle: &Handle
+) {
+    listeners.retain(|addr, _| {
+        if !addrs.contains(addr) {
+            info!("stopped listening on {}{}", addr, what);
+        }
+        addrs.contains(addr)
+    });
+    for addr in addrs {
+        if listeners.contains_key(addr) {
+            continue;
+        }
+        match listen(addr, service.clone(), serve, handle) {
+            Ok(stop) => {
+                info!("listening on {}{}", addr, what);
+                listeners.insert(*addr, stop);
+            },
+            Err(e) => error!("failed to listen on {}{}: {}", addr, what, e)
+        }
+    }
+}
+
+fn listen(
+    addr: &SocketAddr,
+    service: TunnelService,
+    serve: Serve,
+    handle: &Handle
+) -> io::Result<oneshot::Sender<()>> {
+    let listener = bind(addr, handle)?;
+    let (stop, stopped) = oneshot::channel::<()>();
+    let conn_handle = handle.clone();
+    let accept = listener.incoming()
+        .map_err(|e| error!("listen error: {}", e))
+        .for_each(move |(conn, _)| {
+            conn_handle.spawn(serve(&service, conn));
+            Ok(())
+        });
+    handle.spawn(accept.select(stopped.then(|_| Ok(()))).then(|_| Ok(())));
+    Ok(stop)
+}
+
+/// Bind a listener to an address.
+///
+/// IPv6 listeners only take IPv6 connections, so that the same port can be
+/// bound for IPv4 as well.
+fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<TcpListener> {
+    let builder = match *addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+    let listener = builder.reuse_address(true)?.bind(addr)?.listen(1024)?;
+    TcpListener::from_listener(listener, addr, handle)
+}
+
+/// Parse a limit given on the command line, where 0 means no limit.
+fn limit_arg<T>(matches: &ArgMatches, name: &str, default: Option<T>) -> Result<Option<T>, String>
+    where T: FromStr + Default + PartialEq
+{
+    match matches.value_of(name) {
+        Some(value) => Ok(Some(parse_arg(name, value)?).filter(|x| *x != T::default())),
+        None => Ok(default)
+    }
+}
+
+/// Parse the value of a command line argument.
+fn parse_arg<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid --{}: {}", name, value))
+}
@@ -0,0 +1,105 @@
+use std::cmp::{max, min};
+use std::time::Duration;
+
+const MIN_CHUNK_SIZE: usize = 1024;
+
+/// The latency above which we assume a chunk is being held up by the proxy.
+const SLOW_REQUEST_MILLIS: u64 = 2000;
+
+/// Adaptively choose how many bytes to send or request at once.
+///
+/// Chunks grow while full-sized requests are fast and keep improving
+/// throughput, and shrink on slow requests or proxy failures.
+pub struct ChunkSizer {
+    size: usize,
+    limit: usize,
+    best_throughput: f64
+}
+
+impl ChunkSizer {
+    pub fn new(initial: usize, limit: usize) -> ChunkSizer {
+        ChunkSizer{
+            size: min(max(MIN_CHUNK_SIZE, initial), limit),
+            limit,
+            best_throughput: 0.0
+        }
+    }
+
+    /// Get the current chunk size.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Get the largest chunk size.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Set the largest chunk size, e.g. to a limit advertised by the server.
+    ///
+    /// The limit is respected even if it is below the size that chunks
+    /// otherwise never shrink under.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = max(1, limit);
+        self.size = min(self.size, self.limit);
+    }
+
+    /// Record a successful request which transferred `bytes` bytes.
+    pub fn record_success(&mut self, bytes: usize, elapsed: Duration) {
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        if elapsed > Duration::from_millis(SLOW_REQUEST_MILLIS) {
+            self.resize(self.size * 3 / 4);
+            return;
+        }
+        if bytes < self.size || secs <= 0.0 {
+            // Partial chunks tell us nothing about larger sizes.
+            return;
+        }
+        let throughput = bytes as f64 / secs;
+        if throughput >= self.best_throughput {
+            self.best_throughput = throughput;
+            self.resize(self.size * 2);
+        } else if throughput < self.best_throughput / 2.0 {
+            // Bigger chunks stopped paying off on this path.
+            self.best_throughput = throughput;
+            self.resize(self.size / 2);
+        }
+    }
+
+    /// Record a request that failed, e.g. with a 413 or truncated body.
+    pub fn record_failure(&mut self) {
+        self.best_throughput = 0.0;
+        self.resize(self.size / 2);
+    }
+
+    fn resize(&mut self, size: usize) {
+        self.size = min(max(MIN_CHUNK_SIZE, size), self.limit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn limit_below_minimum() {
+        let mut sizer = ChunkSizer::new(4096, 65536);
+        sizer.set_limit(512);
+        assert_eq!(sizer.size(), 512);
+        sizer.record_success(512, Duration::from_millis(1));
+        assert_eq!(sizer.size(), 512);
+        sizer.record_failure();
+        assert_eq!(sizer.size(), 512);
+    }
+
+    #[test]
+    fn shrinks_to_minimum() {
+        let mut sizer = ChunkSizer::new(4096, 65536);
+        for _ in 0..10 {
+            sizer.record_failure();
+        }
+        assert_eq!(sizer.size(), MIN_CHUNK_SIZE);
+    }
+}
@@ -0,0 +1,143 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use config::{addresses, bad_value, boolean, integer, load_file, parse_table, string,
+    table_value};
+use client::ClientOptions;
+use keys::KeyPair;
+use resolve::RemoteAddr;
+
+/// Client settings read from a profile in a TOML file.
+///
+/// Each profile is a table under "profiles". Settings at the top level of the
+/// file apply to every profile which doesn't set them itself, and key paths
+/// are relative to the file's directory.
+#[derive(Clone, Debug, Default)]
+pub struct ClientProfile {
+    /// The proxy to reach the server through.
+    pub proxy: Option<RemoteAddr>,
+
+    /// The hostname to query through the proxy.
+    pub host: Option<String>,
+    pub password: Option<String>,
+
+    /// The local addresses to accept connections on.
+    pub forwards: Vec<SocketAddr>,
+    pub options: ClientOptions
+}
+
+impl ClientProfile {
+    /// Load the profile called `name` from a config file.
+    pub fn load<P: AsRef<Path>>(path: P, name: &str) -> io::Result<ClientProfile> {
+        load_file(path.as_ref(), |contents, dir| ClientProfile::parse(contents, dir, name))
+    }
+
+    /// Parse the profile called `name` from the contents of a config file,
+    /// reading key files relative to `dir`.
+    pub fn parse(contents: &str, dir: &Path, name: &str) -> Result<ClientProfile, String> {
+        let mut settings = parse_table(contents, SETTINGS)?;
+        let profiles = match settings.remove("profiles") {
+            Some(value) => table_value(&value, "profiles")?.clone(),
+            None => Default::default()
+        };
+        match profiles.get(name) {
+            Some(value) => settings.extend(table_value(value, name)?.clone()),
+            None => return Err(format!("no profile named {}", name))
+        }
+        let mut profile = ClientProfile::default();
+        for (name, value) in &settings {
+            let options = &mut profile.options;
+            match name.as_str() {
+                "proxy" => {
+                    let proxy = string(value, name)?.parse().map_err(|_| bad_value(name))?;
+                    profile.proxy = Some(proxy);
+                },
+                "host" => profile.host = Some(string(value, name)?.to_owned()),
+                "password" => profile.password = Some(string(value, name)?.to_owned()),
+                "forwards" => profile.forwards = addresses(value, name)?,
+                "user" => options.user = Some(string(value, name)?.to_owned()),
+                "key" => {
+                    let path = dir.join(string(value, name)?);
+                    let key = KeyPair::load(&path)
+                        .map_err(|e| format!("failed to load key {}: {}", path.display(), e))?;
+                    options.key = Some(key);
+                },
+                "pipeline" => options.pipeline = integer(value, name)?,
+                "stream_download" => options.stream_download = boolean(value, name)?,
+                "stream_upload" => options.stream_upload = boolean(value, name)?,
+                "websocket" => options.websocket = boolean(value, name)?,
+                "connect_port" => options.connect_port = Some(integer(value, name)?),
+                _ => return Err(format!("unknown setting: {}", name))
+            }
+        }
+        Ok(profile)
+    }
+}
+
+/// The settings at the top level of a config file.
+const SETTINGS: &[&str] = &["proxy", "host", "password", "forwards", "user", "key", "pipeline",
+    "stream_download", "stream_upload", "websocket", "connect_port", "profiles"];
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+
+    const CONTENTS: &str = "proxy = \"proxy.example:3128\"\nhost = \"tunnel.example\"\n\
+        password = \"shared\"\npipeline = 2\nforwards = [\"127.0.0.1:2222\"]\n\
+        [profiles.home]\n\
+        [profiles.work]\nproxy = \"10.0.0.1:8080\"\npassword = \"secret\"\nwebsocket = true\n\
+        forwards = [\"127.0.0.1:2223\", \"127.0.0.1:2224\"]\n";
+
+    #[test]
+    fn profiles_override_defaults() {
+        let home = ClientProfile::parse(CONTENTS, Path::new("."), "home").unwrap();
+        assert_eq!(home.proxy, Some("proxy.example:3128".parse().unwrap()));
+        assert_eq!(home.host.as_deref(), Some("tunnel.example"));
+        assert_eq!(home.password.as_deref(), Some("shared"));
+        assert_eq!(home.forwards, ["127.0.0.1:2222".parse().unwrap()]);
+        assert_eq!(home.options.pipeline, 2);
+        assert!(!home.options.websocket);
+
+        let work = ClientProfile::parse(CONTENTS, Path::new("."), "work").unwrap();
+        assert_eq!(work.proxy, Some("10.0.0.1:8080".parse().unwrap()));
+        assert_eq!(work.host.as_deref(), Some("tunnel.example"));
+        assert_eq!(work.password.as_deref(), Some("secret"));
+        assert_eq!(work.forwards.len(), 2);
+        assert_eq!(work.options.pipeline, 2);
+        assert!(work.options.websocket);
+    }
+
+    #[test]
+    fn bad_profiles() {
+        let err = ClientProfile::parse(CONTENTS, Path::new("."), "cafe").err().unwrap();
+        assert_eq!(err, "no profile named cafe");
+        let contents = "[profiles.home]\nspeed = 3\n";
+        let err = ClientProfile::parse(contents, Path::new("."), "home").err().unwrap();
+        assert_eq!(err, "unknown setting: speed");
+        let contents = "speed = 3\n[profiles.home]\n";
+        assert!(ClientProfile::parse(contents, Path::new("."), "home").is_err());
+        let contents = "[profiles.home]\nproxy = \"nowhere\"\n";
+        assert!(ClientProfile::parse(contents, Path::new("."), "home").is_err());
+    }
+
+    #[test]
+    fn key_paths_are_relative() {
+        let dir = env::temp_dir().join(format!("squidtun-profile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = KeyPair::generate().unwrap();
+        key.save(dir.join("id_key")).unwrap();
+        let path = dir.join("client.toml");
+        fs::write(&path, "user = \"dave\"\n[profiles.home]\nkey = \"id_key\"\n").unwrap();
+        let profile = ClientProfile::load(&path, "home");
+        fs::remove_dir_all(&dir).unwrap();
+
+        let options = profile.unwrap().options;
+        assert_eq!(options.user.as_deref(), Some("dave"));
+        assert_eq!(options.key.unwrap().public_line("dave"), key.public_line("dave"));
+    }
+}
@@ -0,0 +1,126 @@
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{Future, IntoFuture, Sink, Stream};
+use futures::future::Either;
+use futures::stream::once;
+use tokio_core::net::TcpStream;
+use tokio_io::io::write_all;
+
+use error::{Error, is_reset};
+use handshake::{Handshake, HandshakeReply};
+use stream_util::SharedStream;
+use websocket::HttpHead;
+use client::{ChunkSink, Chunks, Context, HostInfo, MAX_HEAD_SIZE, MAX_READ_SIZE};
+use client::future_util::{ReadStream, WriteSink, read_until};
+use client::session::login_proof;
+
+/// A raw connection to the server, along with any data which arrived right
+/// after the handshake reply.
+pub struct Tunnel {
+    conn: TcpStream,
+    leftover: Vec<u8>
+}
+
+/// Open a raw tunnel to the server with an HTTP CONNECT request.
+///
+/// Yields None if the proxy refused the CONNECT, in which case the session
+/// should use regular HTTP requests instead.
+pub fn open_tunnel(
+    context: &Context,
+    host_info: &HostInfo,
+    port: u16
+) -> Box<dyn Future<Item = Option<Tunnel>, Error = Error>> {
+    let target = format!("{}:{}", host_name(&host_info.host), port);
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    let context_1 = context.clone();
+    let host_info_1 = host_info.clone();
+    Box::new(host_info.proxy_addr.connect()
+        .and_then(move |conn| write_all(conn, request))
+        .and_then(|(conn, _)| read_until(conn, |data| {
+            HttpHead::parse(data).is_some() || data.len() >= MAX_HEAD_SIZE
+        }))
+        .map_err(|e| Error::Transport(format!("failed to open tunnel: {}", e)))
+        .and_then(move |(conn, data)| {
+            let refused = match HttpHead::parse(&data) {
+                Some((ref head, _)) if head.status() == Some(200) => None,
+                Some((head, _)) => Some(head.start_line),
+                None => Some("no response".to_owned())
+            };
+            if let Some(status) = refused {
+                warn!("CONNECT refused: {}", status);
+                return Either::A(Ok(None).into_future());
+            }
+            Either::B(login_proof(&context_1, &host_info_1).and_then(move |(proof, _)| {
+                finish_handshake(conn, Handshake{proof, target: None}).map(Some)
+            }))
+        }))
+}
+
+/// Send the squidtun handshake through an open CONNECT tunnel and wait for
+/// the server to accept it.
+fn finish_handshake(
+    conn: TcpStream,
+    handshake: Handshake
+) -> Box<dyn Future<Item = Tunnel, Error = Error>> {
+    Box::new(write_all(conn, handshake.encode())
+        .and_then(|(conn, _)| read_until(conn, |data| {
+            !matches!(HandshakeReply::parse(data), Ok(None))
+        }))
+        .map_err(|e| Error::Transport(format!("failed to open tunnel: {}", e)))
+        .and_then(|(conn, data)| {
+            match HandshakeReply::parse(&data)? {
+                Some((HandshakeReply::Accepted, size)) => {
+                    Ok(Tunnel{conn, leftover: data[size..].to_vec()})
+                },
+                Some((HandshakeReply::Rejected(status, e), _)) => {
+                    Err(Error::from_status(status, &e))
+                },
+                None => Err(Error::Transport("tunnel closed during handshake".to_owned()))
+            }
+        }))
+}
+
+/// Carry data between the local end of a session and a tunnel until both
+/// directions have reached EOF.
+///
+/// The upload's EOF is passed along as a write shutdown.
+pub fn relay_tunnel(
+    tunnel: Tunnel,
+    chunks: Chunks,
+    sink: ChunkSink
+) -> Box<dyn Future<Item = (), Error = Error>> {
+    let conn = Rc::new(tunnel.conn);
+    let reset_conn = conn.clone();
+    let upload = chunks
+        .forward(WriteSink::new(SharedStream(conn.clone())).sink_map_err(tunnel_error))
+        .map_err(move |e| {
+            // Closing the tunnel without lingering resets it.
+            if let Error::Reset(_) = e {
+                reset_conn.set_linger(Some(Duration::from_secs(0))).ok();
+            }
+            e
+        });
+    let download = once(Ok(tunnel.leftover))
+        .chain(ReadStream::new(SharedStream(conn), MAX_READ_SIZE))
+        .map_err(tunnel_error)
+        .filter(|data| !data.is_empty())
+        .forward(sink);
+    Box::new(upload.join(download).map(|_| ()))
+}
+
+fn tunnel_error(e: io::Error) -> Error {
+    if is_reset(&e) {
+        return Error::Reset(format!("tunnel was reset: {}", e));
+    }
+    Error::Transport(format!("tunnel error: {}", e))
+}
+
+/// Strip the port, if any, from the host we query through the proxy.
+fn host_name(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host
+    }
+}
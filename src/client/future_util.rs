@@ -1,9 +1,167 @@
 use std::io;
-use std::iter::repeat;
-use std::mem::replace;
+use std::rc::Rc;
+use std::time::Duration;
 
-use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use futures::{Async, AsyncSink, Future, IntoFuture, Poll, Sink, StartSend, Stream};
+use futures::future::{Either, Loop, loop_fn};
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::read;
+
+use error::Error;
+
+/// Read from a connection until `done` returns true for everything read so
+/// far, or the connection ends.
+pub fn read_until<T, F>(
+    conn: T,
+    done: F
+) -> Box<dyn Future<Item = (T, Vec<u8>), Error = io::Error>>
+    where T: AsyncRead + 'static,
+          F: Fn(&[u8]) -> bool + 'static
+{
+    let done = Rc::new(done);
+    Box::new(loop_fn((conn, Vec::new()), move |(conn, mut data)| {
+        let done = done.clone();
+        read(conn, vec![0u8; 1024]).map(move |(conn, buf, size)| {
+            data.extend_from_slice(&buf[..size]);
+            if size == 0 || done(&data) {
+                Loop::Break((conn, data))
+            } else {
+                Loop::Continue((conn, data))
+            }
+        })
+    }))
+}
+
+/// Run an operation until it succeeds, fails with an error which isn't worth
+/// retrying, or has been tried `attempts` times.
+///
+/// Only operations which are safe to repeat should be retried.
+pub fn retry<F, R>(
+    handle: &Handle,
+    attempts: usize,
+    delay: Duration,
+    f: F
+) -> Box<dyn Future<Item = R::Item, Error = Error>>
+    where F: Fn() -> R + 'static,
+          R: IntoFuture<Error = Error> + 'static,
+          R::Item: 'static
+{
+    let handle = handle.clone();
+    Box::new(loop_fn(1usize, move |attempt| {
+        let handle = handle.clone();
+        f().into_future().then(move |res| {
+            match res {
+                Ok(x) => Either::A(Ok(Loop::Break(x)).into_future()),
+                Err(ref e) if e.is_retryable() && attempt < attempts => {
+                    warn!("retrying after error: {}", e);
+                    Either::B(Timeout::new(delay, &handle).unwrap()
+                        .map(move |_| Loop::Continue(attempt + 1))
+                        .map_err(Error::from))
+                },
+                Err(e) => Either::A(Err(e).into_future())
+            }
+        })
+    }))
+}
+
+/// A stream which merges the chunks that are ready at the same time, up to
+/// a maximum size.
+pub struct Coalesce<S: Stream<Item = Vec<u8>>> {
+    inner: S,
+    max_size: usize,
+    done: bool
+}
+
+impl<S: Stream<Item = Vec<u8>>> Coalesce<S> {
+    pub fn new(inner: S, max_size: usize) -> Coalesce<S> {
+        Coalesce{inner, max_size, done: false}
+    }
+}
+
+impl<S: Stream<Item = Vec<u8>>> Stream for Coalesce<S> {
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut res = Vec::new();
+        while !self.done && res.len() < self.max_size {
+            match self.inner.poll()? {
+                Async::Ready(Some(data)) => res.extend(data),
+                Async::Ready(None) => self.done = true,
+                Async::NotReady if res.is_empty() => return Ok(Async::NotReady),
+                Async::NotReady => break
+            }
+        }
+        if res.is_empty() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::Ready(Some(res)))
+        }
+    }
+}
+
+/// A stream which ends early once `end` resolves.
+pub struct TakeUntil<S: Stream> {
+    inner: S,
+    end: Box<dyn Future<Item = (), Error = ()>>,
+    done: bool
+}
+
+impl<S: Stream> TakeUntil<S> {
+    pub fn new(inner: S, end: Box<dyn Future<Item = (), Error = ()>>) -> TakeUntil<S> {
+        TakeUntil{inner, end, done: false}
+    }
+}
+
+impl<S: Stream> Stream for TakeUntil<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if !self.done && self.end.poll() != Ok(Async::NotReady) {
+            self.done = true;
+        }
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        self.inner.poll()
+    }
+}
+
+/// A stream which fails as soon as `fail` does, without waiting for the rest
+/// of the inner stream. If `fail` resolves instead, it is ignored.
+pub struct FailOn<S: Stream> {
+    inner: S,
+    fail: Option<Box<dyn Future<Item = (), Error = S::Error>>>
+}
+
+impl<S: Stream> FailOn<S> {
+    pub fn new(inner: S, fail: Box<dyn Future<Item = (), Error = S::Error>>) -> FailOn<S> {
+        FailOn{inner, fail: Some(fail)}
+    }
+}
+
+impl<S: Stream> Stream for FailOn<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let res = match self.fail {
+            Some(ref mut fail) => fail.poll(),
+            None => Ok(Async::NotReady)
+        };
+        match res {
+            Ok(Async::NotReady) => (),
+            Ok(Async::Ready(())) => self.fail = None,
+            Err(e) => {
+                self.fail = None;
+                return Err(e);
+            }
+        }
+        self.inner.poll()
+    }
+}
 
 pub struct ReadStream<T: AsyncRead> {
     reader: T,
@@ -12,7 +170,7 @@ pub struct ReadStream<T: AsyncRead> {
 
 impl<T: AsyncRead> ReadStream<T> {
     pub fn new(reader: T, buf_size: usize) -> ReadStream<T> {
-        ReadStream{reader: reader, buf_size: buf_size}
+        ReadStream{reader, buf_size}
     }
 }
 
@@ -21,7 +179,7 @@ impl<T: AsyncRead> Stream for ReadStream<T> {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let mut res: Vec<u8> = repeat(0).take(self.buf_size).collect();
+        let mut res = vec![0u8; self.buf_size];
         match self.reader.poll_read(&mut res) {
             Ok(Async::Ready(size)) => {
                 if size == 0 {
@@ -43,22 +201,22 @@ pub struct WriteSink<T: AsyncWrite> {
 
 impl<T: AsyncWrite> WriteSink<T> {
     pub fn new(writer: T) -> WriteSink<T> {
-        WriteSink{writer: writer, cur_buf: None}
+        WriteSink{writer, cur_buf: None}
     }
 
     fn attempt_write(&mut self) -> Poll<(), io::Error> {
-        while self.cur_buf.is_some() {
-            let buf = replace(&mut self.cur_buf, None).unwrap();
-            self.cur_buf = match self.writer.poll_write(&buf)? {
-                Async::Ready(size) => {
-                    if size == buf.len() {
-                        None
-                    } else {
-                        Some(buf[size..buf.len()].to_vec())
-                    }
+        while let Some(buf) = self.cur_buf.take() {
+            match self.writer.poll_write(&buf)? {
+                Async::Ready(size) if size < buf.len() => {
+                    self.cur_buf = Some(buf[size..].to_vec());
                 },
-                Async::NotReady => Some(buf)
-            };
+                Async::Ready(_) => (),
+                Async::NotReady => {
+                    // The writer wakes the task once it has room again.
+                    self.cur_buf = Some(buf);
+                    return Ok(Async::NotReady);
+                }
+            }
         }
         Ok(Async::Ready(()))
     }
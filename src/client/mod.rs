@@ -0,0 +1,726 @@
+mod chunk_size;
+mod config;
+mod connect;
+mod future_util;
+mod pipe;
+mod reorder;
+mod session;
+mod stream_upload;
+mod websocket;
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use futures::{Future, IntoFuture, Sink, Stream};
+use hyper::Headers;
+use hyper::client::{Client, HttpConnector};
+use tokio_core::reactor::Handle;
+
+use drain::Drain;
+use error::Error;
+use headers::MaxChunkSize;
+use keys::KeyPair;
+use keys::from_hex;
+use mac::OpenKey;
+use proof::{answer_challenge, client_key, proof_for_time, unix_time, user_key};
+use resolve::RemoteAddr;
+use client::chunk_size::ChunkSizer;
+use client::connect::{Tunnel, open_tunnel, relay_tunnel};
+use client::pipe::spawn_stream;
+use client::session::{SessionInfo, establish_session, measure_clock, relay_session};
+use client::websocket::{WebSocket, open_websocket, relay_websocket};
+
+pub use client::config::ClientProfile;
+pub use client::pipe::TunnelStream;
+
+const MAX_READ_SIZE: usize = 65536;
+
+/// The largest frame to accept in a streaming download. Servers only send
+/// frames of up to their chunk size, so this just stops a broken server from
+/// making us buffer without end.
+const MAX_FRAME_SIZE: usize = 1 << 24;
+
+/// The most data to read while waiting for the end of a response head.
+const MAX_HEAD_SIZE: usize = 8192;
+const INITIAL_CHUNK_SIZE: usize = 16384;
+
+/// The difference from the server's clock, in seconds, below which a failed
+/// login is blamed on the credentials rather than the clock.
+const MIN_CLOCK_SKEW: i64 = 5;
+
+/// The most rounds of password stretching to do for a server, so that a
+/// rogue one can't tie the client up.
+const MAX_PASSWORD_ROUNDS: u32 = 1 << 20;
+
+/// Data read from the local end of a session.
+type Chunks = Box<dyn Stream<Item = Vec<u8>, Error = Error>>;
+
+/// Where data for the local end of a session goes.
+type ChunkSink = Box<dyn Sink<SinkItem = Vec<u8>, SinkError = Error>>;
+
+/// Settings for how a TunnelClient carries its sessions.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    /// The number of uploads and downloads to keep in flight per session.
+    pub pipeline: usize,
+
+    /// If true, download through long-lived streaming responses.
+    pub stream_download: bool,
+
+    /// If true, upload through streaming request bodies when possible.
+    pub stream_upload: bool,
+
+    /// If true, try to carry sessions over WebSockets.
+    pub websocket: bool,
+
+    /// The port to try to reach the server on with CONNECT requests, if any.
+    pub connect_port: Option<u16>,
+
+    /// The user to log in as. Without one, the password is the server's
+    /// shared password.
+    pub user: Option<String>,
+
+    /// The key to sign in with instead of the password. This needs a user.
+    pub key: Option<KeyPair>
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions{
+            pipeline: 4,
+            stream_download: false,
+            stream_upload: false,
+            websocket: false,
+            connect_port: None,
+            user: None,
+            key: None
+        }
+    }
+}
+
+/// Opens connections to the remote host through the proxy and the server.
+#[derive(Clone)]
+pub struct TunnelClient {
+    context: Context,
+    host_info: HostInfo,
+    drain: Rc<Drain>,
+
+    /// The number of sessions which are carrying data.
+    open: Rc<Cell<usize>>
+}
+
+impl TunnelClient {
+    /// Create a client which reaches the server through the proxy at
+    /// `proxy_addr`, using `host` as the hostname to query.
+    ///
+    /// The proxy may be a SocketAddr, or a RemoteAddr with a hostname.
+    pub fn new<A: Into<RemoteAddr>>(
+        handle: &Handle,
+        proxy_addr: A,
+        host: &str,
+        password: &str,
+        options: ClientOptions
+    ) -> TunnelClient {
+        TunnelClient{
+            context: Context{
+                client: Client::configure().keep_alive(true).build(handle),
+                handle: handle.clone(),
+                sizers: ChunkSizers::new(),
+                pipeline: options.pipeline.max(1),
+                stream_download: options.stream_download,
+                stream_upload: options.stream_upload,
+                websocket: Rc::new(Cell::new(options.websocket)),
+                connect_port: Rc::new(Cell::new(options.connect_port)),
+                clock_offset: Rc::new(Cell::new(None))
+            },
+            host_info: HostInfo{
+                proxy_addr: proxy_addr.into(),
+                host: host.to_owned(),
+                user: options.user,
+                key: options.key,
+                password: password.to_owned()
+            },
+            drain: Rc::new(Drain::new()),
+            open: Rc::new(Cell::new(0))
+        }
+    }
+
+    /// Open a new connection to the remote host.
+    ///
+    /// The resulting future yields once the session is established. The
+    /// stream is driven by the reactor the client was created on.
+    pub fn connect(&self) -> Box<dyn Future<Item = TunnelStream, Error = Error>> {
+        if self.drain.is_draining() {
+            return Box::new(Err(Error::Busy("client is shutting down".to_owned())).into_future());
+        }
+        let handle = self.context.handle.clone();
+        let (drain, open) = (self.drain.clone(), self.open.clone());
+        Box::new(open_checked_transport(self.context.clone(), self.host_info.clone())
+            .map(move |transport| {
+                open.set(open.get() + 1);
+                spawn_stream(&handle, drain.closed(), move |chunks, sink| {
+                    Box::new(run_transport(transport, chunks, sink).then(move |res| {
+                        open.set(open.get() - 1);
+                        res
+                    }))
+                })
+            }))
+    }
+
+    /// Finish every open session, and refuse to open new ones.
+    ///
+    /// Streams being relayed stop reading from their connections. Whatever
+    /// was already read is uploaded, and then the remote host gets an EOF.
+    /// Downloads keep going until the remote host is done.
+    pub fn close(&self) {
+        self.drain.close();
+    }
+
+    /// Get how many sessions are still carrying data.
+    pub fn open_sessions(&self) -> usize {
+        self.open.get()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct HostInfo {
+    proxy_addr: RemoteAddr,
+    host: String,
+    user: Option<String>,
+    key: Option<KeyPair>,
+    password: String
+}
+
+impl HostInfo {
+    /// Create a fresh proof of the password, for a server whose clock is
+    /// `clock_offset` seconds ahead of ours.
+    fn proof(&self, clock_offset: i64) -> String {
+        let time = (unix_time() as i64 + clock_offset) as u64;
+        match self.user {
+            Some(ref user) => {
+                format!("{}:{}", user, proof_for_time(&user_key(user, &self.password), time))
+            },
+            None => proof_for_time(&self.password, time)
+        }
+    }
+
+    /// Answer a challenge for a user's password, which comes as
+    /// "<nonce>:<rounds>:<salt>", yielding the proof to log in with and the
+    /// key which opens the secrets of its sessions.
+    fn answer(&self, user: &str, challenge: &str) -> Result<(String, OpenKey), Error> {
+        let mut fields = challenge.split(':');
+        let (nonce, rounds, salt) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(nonce), Some(rounds), Some(salt)) => (nonce, rounds.parse().ok(), from_hex(salt)),
+            _ => (challenge, None, None)
+        };
+        match (rounds, salt) {
+            (Some(rounds), Some(salt)) if rounds <= MAX_PASSWORD_ROUNDS => {
+                let key = client_key(&self.password, &salt, rounds);
+                let proof = format!("{}:{}", user, answer_challenge(&key, user, nonce));
+                Ok((proof, OpenKey::Shared(key.to_vec())))
+            },
+            _ => Err(Error::Protocol("invalid challenge from server".to_owned()))
+        }
+    }
+}
+
+/// Chunk sizes learned for the path through the proxy.
+///
+/// These are shared between sessions, since every session goes through the
+/// same proxy and server.
+#[derive(Clone)]
+struct ChunkSizers {
+    upload: Rc<RefCell<ChunkSizer>>,
+    download: Rc<RefCell<ChunkSizer>>
+}
+
+impl ChunkSizers {
+    fn new() -> ChunkSizers {
+        ChunkSizers{
+            upload: Rc::new(RefCell::new(ChunkSizer::new(INITIAL_CHUNK_SIZE, MAX_READ_SIZE))),
+            download: Rc::new(RefCell::new(ChunkSizer::new(INITIAL_CHUNK_SIZE, MAX_READ_SIZE)))
+        }
+    }
+
+    /// Apply the chunk size limit advertised by the server, if any.
+    fn update_limit(&self, headers: &Headers) {
+        if let Some(&MaxChunkSize(limit)) = headers.get::<MaxChunkSize>() {
+            self.upload.borrow_mut().set_limit(limit);
+            self.download.borrow_mut().set_limit(limit);
+        }
+    }
+}
+
+/// State shared by every session.
+#[derive(Clone)]
+struct Context {
+    client: Client<HttpConnector>,
+    handle: Handle,
+    sizers: ChunkSizers,
+
+    /// The number of uploads and downloads to keep in flight per session.
+    pipeline: usize,
+
+    /// If true, download through long-lived streaming responses.
+    stream_download: bool,
+
+    /// If true, upload through streaming request bodies when possible.
+    stream_upload: bool,
+
+    /// If true, try to carry sessions over WebSockets. This is turned off
+    /// once the proxy refuses an upgrade.
+    websocket: Rc<Cell<bool>>,
+
+    /// The port to try to reach the server on with CONNECT requests, if any.
+    /// This is cleared once the proxy refuses a CONNECT.
+    connect_port: Rc<Cell<Option<u16>>>,
+
+    /// How many seconds the server's clock is ahead of ours, once a failed
+    /// login has made us check.
+    clock_offset: Rc<Cell<Option<i64>>>
+}
+
+/// What carries an established session.
+enum Transport {
+    Tunnel(Tunnel),
+    WebSocket(WebSocket),
+    Polled(Box<SessionInfo>)
+}
+
+/// Establish a new session, and if the login fails, check whether it was
+/// because of the local clock.
+///
+/// Proofs of a password are only good for a short time, so a clock which is
+/// too far off makes every login fail. When the server's clock disagrees with
+/// ours, later proofs are made for the server's time instead.
+fn open_checked_transport(
+    context: Context,
+    info: HostInfo
+) -> Box<dyn Future<Item = Transport, Error = Error>> {
+    Box::new(open_transport(context.clone(), info.clone()).or_else(move |e| {
+        match e {
+            Error::Auth(_) if context.clock_offset.get().is_none() => (),
+            e => return Box::new(Err(e).into_future()) as Box<dyn Future<Item = _, Error = _>>
+        }
+        Box::new(measure_clock(&context, &info).then(move |res| {
+            let offset = match res {
+                Ok(offset) => offset,
+                Err(err) => {
+                    warn!("failed to check the server's clock: {}", err);
+                    return Box::new(Err(e).into_future()) as Box<dyn Future<Item = _, Error = _>>;
+                }
+            };
+            context.clock_offset.set(Some(offset));
+            if offset.abs() < MIN_CLOCK_SKEW {
+                return Box::new(Err(e).into_future());
+            }
+            let direction = if offset > 0 { "behind" } else { "ahead of" };
+            warn!("login failed because the local clock is {}s {} the server's; \
+                making proofs for the server's time instead", offset.abs(), direction);
+            open_transport(context, info)
+        }))
+    }))
+}
+
+/// Establish a new session, through a CONNECT tunnel if possible.
+fn open_transport(
+    context: Context,
+    info: HostInfo
+) -> Box<dyn Future<Item = Transport, Error = Error>> {
+    let port = match context.connect_port.get() {
+        Some(port) => port,
+        None => return open_http_transport(context, info)
+    };
+    Box::new(open_tunnel(&context, &info, port).then(move |res| {
+        match res {
+            Ok(Some(tunnel)) => Box::new(Ok(Transport::Tunnel(tunnel)).into_future()),
+            Err(e) if !e.is_retryable() => Box::new(Err(e).into_future()),
+            res => {
+                if let Err(e) = res {
+                    warn!("failed to open tunnel: {}", e);
+                }
+                warn!("falling back to HTTP requests");
+                context.connect_port.set(None);
+                open_http_transport(context, info)
+            }
+        }
+    }))
+}
+
+/// Establish a new session through the proxy's HTTP support, over a
+/// WebSocket if possible.
+fn open_http_transport(
+    context: Context,
+    info: HostInfo
+) -> Box<dyn Future<Item = Transport, Error = Error>> {
+    if !context.websocket.get() {
+        return open_polled_transport(context, info);
+    }
+    Box::new(open_websocket(&context, &info).then(move |res| {
+        match res {
+            Ok(Some(websocket)) => Box::new(Ok(Transport::WebSocket(websocket)).into_future()),
+            Err(e) if !e.is_retryable() => Box::new(Err(e).into_future()),
+            res => {
+                if let Err(e) = res {
+                    warn!("failed to open WebSocket: {}", e);
+                }
+                warn!("falling back to HTTP requests");
+                context.websocket.set(false);
+                open_polled_transport(context, info)
+            }
+        }
+    }))
+}
+
+/// Establish a new session which is carried by HTTP requests.
+fn open_polled_transport(
+    context: Context,
+    info: HostInfo
+) -> Box<dyn Future<Item = Transport, Error = Error>> {
+    Box::new(establish_session(&context, &info).map(move |(id, protocol, key)| {
+        Transport::Polled(Box::new(SessionInfo{context, host_info: info, id, protocol, key}))
+    }))
+}
+
+/// Carry data between the local end of a session and the server until both
+/// directions are done.
+fn run_transport(
+    transport: Transport,
+    chunks: Chunks,
+    sink: ChunkSink
+) -> Box<dyn Future<Item = (), Error = Error>> {
+    match transport {
+        Transport::Tunnel(tunnel) => relay_tunnel(tunnel, chunks, sink),
+        Transport::WebSocket(websocket) => relay_websocket(websocket, chunks, sink),
+        Transport::Polled(info) => relay_session(*info, chunks, sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+    use tokio_io::AsyncRead;
+    use tokio_io::io::{read_to_end, shutdown, write_all};
+
+    use protocol::{Agreement, Features};
+    use response::{ResponseFrame, Status};
+    use server::{ServerBuilder, TunnelService};
+    use super::*;
+
+    /// Serve a TunnelService for `remote`, with a password of "pw", on the
+    /// core's reactor.
+    fn start_server(core: &Core, remote: SocketAddr) -> (TunnelService, SocketAddr) {
+        let handle = core.handle();
+        let service = ServerBuilder::new().password("pw").remote(remote).build(&handle).unwrap();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (service_1, handle_1) = (service.clone(), handle.clone());
+        handle.spawn(listener.incoming().for_each(move |(conn, _)| {
+            handle_1.spawn(service_1.serve(conn));
+            Ok(())
+        }).map_err(|_| ()));
+        (service, addr)
+    }
+
+    /// Start a remote host which echoes back its first connection.
+    fn echo_server() -> SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut conn = listener.accept().unwrap().0;
+            io::copy(&mut conn.try_clone().unwrap(), &mut conn).unwrap();
+            conn.shutdown(net::Shutdown::Write).unwrap();
+        });
+        addr
+    }
+
+    /// Turn the reactor until `done` is true.
+    fn turn_until<F: FnMut() -> bool>(core: &mut Core, mut done: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            core.turn(Some(Duration::from_millis(10)));
+        }
+    }
+
+    /// Send data through a tunnel to an echo server and check that all of it
+    /// comes back, yielding the service for a look at its metrics.
+    fn round_trip(options: ClientOptions) -> TunnelService {
+        let mut core = Core::new().unwrap();
+        let (service, addr) = start_server(&core, echo_server());
+        let client = TunnelClient::new(&core.handle(), addr, "localhost", "pw", options);
+        let stream = core.run(client.connect()).unwrap();
+        let (reader, writer) = stream.split();
+        let data = (0..200000).map(|x| x as u8).collect::<Vec<u8>>();
+        let upload = write_all(writer, data.clone()).and_then(|(writer, _)| shutdown(writer));
+        let (_, (_, received)) = core.run(upload.join(read_to_end(reader, Vec::new()))).unwrap();
+        assert!(received == data, "got {} bytes back", received.len());
+        service
+    }
+
+    #[test]
+    fn polled_sessions() {
+        round_trip(ClientOptions::default());
+        round_trip(ClientOptions{pipeline: 1, ..ClientOptions::default()});
+    }
+
+    #[test]
+    fn streamed_sessions() {
+        let service = round_trip(ClientOptions{stream_download: true, stream_upload: true,
+            ..ClientOptions::default()});
+        let metrics = service.metrics();
+        assert!(!metrics.contains("squidtun_requests_total{api=\"stream\"} 0\n"));
+        assert!(!metrics.contains("squidtun_requests_total{api=\"upstream\"} 0\n"));
+    }
+
+    #[test]
+    fn wrong_password() {
+        let mut core = Core::new().unwrap();
+        let (_service, addr) = start_server(&core, echo_server());
+        let client = TunnelClient::new(&core.handle(), addr, "localhost", "nope",
+            ClientOptions::default());
+        match core.run(client.connect()) {
+            Err(Error::Auth(_)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("logged in with the wrong password")
+        }
+        assert_eq!(client.open_sessions(), 0);
+    }
+
+    #[test]
+    fn websocket_sessions() {
+        let service = round_trip(ClientOptions{websocket: true, ..ClientOptions::default()});
+        let metrics = service.metrics();
+        assert!(metrics.contains("squidtun_requests_total{api=\"websocket\"} 1\n"));
+        assert!(metrics.contains("squidtun_requests_total{api=\"connect\"} 0\n"));
+    }
+
+    /// Start a server which answers every request with the frame from
+    /// `respond`, given the API name and its argument. Connect responses carry
+    /// an agreement on version 2 with ordered transfers.
+    ///
+    /// The APIs requested are recorded in order.
+    fn fake_server<F>(respond: F) -> (SocketAddr, Arc<Mutex<Vec<String>>>)
+        where F: Fn(&str, &str) -> ResponseFrame + Send + 'static
+    {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_1 = requests.clone();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = BufReader::new(conn.unwrap());
+                let mut line = String::new();
+                conn.read_line(&mut line).unwrap();
+                let path = line.split(' ').nth(1).unwrap_or("").to_owned();
+                while line.trim() != "" {
+                    line.clear();
+                    conn.read_line(&mut line).unwrap();
+                }
+                let mut parts = path.split('/').skip(1);
+                let (api, arg) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+                requests_1.lock().unwrap().push(api.to_owned());
+                let body = respond(api, arg).encode();
+                let header = if api == "connect" {
+                    let agreement = Agreement{version: 2, features: Features::ORDERED};
+                    format!("X-Squidtun-Protocol: {}\r\n", agreement.encode())
+                } else {
+                    String::new()
+                };
+                write!(conn.get_mut(), "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\
+                    Connection: close\r\n{}\r\n", body.len(), header).unwrap();
+                conn.get_mut().write_all(&body).unwrap();
+            }
+        });
+        (addr, requests)
+    }
+
+    #[test]
+    fn measure_server_clock() {
+        let mut core = Core::new().unwrap();
+        let (addr, _) = fake_server(|_, _| {
+            ResponseFrame::ok((unix_time() + 3600).to_string().into_bytes())
+        });
+        let client = TunnelClient::new(&core.handle(), addr, "localhost", "pw",
+            ClientOptions::default());
+        let offset = core.run(measure_clock(&client.context, &client.host_info)).unwrap();
+        assert!((3599..3602).contains(&offset), "offset of {}", offset);
+
+        let (addr, _) = fake_server(|_, _| ResponseFrame::ok(b"noon".to_vec()));
+        let client = TunnelClient::new(&core.handle(), addr, "localhost", "pw",
+            ClientOptions::default());
+        match core.run(measure_clock(&client.context, &client.host_info)) {
+            Err(Error::Protocol(_)) => (),
+            res => panic!("unexpected result: {:?}", res)
+        }
+    }
+
+    #[test]
+    fn retry_after_clock_skew() {
+        let mut core = Core::new().unwrap();
+        let server_time = || unix_time() + 3600;
+        let (addr, requests) = fake_server(move |api, arg| {
+            if api == "time" {
+                return ResponseFrame::ok(server_time().to_string().into_bytes());
+            }
+            let now = server_time();
+            if (now - 2..now + 3).any(|time| proof_for_time("pw", time) == arg) {
+                ResponseFrame::ok(b"id".to_vec())
+            } else {
+                ResponseFrame::error(Status::AuthFailed, "bad proof")
+            }
+        });
+        let client = TunnelClient::new(&core.handle(), addr, "localhost", "pw",
+            ClientOptions::default());
+        let open = open_checked_transport(client.context.clone(), client.host_info.clone());
+        match core.run(open) {
+            Ok(Transport::Polled(ref info)) if info.id == "id" => (),
+            Ok(_) => panic!("unexpected transport"),
+            Err(e) => panic!("unexpected error: {}", e)
+        }
+        let offset = client.context.clock_offset.get().unwrap();
+        assert!((3599..3602).contains(&offset), "offset of {}", offset);
+        assert_eq!(*requests.lock().unwrap(), ["connect", "time", "connect"]);
+    }
+
+    #[test]
+    fn auth_failure_without_skew() {
+        let mut core = Core::new().unwrap();
+        let (addr, requests) = fake_server(|api, _| {
+            if api == "time" {
+                ResponseFrame::ok(unix_time().to_string().into_bytes())
+            } else {
+                ResponseFrame::error(Status::AuthFailed, "bad proof")
+            }
+        });
+        let client = TunnelClient::new(&core.handle(), addr, "localhost", "pw",
+            ClientOptions::default());
+        for _ in 0..2 {
+            let open = open_checked_transport(client.context.clone(), client.host_info.clone());
+            match core.run(open) {
+                Err(Error::Auth(_)) => (),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("logged in with a bad proof")
+            }
+        }
+        assert!(client.context.clock_offset.get().unwrap().abs() <= 1);
+        // The clock is only checked once.
+        assert_eq!(*requests.lock().unwrap(), ["connect", "time", "connect"]);
+    }
+
+    #[test]
+    fn close_sessions() {
+        let mut core = Core::new().unwrap();
+        let remote = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (_service, addr) = start_server(&core, remote.local_addr().unwrap());
+        let (got_data, data_arrived) = mpsc::channel();
+        let remote_thread = thread::spawn(move || {
+            let mut conn = remote.accept().unwrap().0;
+            let mut data = vec![0; 1000];
+            conn.read_exact(&mut data).unwrap();
+            got_data.send(()).unwrap();
+            // Nothing more is read once the client closes, so the EOF comes
+            // next.
+            assert_eq!(conn.read(&mut [0; 1]).unwrap(), 0);
+            conn.write_all(b"bye").unwrap();
+            data
+        });
+        let local = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let local_addr = local.local_addr().unwrap();
+        let local_thread = thread::spawn(move || {
+            let mut conn = net::TcpStream::connect(local_addr).unwrap();
+            conn.write_all(&[7; 1000]).unwrap();
+            let mut received = Vec::new();
+            conn.read_to_end(&mut received).unwrap();
+            received
+        });
+        let conn = TcpStream::from_stream(local.accept().unwrap().0, &core.handle()).unwrap();
+
+        let client = TunnelClient::new(&core.handle(), addr, "localhost", "pw",
+            ClientOptions::default());
+        let stream = core.run(client.connect()).unwrap();
+        let relayed = Rc::new(Cell::new(false));
+        let relayed_1 = relayed.clone();
+        core.handle().spawn(stream.relay(conn)
+            .map(move |_| relayed_1.set(true))
+            .map_err(|e| panic!("relay failed: {}", e)));
+        turn_until(&mut core, || data_arrived.try_recv().is_ok());
+        assert_eq!(client.open_sessions(), 1);
+        client.close();
+        turn_until(&mut core, || client.open_sessions() == 0 && relayed.get());
+        assert_eq!(remote_thread.join().unwrap(), vec![7; 1000]);
+        assert_eq!(local_thread.join().unwrap(), b"bye");
+        match core.run(client.connect()) {
+            Err(Error::Busy(_)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("connected after closing")
+        }
+    }
+
+    /// The ways of carrying a session which pass resets along.
+    fn reset_options() -> Vec<ClientOptions> {
+        vec![
+            ClientOptions::default(),
+            ClientOptions{stream_download: true, stream_upload: true, ..ClientOptions::default()}
+        ]
+    }
+
+    #[test]
+    fn reset_by_client() {
+        for options in reset_options() {
+            let mut core = Core::new().unwrap();
+            let remote = net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let (_service, addr) = start_server(&core, remote.local_addr().unwrap());
+            let (got_data, data_arrived) = mpsc::channel();
+            let (sender, results) = mpsc::channel();
+            thread::spawn(move || {
+                let mut conn = remote.accept().unwrap().0;
+                let mut data = [0; 5];
+                conn.read_exact(&mut data).unwrap();
+                got_data.send(()).unwrap();
+                sender.send(conn.read(&mut data)).unwrap();
+            });
+            let client = TunnelClient::new(&core.handle(), addr, "localhost", "pw", options);
+            let stream = core.run(client.connect()).unwrap();
+            let (mut stream, _) = core.run(write_all(stream, b"hello")).unwrap();
+            turn_until(&mut core, || data_arrived.try_recv().is_ok());
+            stream.reset();
+            let mut result = None;
+            turn_until(&mut core, || { result = results.try_recv().ok(); result.is_some() });
+            match result.unwrap() {
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => (),
+                res => panic!("unexpected result: {:?}", res)
+            }
+        }
+    }
+
+    #[test]
+    fn reset_by_remote() {
+        for options in reset_options() {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let remote = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+            let (_service, addr) = start_server(&core, remote.local_addr().unwrap());
+            // Closing the connection without lingering resets it.
+            handle.spawn(remote.incoming().into_future().map(|(conn, _)| {
+                conn.unwrap().0.set_linger(Some(Duration::from_secs(0))).unwrap();
+            }).map_err(|_| ()));
+            let client = TunnelClient::new(&handle, addr, "localhost", "pw", options);
+            let stream = core.run(client.connect()).unwrap();
+            match core.run(read_to_end(stream, Vec::new())) {
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => (),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("read an EOF instead of a reset")
+            }
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::future::empty;
+use futures::sync::{mpsc, oneshot};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use error::{Error, is_reset};
+use stream_util::SharedStream;
+use client::{ChunkSink, Chunks, MAX_READ_SIZE};
+use client::future_util::{Coalesce, FailOn, ReadStream, TakeUntil, WriteSink};
+
+/// How many chunks to queue in each direction.
+const QUEUE_SIZE: usize = 16;
+
+/// A connection to the remote host through a session.
+///
+/// The session is driven by a task on the client's reactor, so the stream
+/// must be used from that reactor. Shutting down the stream sends an EOF to
+/// the remote host, and reads keep working until the remote host is done.
+///
+/// If the remote host resets the connection, reads and writes fail with
+/// `ErrorKind::ConnectionReset`.
+pub struct TunnelStream {
+    outgoing: Option<mpsc::Sender<Vec<u8>>>,
+    incoming: mpsc::Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+
+    /// Resolves when the client is closing its sessions.
+    closed: Box<dyn Future<Item = (), Error = ()>>,
+
+    /// Tells the session to reset the connection to the remote host.
+    reset: Option<oneshot::Sender<()>>,
+
+    /// Yields an error if the session fails, and is canceled if it finishes.
+    failure: Option<oneshot::Receiver<Error>>,
+    failed: bool
+}
+
+impl TunnelStream {
+    /// Copy data both ways between the stream and a connection until both
+    /// directions are done.
+    ///
+    /// Each EOF is passed along as a write shutdown, so half-closed
+    /// connections keep working. Once the client closes its sessions, the
+    /// connection is no longer read from, and the remote host gets an EOF
+    /// after what was already read.
+    ///
+    /// Resets are passed along too: if the connection is reset, so is the
+    /// connection to the remote host, and the other way around.
+    pub fn relay(mut self, conn: TcpStream) -> Box<dyn Future<Item = (), Error = Error>> {
+        let conn = Rc::new(conn);
+        let closed = mem::replace(&mut self.closed, Box::new(empty()));
+        let reset = self.reset.take();
+        let (read_half, write_half) = self.split();
+        let reads = ReadStream::new(SharedStream(conn.clone()), MAX_READ_SIZE);
+        let upload = TakeUntil::new(reads, closed)
+            .forward(WriteSink::new(write_half))
+            .then(move |res| {
+                if let (Err(ref e), Some(reset)) = (&res, reset) {
+                    if is_reset(e) {
+                        reset.send(()).ok();
+                    }
+                }
+                res
+            });
+        let download = ReadStream::new(read_half, MAX_READ_SIZE)
+            .forward(WriteSink::new(SharedStream(conn.clone())));
+        Box::new(upload.join(download).map(|_| ()).map_err(move |e| {
+            // Closing the connection without lingering resets it.
+            if is_reset(&e) {
+                conn.set_linger(Some(Duration::from_secs(0))).ok();
+            }
+            Error::from(e)
+        }))
+    }
+
+    /// Reset the connection to the remote host rather than sending it an
+    /// EOF. Anything not delivered yet is thrown away, and the stream can't
+    /// be used afterwards.
+    pub fn reset(&mut self) {
+        if let Some(reset) = self.reset.take() {
+            reset.send(()).ok();
+        }
+        self.outgoing = None;
+        self.failed = true;
+    }
+
+    /// Check if the session has failed, and report the error the first time.
+    fn check_failure(&mut self) -> io::Result<()> {
+        if self.failed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "session failed"));
+        }
+        let res = match self.failure {
+            Some(ref mut failure) => failure.poll(),
+            None => return Ok(())
+        };
+        match res {
+            Ok(Async::Ready(e)) => {
+                self.failure = None;
+                self.failed = true;
+                Err(e.into())
+            },
+            Ok(Async::NotReady) => Ok(()),
+            Err(_) => {
+                self.failure = None;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Read for TunnelStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.buffer.is_empty() {
+            self.check_failure()?;
+            match self.incoming.poll() {
+                Ok(Async::Ready(Some(data))) => self.buffer = data,
+                Ok(Async::Ready(None)) | Err(_) => {
+                    // The download ends early if the session fails.
+                    self.check_failure()?;
+                    return Ok(0);
+                },
+                Ok(Async::NotReady) => return Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+        let size = buf.len().min(self.buffer.len());
+        buf[..size].copy_from_slice(&self.buffer[..size]);
+        self.buffer.drain(..size);
+        Ok(size)
+    }
+}
+
+impl Write for TunnelStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_failure()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let sender = match self.outgoing {
+            Some(ref mut sender) => sender,
+            None => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "write after shutdown"))
+        };
+        match sender.start_send(buf.to_vec()) {
+            Ok(AsyncSink::Ready) => Ok(buf.len()),
+            Ok(AsyncSink::NotReady(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(_) => Err(session_closed())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_failure()?;
+        match self.outgoing {
+            Some(ref mut sender) => {
+                match sender.poll_complete() {
+                    Ok(Async::Ready(_)) => Ok(()),
+                    Ok(Async::NotReady) => Err(io::ErrorKind::WouldBlock.into()),
+                    Err(_) => Err(session_closed())
+                }
+            },
+            None => Ok(())
+        }
+    }
+}
+
+impl AsyncRead for TunnelStream {}
+
+impl AsyncWrite for TunnelStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        // The upload ends once the queued chunks are sent and every sender is
+        // gone.
+        self.outgoing = None;
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Start a task which carries the data written to a new stream to `run`, and
+/// the data `run` produces back to the stream.
+///
+/// `closed` resolves when the client wants the stream's upload to end.
+pub fn spawn_stream<F>(
+    handle: &Handle,
+    closed: Box<dyn Future<Item = (), Error = ()>>,
+    run: F
+) -> TunnelStream
+    where F: FnOnce(Chunks, ChunkSink) -> Box<dyn Future<Item = (), Error = Error>>
+{
+    let (outgoing, upload) = mpsc::channel(QUEUE_SIZE);
+    let (download, incoming) = mpsc::channel(QUEUE_SIZE);
+    let (failure_sender, failure) = oneshot::channel();
+    let (reset, reset_receiver) = oneshot::channel();
+    // The session fails right away on a reset, dropping whatever is queued.
+    let reset_receiver = Box::new(reset_receiver.then(|res| {
+        match res {
+            Ok(()) => Err(Error::Reset("local connection was reset".to_owned())),
+            Err(_) => Ok(())
+        }
+    }));
+    let upload = Coalesce::new(upload.map_err(|_| stream_dropped()), MAX_READ_SIZE);
+    let chunks = Box::new(FailOn::new(upload, reset_receiver));
+    let sink = Box::new(download.sink_map_err(|_| stream_dropped()));
+    handle.spawn(run(chunks, sink).then(move |res| {
+        if let Err(e) = res {
+            failure_sender.send(e).ok();
+        }
+        Ok(())
+    }));
+    TunnelStream{
+        outgoing: Some(outgoing),
+        incoming,
+        buffer: Vec::new(),
+        closed,
+        reset: Some(reset),
+        failure: Some(failure),
+        failed: false
+    }
+}
+
+fn session_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "session closed")
+}
+
+fn stream_dropped() -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "tunnel stream was dropped"))
+}
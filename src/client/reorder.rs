@@ -0,0 +1,132 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use futures::{Async, Poll, Stream};
+
+/// A piece of a download, tagged with its offset in the stream.
+pub enum Piece {
+    Data(u64, Vec<u8>),
+    Eof(u64)
+}
+
+/// A stream which puts out-of-order pieces back in order.
+///
+/// Pieces may arrive more than once or overlap, in which case only the bytes
+/// which haven't been yielded yet are used. The stream ends once every byte
+/// before the EOF has been yielded.
+pub struct Reassemble<S: Stream<Item = Piece>> {
+    pieces: S,
+    offset: Rc<Cell<u64>>,
+    pending: BTreeMap<u64, Vec<u8>>,
+    eof: Option<u64>
+}
+
+impl<S: Stream<Item = Piece>> Reassemble<S> {
+    /// Create a reassembler which updates `offset` as data is yielded.
+    pub fn new(pieces: S, offset: Rc<Cell<u64>>) -> Reassemble<S> {
+        Reassemble{pieces, offset, pending: BTreeMap::new(), eof: None}
+    }
+
+    /// Take the pending data which starts at `offset`, dropping any pieces
+    /// which were already yielded.
+    fn take_pending(&mut self, offset: u64) -> Option<Vec<u8>> {
+        while let Some(&start) = self.pending.keys().next() {
+            if start > offset {
+                break;
+            }
+            let data = self.pending.remove(&start).unwrap();
+            if start + data.len() as u64 > offset {
+                return Some(data[(offset - start) as usize..].to_vec());
+            }
+        }
+        None
+    }
+}
+
+impl<S: Stream<Item = Piece>> Stream for Reassemble<S> {
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let offset = self.offset.get();
+            if let Some(data) = self.take_pending(offset) {
+                self.offset.set(offset + data.len() as u64);
+                return Ok(Async::Ready(Some(data)));
+            }
+            if self.eof == Some(offset) {
+                return Ok(Async::Ready(None));
+            }
+            match self.pieces.poll()? {
+                Async::Ready(Some(Piece::Data(start, data))) => {
+                    if start + data.len() as u64 > offset {
+                        let pending = self.pending.entry(start).or_default();
+                        if data.len() > pending.len() {
+                            *pending = data;
+                        }
+                    }
+                },
+                Async::Ready(Some(Piece::Eof(end))) => {
+                    if self.eof.is_none() {
+                        self.eof = Some(end);
+                    }
+                },
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use futures::stream::iter_ok;
+
+    use super::*;
+
+    fn reassemble(pieces: Vec<Piece>) -> (Vec<u8>, u64) {
+        let offset = Rc::new(Cell::new(0));
+        let stream = Reassemble::new(iter_ok::<_, ()>(pieces), offset.clone());
+        let data = stream.concat2().wait().unwrap();
+        (data, offset.get())
+    }
+
+    fn data(offset: u64, data: &str) -> Piece {
+        Piece::Data(offset, data.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn out_of_order() {
+        let pieces = vec![data(6, "world"), data(0, "hello "), Piece::Eof(11)];
+        assert_eq!(reassemble(pieces), (b"hello world".to_vec(), 11));
+    }
+
+    #[test]
+    fn duplicates() {
+        let pieces = vec![data(6, "world"), data(0, "hello "), data(6, "world"),
+            data(0, "hello "), Piece::Eof(11), Piece::Eof(11)];
+        assert_eq!(reassemble(pieces), (b"hello world".to_vec(), 11));
+    }
+
+    #[test]
+    fn overlaps() {
+        let pieces = vec![data(6, "world"), data(0, "hel"), data(1, "ello wor"), data(3, "lo"),
+            Piece::Eof(11)];
+        assert_eq!(reassemble(pieces), (b"hello world".to_vec(), 11));
+    }
+
+    #[test]
+    fn eof_before_data() {
+        let pieces = vec![Piece::Eof(5), data(3, "lo"), data(0, "hel"), data(5, "extra")];
+        assert_eq!(reassemble(pieces), (b"hello".to_vec(), 5));
+    }
+
+    #[test]
+    fn cut_off() {
+        // Without an EOF, the stream ends with the pieces, minus any gaps.
+        let pieces = vec![data(0, "hello"), data(8, "ld")];
+        assert_eq!(reassemble(pieces), (b"hello".to_vec(), 5));
+    }
+}
@@ -0,0 +1,567 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{Future, IntoFuture, Sink, Stream};
+use futures::future::{Either, Loop, join_all, loop_fn};
+use futures::stream::{iter_result, repeat};
+use hyper;
+use hyper::{Body, Headers, Method, Request, StatusCode};
+use hyper::client::{Client, HttpConnector};
+use hyper::header::{Connection, Host};
+use tokio_core::reactor::Timeout;
+
+use error::Error;
+use generate_session_id;
+use headers::{ProtocolHeader, SessionSecret};
+use mac::{OpenKey, SessionKey};
+use proof::user_key;
+use protocol::{Agreement, Features, Hello};
+use response::{ResponseFrame, Status};
+use stream::{StreamDecoder, StreamFrame};
+use client::{ChunkSink, Chunks, Context, HostInfo, MAX_FRAME_SIZE};
+use client::future_util::retry;
+use client::reorder::{Piece, Reassemble};
+use client::stream_upload::upload_streamed;
+
+const BLOCKED_RETRY_MILLIS: u64 = 50;
+
+/// How many times to try a request which keeps failing to get through the
+/// proxy.
+const MAX_ATTEMPTS: usize = 3;
+const FAILED_RETRY_MILLIS: u64 = 500;
+
+/// The oldest protocol version we speak, which is the first with
+/// ResponseFrames.
+const MIN_PROTOCOL_VERSION: u16 = 2;
+
+#[derive(Clone)]
+pub struct SessionInfo {
+    pub context: Context,
+    pub host_info: HostInfo,
+    pub id: String,
+
+    /// The protocol version and features agreed on with the server.
+    pub protocol: Agreement,
+
+    /// The key to sign requests with, if the server checks them.
+    pub key: Option<SessionKey>
+}
+
+impl SessionInfo {
+    /// Build a request for an API call on this session.
+    pub fn request(&self, api: &str, query: Option<String>, data: Option<Vec<u8>>) -> Request {
+        let mut query = match query {
+            Some(query) => format!("v={}&{}", self.protocol.version, query),
+            None => format!("v={}", self.protocol.version)
+        };
+        if let Some(ref key) = self.key {
+            let mac = key.sign_request(api, &self.id, &query, data.as_deref().unwrap_or(&[]));
+            query = format!("{}&mac={}", query, mac);
+        }
+        build_request(&self.host_info, api, &self.id, Some(query), data)
+    }
+
+    /// Check if the server supports a feature, warning if it was requested
+    /// but isn't available.
+    pub fn supports(&self, requested: bool, feature: Features) -> bool {
+        if requested && !self.protocol.features.contains(feature) {
+            warn!("server does not support {}", feature.encode());
+            return false;
+        }
+        requested
+    }
+}
+
+/// Create a new proxy session, negotiating the protocol to use for it.
+///
+/// The resulting future yields the session ID, the protocol agreement, and
+/// the key to sign the session's requests with, if any.
+pub fn establish_session(
+    context: &Context,
+    host_info: &HostInfo
+) -> Box<dyn Future<Item = (String, Agreement, Option<SessionKey>), Error = Error>> {
+    let handle = context.handle.clone();
+    let context = context.clone();
+    let sizers = context.sizers.clone();
+    let host_info = host_info.clone();
+    // If a response gets lost, the session it created simply times out, so
+    // connecting is safe to retry.
+    retry(&handle, MAX_ATTEMPTS, Duration::from_millis(FAILED_RETRY_MILLIS), move || {
+        let client = context.client.clone();
+        let host_info = host_info.clone();
+        let sizers = sizers.clone();
+        login_proof(&context, &host_info).and_then(move |(proof, open_key)| {
+            let mut req = build_request(&host_info, "connect", &proof, None, None);
+            let hello = Hello{min_version: MIN_PROTOCOL_VERSION, ..Hello::new(Features::all())};
+            req.headers_mut().set(ProtocolHeader(hello.encode()));
+            send_request(&client, req).map(move |res| (res, proof, open_key))
+        }).and_then(move |((status, headers, body), proof, open_key)| {
+            if status != StatusCode::Ok {
+                return Err(http_error(status, &body));
+            }
+            sizers.update_limit(&headers);
+            // Servers which predate negotiation don't send an agreement.
+            let protocol = match headers.get::<ProtocolHeader>() {
+                Some(ProtocolHeader(x)) => Agreement::parse(x)?,
+                None => Agreement::legacy()
+            };
+            if protocol.version < MIN_PROTOCOL_VERSION {
+                return Err(Error::Unsupported(format!("server is too old (protocol version {})",
+                    protocol.version)));
+            } else if !protocol.features.contains(Features::ORDERED) {
+                let msg = "server does not support ordered transfers";
+                return Err(Error::Unsupported(msg.to_owned()));
+            }
+            let frame = check_frame(ResponseFrame::decode(&body)?)?;
+            let id = String::from_utf8_lossy(&frame.payload).into_owned();
+            let key = if protocol.features.contains(Features::MAC) {
+                let secret = headers.get::<SessionSecret>()
+                    .and_then(|sealed| open_key.open(&id, sealed))
+                    .ok_or_else(|| Error::Protocol("invalid session secret".to_owned()))?;
+                Some(SessionKey::derive(&secret, &id, &proof))
+            } else {
+                warn!("server does not check MACs, so the session ID is all it takes to use \
+                    the session");
+                None
+            };
+            Ok((id, protocol, key))
+        })
+    })
+}
+
+/// Create a proof to log in with, along with the key which opens the
+/// secrets of the sessions it creates.
+///
+/// A client with a user name first fetches a nonce from the server and
+/// answers it with its key or password, so each proof can only be used once.
+/// Servers which don't issue nonces for passwords get a time-based proof
+/// instead.
+pub fn login_proof(
+    context: &Context,
+    host_info: &HostInfo
+) -> Box<dyn Future<Item = (String, OpenKey), Error = Error>> {
+    let clock_offset = context.clock_offset.get().unwrap_or(0);
+    let user = match host_info.user {
+        Some(ref user) => user.clone(),
+        None => {
+            let open_key = OpenKey::Shared(host_info.password.clone().into_bytes());
+            return Box::new(Ok((host_info.proof(clock_offset), open_key)).into_future());
+        }
+    };
+    let query = format!("v={}", MIN_PROTOCOL_VERSION);
+    let req = build_request(host_info, "challenge", &user, Some(query), None);
+    let host_info = host_info.clone();
+    Box::new(send_request(&context.client, req).and_then(|(status, _, body)| {
+        if status != StatusCode::Ok {
+            return Err(http_error(status, &body));
+        }
+        let frame = check_frame(ResponseFrame::decode(&body)?)?;
+        Ok(String::from_utf8_lossy(&frame.payload).into_owned())
+    }).then(move |res| {
+        match (res, host_info.key.as_ref()) {
+            (Ok(nonce), Some(key)) => {
+                let proof = format!("{}:{}", user, key.sign_challenge(&user, &nonce));
+                Ok((proof, OpenKey::KeyPair(key.clone())))
+            },
+            (Ok(challenge), None) => host_info.answer(&user, &challenge),
+            (Err(Error::Unsupported(_)), None) => {
+                let open_key = OpenKey::Shared(user_key(&user, &host_info.password).into_bytes());
+                Ok((host_info.proof(clock_offset), open_key))
+            },
+            (Err(e), _) => Err(e)
+        }
+    }))
+}
+
+/// Ask the server for its time, yielding how many seconds its clock is ahead
+/// of ours.
+pub fn measure_clock(
+    context: &Context,
+    host_info: &HostInfo
+) -> Box<dyn Future<Item = i64, Error = Error>> {
+    let query = format!("v={}", MIN_PROTOCOL_VERSION);
+    let req = build_request(host_info, "time", "now", Some(query), None);
+    let sent = Instant::now();
+    let sent_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    Box::new(send_request(&context.client, req).and_then(move |(status, _, body)| {
+        if status != StatusCode::Ok {
+            return Err(http_error(status, &body));
+        }
+        let frame = check_frame(ResponseFrame::decode(&body)?)?;
+        let server_time: u64 = String::from_utf8_lossy(&frame.payload).parse()
+            .map_err(|_| Error::Protocol("invalid time from server".to_owned()))?;
+        // The server read its clock about halfway through the request, and
+        // its time is rounded down to the second.
+        let local_time = (sent_at + sent.elapsed() / 2).as_secs_f64();
+        Ok((server_time as f64 + 0.5 - local_time).round() as i64)
+    }))
+}
+
+/// Carry data between the local end of a session and the server through
+/// HTTP requests.
+pub fn relay_session(
+    info: SessionInfo,
+    chunks: Chunks,
+    sink: ChunkSink
+) -> Box<dyn Future<Item = (), Error = Error>> {
+    let stream_upload = info.supports(info.context.stream_upload, Features::STREAM_UPLOAD);
+    let download = download_stream(&info);
+    let info_1 = info.clone();
+    let read_future = if stream_upload {
+        upload_streamed(info, chunks)
+    } else {
+        upload_pipelined(info, chunks)
+    };
+    // Pass a reset of the local connection on to the server.
+    let read_future: Box<dyn Future<Item = (), Error = Error>> = Box::new(read_future
+        .or_else(move |e| {
+            match e {
+                Error::Reset(_) => Either::A(send_reset(&info_1).then(|_| Err(e))),
+                e => Either::B(Err(e).into_future())
+            }
+        }));
+    let write_future: Box<dyn Future<Item = (), Error = Error>> = Box::new(sink
+        .send_all(download)
+        .map(|_| ()));
+    Box::new(join_all(vec![read_future, write_future]).map(|_| ()))
+}
+
+/// Upload chunks with discrete requests, keeping up to `pipeline` of them in
+/// flight at once.
+fn upload_pipelined(
+    info: SessionInfo,
+    chunks: Chunks
+) -> Box<dyn Future<Item = (), Error = Error>> {
+    let info_1 = info.clone();
+    let mut upload_offset = 0u64;
+    Box::new(chunks
+        .map(move |buf| {
+            let offset = upload_offset;
+            upload_offset += buf.len() as u64;
+            upload_chunk(info_1.clone(), offset, buf)
+        })
+        .buffered(info.context.pipeline)
+        .fold(0u64, |total, size| Ok::<u64, Error>(total + size as u64))
+        .and_then(move |total| send_eof(&info, total)))
+}
+
+/// Send a chunk of data on the session, starting at the given stream offset.
+///
+/// The chunk is split up according to the current upload chunk size, which is
+/// adjusted as requests succeed or get rejected by the proxy.
+///
+/// The resulting future yields the size of the chunk.
+pub fn upload_chunk(
+    info: SessionInfo,
+    offset: u64,
+    chunk: Vec<u8>
+) -> Box<dyn Future<Item = usize, Error = Error>> {
+    let total_size = chunk.len();
+    Box::new(loop_fn((0usize, 0usize), move |(state, failures)| {
+        let sizers = info.context.sizers.clone();
+        let handle = info.context.handle.clone();
+        let size = sizers.upload.borrow().size();
+        let end = total_size.min(state + size);
+        let piece = chunk[state..end].to_vec();
+        let query = format!("offset={}", offset + state as u64);
+        let start_time = Instant::now();
+        frame_request(&info, info.request("upload", Some(query), Some(piece)))
+            .then(move |res| {
+                let (headers, frame) = match res {
+                    Ok(x) => x,
+                    Err(e) => {
+                        sizers.upload.borrow_mut().record_failure();
+                        // Ordered uploads are safe to repeat.
+                        if e.is_retryable() && failures + 1 < MAX_ATTEMPTS {
+                            return Ok(Loop::Continue((state, failures + 1)));
+                        }
+                        return Err(e);
+                    }
+                };
+                sizers.update_limit(&headers);
+                match frame.status {
+                    Status::Ok => {
+                        sizers.upload.borrow_mut().record_success(end - state,
+                            start_time.elapsed());
+                        Ok(if end == total_size {
+                            Loop::Break(total_size)
+                        } else {
+                            Loop::Continue((end, 0))
+                        })
+                    },
+                    // Nothing was written, so we can retry with a smaller chunk.
+                    Status::TooLarge if failures + 1 < MAX_ATTEMPTS => {
+                        sizers.upload.borrow_mut().record_failure();
+                        Ok(Loop::Continue((state, failures + 1)))
+                    },
+                    // The server is buffering too much data, so retry the
+                    // same piece after a delay.
+                    Status::WouldBlock => Ok(Loop::Continue((state, failures))),
+                    _ => Err(Error::from_frame(&frame))
+                }
+            })
+            .and_then(move |next| {
+                match next {
+                    Loop::Continue((x, _)) if x == state => {
+                        // Nothing was accepted, so back off briefly.
+                        let delay = Duration::from_millis(BLOCKED_RETRY_MILLIS);
+                        Either::A(Timeout::new(delay, &handle).unwrap()
+                            .map(move |_| next)
+                            .map_err(Error::from))
+                    },
+                    _ => Either::B(Ok(next).into_future())
+                }
+            })
+    }))
+}
+
+/// Send an EOF to the remote end after the given number of bytes.
+pub fn send_eof(info: &SessionInfo, offset: u64) -> Box<dyn Future<Item = (), Error = Error>> {
+    let info = info.clone();
+    let delay = Duration::from_millis(FAILED_RETRY_MILLIS);
+    Box::new(retry(&info.context.handle.clone(), MAX_ATTEMPTS, delay, move || {
+        api_request(&info, "close", Some(format!("offset={}", offset)), None)
+    }).map(|_| ()))
+}
+
+/// Reset the connection to the remote host, or just send it an EOF if the
+/// server can't reset it.
+pub fn send_reset(info: &SessionInfo) -> Box<dyn Future<Item = (), Error = Error>> {
+    let api = if info.protocol.features.contains(Features::RESET) { "reset" } else { "close" };
+    Box::new(api_request(info, api, None, None).map(|_| ()))
+}
+
+/// Get a stream of chunks of data from the session.
+///
+/// Chunks may arrive out of order, in which case they are put back in order.
+fn download_stream(info: &SessionInfo) -> Box<dyn Stream<Item = Vec<u8>, Error = Error>> {
+    let offset = Rc::new(Cell::new(0u64));
+    let pieces = if info.supports(info.context.stream_download, Features::STREAM_DOWNLOAD) {
+        streamed_pieces(info, offset.clone())
+    } else {
+        polled_pieces(info, offset.clone())
+    };
+    Box::new(Reassemble::new(pieces, offset).filter(|data| !data.is_empty()))
+}
+
+/// Poll for pieces of the download, keeping up to `pipeline` download
+/// requests in flight at once.
+fn polled_pieces(
+    info: &SessionInfo,
+    offset: Rc<Cell<u64>>
+) -> Box<dyn Stream<Item = Piece, Error = Error>> {
+    Box::new(repeat(info.clone())
+        .map(move |info| {
+            let sizer = info.context.sizers.download.clone();
+            let query = format!("max={}&offset={}", sizer.borrow().size(), offset.get());
+            let start_time = Instant::now();
+            api_request(&info, "download", Some(query), None).then(move |res| {
+                match res {
+                    Ok(ref frame) if !frame.payload.is_empty() => {
+                        sizer.borrow_mut().record_success(frame.payload.len(),
+                            start_time.elapsed());
+                    },
+                    Err(_) => sizer.borrow_mut().record_failure(),
+                    _ => ()
+                }
+                res
+            })
+        })
+        .buffer_unordered(info.context.pipeline)
+        .map(|frame| {
+            if frame.status == Status::WouldBlock {
+                // Nothing to read yet.
+                None
+            } else if frame.eof {
+                Some(Piece::Eof(frame.offset))
+            } else {
+                Some(Piece::Data(frame.offset, frame.payload))
+            }
+        })
+        .filter_map(|x| x))
+}
+
+/// Receive pieces of the download through streaming responses.
+///
+/// Whenever a response ends (e.g. because the proxy cut it off), a new one is
+/// requested starting at the first byte we are missing.
+fn streamed_pieces(
+    info: &SessionInfo,
+    offset: Rc<Cell<u64>>
+) -> Box<dyn Stream<Item = Piece, Error = Error>> {
+    Box::new(repeat(info.clone())
+        .and_then(move |info| {
+            let handle = info.context.handle.clone();
+            let offset = offset.clone();
+            let delay = Duration::from_millis(FAILED_RETRY_MILLIS);
+            retry(&handle, MAX_ATTEMPTS, delay, move || {
+                let size = info.context.sizers.download.borrow().size();
+                let query = format!("max={}&offset={}", size, offset.get());
+                info.context.client.request(info.request("stream", Some(query), None))
+                    .map_err(transport_error)
+                    .and_then(|resp| {
+                        let status = resp.status();
+                        if status == StatusCode::Ok {
+                            return Either::A(Ok(stream_body_pieces(resp.body())).into_future());
+                        }
+                        Either::B(resp.body().concat2()
+                            .map_err(transport_error)
+                            .and_then(move |body| Err(http_error(status, &body))))
+                    })
+            })
+        })
+        .flatten())
+}
+
+/// Decode the pieces in a streaming response body.
+///
+/// If the body is cut off, the stream simply ends.
+fn stream_body_pieces(body: Body) -> Box<dyn Stream<Item = Piece, Error = Error>> {
+    let mut decoder = StreamDecoder::new(MAX_FRAME_SIZE);
+    Box::new(body
+        .then(|res| Ok::<_, Error>(res.ok()))
+        .take_while(|chunk| Ok(chunk.is_some()))
+        .map(move |chunk| {
+            decoder.push(&chunk.unwrap());
+            let mut pieces = Vec::new();
+            loop {
+                match decoder.next_frame() {
+                    Ok(Some(StreamFrame::Data(offset, data))) => {
+                        pieces.push(Ok(Piece::Data(offset, data)));
+                    },
+                    Ok(Some(StreamFrame::Eof(offset))) => pieces.push(Ok(Piece::Eof(offset))),
+                    Ok(Some(StreamFrame::Error(msg))) => pieces.push(Err(stream_error(&msg))),
+                    Ok(Some(StreamFrame::Reset)) => {
+                        pieces.push(Err(reset_error()));
+                        break;
+                    },
+                    Ok(Some(StreamFrame::Mac(_))) => {
+                        pieces.push(Err(Error::Protocol("unexpected MAC frame".to_owned())));
+                        break;
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        pieces.push(Err(e));
+                        break;
+                    }
+                }
+            }
+            iter_result(pieces)
+        })
+        .flatten())
+}
+
+/// Make an API request on a session and fail if the server returns an
+/// error.
+///
+/// Failed requests are not retried here, since not every API is safe to
+/// repeat.
+fn api_request(
+    info: &SessionInfo,
+    api: &str,
+    query: Option<String>,
+    data: Option<Vec<u8>>
+) -> Box<dyn Future<Item = ResponseFrame, Error = Error>> {
+    Box::new(frame_request(info, info.request(api, query, data))
+        .and_then(|(_, frame)| check_frame(frame)))
+}
+
+/// Send a request on a session and decode the response, regardless of its
+/// status.
+///
+/// A 413 from the proxy is treated like one from the server.
+fn frame_request(
+    info: &SessionInfo,
+    req: Request
+) -> Box<dyn Future<Item = (Headers, ResponseFrame), Error = Error>> {
+    Box::new(send_request(&info.context.client, req)
+        .and_then(|(status, headers, body)| {
+            match status {
+                StatusCode::Ok => Ok((headers, ResponseFrame::decode(&body)?)),
+                StatusCode::PayloadTooLarge => {
+                    Ok((headers, ResponseFrame::error(Status::TooLarge, "chunk too large")))
+                },
+                _ => Err(http_error(status, &body))
+            }
+        }))
+}
+
+/// Send a request and return the raw response, regardless of status.
+fn send_request(
+    client: &Client<HttpConnector>,
+    req: Request
+) -> Box<dyn Future<Item = (StatusCode, Headers, Vec<u8>), Error = Error>> {
+    Box::new(client.request(req)
+        .map_err(transport_error)
+        .and_then(|resp| {
+            let status_code = resp.status();
+            let headers = resp.headers().clone();
+            resp.body().concat2()
+                .map_err(transport_error)
+                .map(move |body| (status_code, headers, body.to_vec()))
+        }))
+}
+
+fn build_request(
+    host_info: &HostInfo,
+    api: &str,
+    arg: &str,
+    query: Option<String>,
+    data: Option<Vec<u8>>
+) -> Request {
+    let cache_once = generate_session_id();
+    let method = if data.is_some() {
+        Method::Post
+    } else {
+        Method::Get
+    };
+    let query = query.map(|q| format!("?{}", q)).unwrap_or_default();
+    let mut req = Request::new(
+        method,
+        format!("http://{}/{}/{}/{}{}", host_info.proxy_addr, api, arg, cache_once, query)
+            .parse().unwrap()
+    );
+    req.headers_mut().set(Host::new(host_info.host.clone(), None));
+    req.headers_mut().set(Connection::keep_alive());
+    if let Some(x) = data {
+        req.set_body(x);
+    }
+    req
+}
+
+/// Fail if a response is an error.
+pub fn check_frame(frame: ResponseFrame) -> Result<ResponseFrame, Error> {
+    if frame.status.is_error() {
+        Err(Error::from_frame(&frame))
+    } else {
+        Ok(frame)
+    }
+}
+
+/// Create the error for a response which didn't come from the server.
+///
+/// Gateway errors usually mean the proxy had trouble reaching the server, so
+/// they are worth retrying.
+pub fn http_error(status: StatusCode, body: &[u8]) -> Error {
+    let msg = format!("bad response ({}): {}", status, String::from_utf8_lossy(body));
+    if status.is_server_error() {
+        Error::Transport(msg)
+    } else {
+        Error::Protocol(msg)
+    }
+}
+
+pub fn transport_error(e: hyper::Error) -> Error {
+    Error::Transport(format!("request failed: {}", e))
+}
+
+/// Create the error for a reset from the server.
+pub fn reset_error() -> Error {
+    Error::Reset("error from server: remote host reset the connection".to_owned())
+}
+
+/// Create the error for a StreamFrame error from the server.
+pub fn stream_error(msg: &str) -> Error {
+    Error::Upstream(format!("error from server: {}", msg))
+}
@@ -0,0 +1,227 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{Future, IntoFuture, Sink, Stream};
+use futures::future::Either;
+use futures::stream::iter_ok;
+use futures::sync::{mpsc, oneshot};
+use hyper;
+use hyper::{Body, Chunk, Method, StatusCode};
+use tokio_core::reactor::Timeout;
+
+use error::Error;
+use response::ResponseFrame;
+use stream::StreamFrame;
+use client::Chunks;
+use client::session::{SessionInfo, check_frame, http_error, send_eof, transport_error,
+    upload_chunk};
+
+/// The most data to send in one streaming request.
+const STREAM_UPLOAD_BYTES: usize = 1 << 20;
+
+/// How long to keep a streaming request open.
+///
+/// Ending requests periodically means that data still gets through proxies
+/// which buffer the entire request body before forwarding it.
+const STREAM_UPLOAD_MILLIS: u64 = 2000;
+
+type BodySender = mpsc::Sender<Result<Chunk, hyper::Error>>;
+type SharedUploader = Rc<RefCell<StreamUploader>>;
+
+/// Upload chunks through streaming request bodies.
+///
+/// Chunks are split up to fit the server's chunk size limit, which also caps
+/// the frames it accepts. If a streaming request fails, the data it may not
+/// have delivered is sent again with discrete uploads, which are used from
+/// then on.
+pub fn upload_streamed(
+    info: SessionInfo,
+    chunks: Chunks
+) -> Box<dyn Future<Item = (), Error = Error>> {
+    let uploader = Rc::new(RefCell::new(StreamUploader{
+        info: info.clone(),
+        sender: None,
+        generation: 0,
+        sent_size: 0,
+        retained: VecDeque::new(),
+        end_offset: 0,
+        failed: false,
+        last_response: None
+    }));
+    let uploader_1 = uploader.clone();
+    let sizers = info.context.sizers.clone();
+    Box::new(chunks
+        .map(move |chunk| {
+            let limit = sizers.upload.borrow().limit();
+            iter_ok(chunk.chunks(limit).map(<[u8]>::to_vec).collect::<Vec<_>>())
+        })
+        .flatten()
+        .for_each(move |chunk| StreamUploader::upload(&uploader, chunk))
+        .and_then(move |_| StreamUploader::finish(&uploader_1))
+        .and_then(move |total| send_eof(&info, total)))
+}
+
+struct StreamUploader {
+    info: SessionInfo,
+
+    /// The body of the open streaming request.
+    sender: Option<BodySender>,
+
+    /// Incremented whenever a streaming request is opened or closed.
+    generation: u64,
+    sent_size: usize,
+
+    /// Chunks which the server has not confirmed yet.
+    retained: VecDeque<(u64, Vec<u8>)>,
+    end_offset: u64,
+
+    /// Set once a streaming request fails.
+    failed: bool,
+    last_response: Option<oneshot::Receiver<()>>
+}
+
+impl StreamUploader {
+    fn upload(
+        uploader: &SharedUploader,
+        chunk: Vec<u8>
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        let mut up = uploader.borrow_mut();
+        let offset = up.end_offset;
+        up.end_offset += chunk.len() as u64;
+        if up.failed {
+            let mut chunks: Vec<_> = up.retained.drain(..).collect();
+            chunks.push((offset, chunk));
+            return upload_all(&up.info, chunks);
+        }
+        let sender = match up.sender.take() {
+            Some(sender) => sender,
+            None => StreamUploader::open(uploader, &mut up)
+        };
+        up.sent_size += chunk.len();
+        up.retained.push_back((offset, chunk.clone()));
+        let generation = up.generation;
+        let mut frame = StreamFrame::Data(offset, chunk).encode();
+        if let Some(ref key) = up.info.key {
+            let mac = StreamFrame::Mac(key.sign_frame(&up.info.id, &frame));
+            frame = mac.encode().into_iter().chain(frame).collect();
+        }
+        let uploader = uploader.clone();
+        Box::new(sender.send(Ok(Chunk::from(frame))).then(move |res| {
+            let mut up = uploader.borrow_mut();
+            if let Ok(sender) = res {
+                if up.generation == generation && up.sent_size < STREAM_UPLOAD_BYTES {
+                    up.sender = Some(sender);
+                } else if up.generation == generation {
+                    // Dropping the sender ends the request body.
+                    up.generation += 1;
+                }
+            }
+            // If the send failed, so did the request, and the chunk is still
+            // retained to be sent again.
+            Ok(())
+        }))
+    }
+
+    /// Start a new streaming request and get the sender for its body.
+    fn open(uploader: &SharedUploader, up: &mut StreamUploader) -> BodySender {
+        let (sender, body) = Body::pair();
+        let mut req = up.info.request("upstream", None, None);
+        req.set_method(Method::Post);
+        req.set_body(body);
+
+        up.generation += 1;
+        up.sent_size = 0;
+        let generation = up.generation;
+        let handle = &up.info.context.handle;
+
+        let (done_sender, done_receiver) = oneshot::channel();
+        up.last_response = Some(done_receiver);
+        let uploader_1 = uploader.clone();
+        handle.spawn(up.info.context.client.request(req)
+            .map_err(transport_error)
+            .and_then(|resp| {
+                let status = resp.status();
+                resp.body().concat2()
+                    .map_err(transport_error)
+                    .and_then(move |body| {
+                        if status != StatusCode::Ok {
+                            return Err(http_error(status, &body));
+                        }
+                        check_frame(ResponseFrame::decode(&body)?).map(|frame| frame.offset)
+                    })
+            })
+            .then(move |res| {
+                let mut up = uploader_1.borrow_mut();
+                match res {
+                    Ok(offset) => {
+                        loop {
+                            match up.retained.front() {
+                                Some(&(start, ref x)) if start + x.len() as u64 <= offset => (),
+                                _ => break
+                            }
+                            up.retained.pop_front();
+                        }
+                    },
+                    Err(e) => {
+                        if !up.failed {
+                            warn!("streaming upload failed, falling back to POSTs: {}", e);
+                        }
+                        up.failed = true;
+                        up.sender = None;
+                    }
+                }
+                done_sender.send(()).ok();
+                Ok(())
+            }));
+
+        let uploader_2 = uploader.clone();
+        let timeout = Timeout::new(Duration::from_millis(STREAM_UPLOAD_MILLIS), handle).unwrap();
+        handle.spawn(timeout.then(move |_| {
+            let mut up = uploader_2.borrow_mut();
+            if up.generation == generation {
+                up.sender = None;
+                up.generation += 1;
+            }
+            Ok(())
+        }));
+
+        sender
+    }
+
+    /// End the streaming request, wait for its response, and send any data
+    /// the server still hasn't confirmed.
+    ///
+    /// The resulting future yields the total number of bytes uploaded.
+    fn finish(uploader: &SharedUploader) -> Box<dyn Future<Item = u64, Error = Error>> {
+        let last_response = {
+            let mut up = uploader.borrow_mut();
+            up.sender = None;
+            up.generation += 1;
+            up.last_response.take()
+        };
+        let wait = match last_response {
+            Some(receiver) => Either::A(receiver.then(|_| Ok(()))),
+            None => Either::B(Ok::<(), Error>(()).into_future())
+        };
+        let uploader = uploader.clone();
+        Box::new(wait.and_then(move |_| {
+            let mut up = uploader.borrow_mut();
+            let chunks = up.retained.drain(..).collect();
+            let end_offset = up.end_offset;
+            upload_all(&up.info, chunks).map(move |_| end_offset)
+        }))
+    }
+}
+
+/// Send chunks with discrete uploads, one after another.
+fn upload_all(
+    info: &SessionInfo,
+    chunks: Vec<(u64, Vec<u8>)>
+) -> Box<dyn Future<Item = (), Error = Error>> {
+    let info = info.clone();
+    Box::new(iter_ok(chunks).for_each(move |(offset, chunk)| {
+        upload_chunk(info.clone(), offset, chunk).map(|_| ())
+    }))
+}
@@ -0,0 +1,160 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use futures::{Future, Sink, Stream};
+use futures::future::join_all;
+use futures::stream::{iter_result, once};
+use tokio_core::net::TcpStream;
+use tokio_io::AsyncRead;
+use tokio_io::io::write_all;
+
+use error::Error;
+use generate_session_id;
+use stream::{StreamDecoder, StreamFrame};
+use websocket::{HttpHead, Message, MessageDecoder, websocket_accept, websocket_key};
+use client::{ChunkSink, Chunks, Context, HostInfo, MAX_FRAME_SIZE, MAX_HEAD_SIZE,
+    MAX_READ_SIZE};
+use client::future_util::{ReadStream, WriteSink, read_until};
+use client::reorder::{Piece, Reassemble};
+use client::session::{login_proof, reset_error, stream_error};
+
+/// An upgraded connection, along with any data which arrived right after the
+/// handshake response.
+pub struct WebSocket {
+    conn: TcpStream,
+    leftover: Vec<u8>
+}
+
+/// Open a WebSocket to the server through the proxy.
+///
+/// Yields None if the upgrade was refused, in which case the session should
+/// use regular HTTP requests instead.
+pub fn open_websocket(
+    context: &Context,
+    host_info: &HostInfo
+) -> Box<dyn Future<Item = Option<WebSocket>, Error = Error>> {
+    let key = websocket_key();
+    let host_info = host_info.clone();
+    Box::new(login_proof(context, &host_info).and_then(move |(proof, _)| {
+        let request = format!("GET /ws/{}/{} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            proof, generate_session_id(), host_info.host, key);
+        host_info.proxy_addr.connect()
+            .and_then(move |conn| write_all(conn, request))
+            .and_then(|(conn, _)| read_until(conn, |data| {
+                HttpHead::parse(data).is_some() || data.len() >= MAX_HEAD_SIZE
+            }))
+            .map_err(|e| Error::Transport(format!("failed to open WebSocket: {}", e)))
+            .map(move |(conn, data)| {
+                let (head, size) = HttpHead::parse(&data)?;
+                if head.status() != Some(101) ||
+                    head.header("sec-websocket-accept") != Some(&websocket_accept(&key))
+                {
+                    warn!("WebSocket upgrade refused: {}", head.start_line);
+                    return None;
+                }
+                Some(WebSocket{conn, leftover: data[size..].to_vec()})
+            })
+    }))
+}
+
+/// Carry data between the local end of a session and the server through a
+/// WebSocket.
+///
+/// Each binary message carries a single StreamFrame. The connection is reliable,
+/// so the offsets only serve as a sanity check.
+pub fn relay_websocket(
+    websocket: WebSocket,
+    chunks: Chunks,
+    sink: ChunkSink
+) -> Box<dyn Future<Item = (), Error = Error>> {
+    let (ws_read, ws_write) = websocket.conn.split();
+
+    let upload_offset = Rc::new(Cell::new(0u64));
+    let end_offset = upload_offset.clone();
+    let frames = chunks
+        .map(move |data| {
+            let offset = upload_offset.get();
+            upload_offset.set(offset + data.len() as u64);
+            StreamFrame::Data(offset, data)
+        })
+        .chain(once(Ok(())).map(move |_| StreamFrame::Eof(end_offset.get())))
+        .then(|res| {
+            match res {
+                Err(Error::Reset(msg)) => Ok((StreamFrame::Reset, Some(Error::Reset(msg)))),
+                res => res.map(|frame| (frame, None))
+            }
+        });
+    // Folding rather than using send_all keeps the connection open for the
+    // download once the upload is done. A reset is sent along before the
+    // upload fails.
+    let upload_future: Box<dyn Future<Item = (), Error = Error>> = Box::new(frames
+        .fold(WriteSink::new(ws_write), |sink, (frame, error)| {
+            sink.send(Message::Binary(frame.encode()).encode(true))
+                .map_err(|e| Error::Transport(format!("error sending data: {}", e)))
+                .and_then(move |sink| match error {
+                    Some(e) => Err(e),
+                    None => Ok(sink)
+                })
+        })
+        .map(|_| ()));
+
+    let mut messages = MessageDecoder::new();
+    let mut frames = StreamDecoder::new(MAX_FRAME_SIZE);
+    let pieces = once(Ok(websocket.leftover))
+        .chain(ReadStream::new(ws_read, MAX_READ_SIZE))
+        .map_err(|e| Error::Transport(format!("error reading from WebSocket: {}", e)))
+        .map(move |data| {
+            messages.push(&data);
+            let mut error = None;
+            loop {
+                match messages.next_message() {
+                    Ok(Some(Message::Binary(data))) => frames.push(&data),
+                    Ok(Some(Message::Close)) => {
+                        error = Some(Error::Transport("WebSocket closed before EOF".to_owned()));
+                        break;
+                    },
+                    Ok(Some(_)) => (),
+                    Ok(None) => break,
+                    Err(e) => {
+                        error = Some(e);
+                        break;
+                    }
+                }
+            }
+            let mut pieces = Vec::new();
+            loop {
+                match frames.next_frame() {
+                    Ok(Some(StreamFrame::Data(offset, data))) => {
+                        pieces.push(Ok(Piece::Data(offset, data)));
+                    },
+                    Ok(Some(StreamFrame::Eof(offset))) => pieces.push(Ok(Piece::Eof(offset))),
+                    Ok(Some(StreamFrame::Error(msg))) => pieces.push(Err(stream_error(&msg))),
+                    Ok(Some(StreamFrame::Reset)) => {
+                        pieces.push(Err(reset_error()));
+                        break;
+                    },
+                    Ok(Some(StreamFrame::Mac(_))) => {
+                        pieces.push(Err(Error::Protocol("unexpected MAC frame".to_owned())));
+                        break;
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        pieces.push(Err(e));
+                        break;
+                    }
+                }
+            }
+            // Any data before the error is still delivered.
+            pieces.extend(error.map(Err));
+            iter_result(pieces)
+        })
+        .flatten();
+    let download = Reassemble::new(pieces, Rc::new(Cell::new(0u64)))
+        .filter(|data| !data.is_empty());
+    let download_future: Box<dyn Future<Item = (), Error = Error>> = Box::new(sink
+        .send_all(download)
+        .map(|_| ()));
+
+    Box::new(join_all(vec![upload_future, download_future]).map(|_| ()))
+}
@@ -0,0 +1,70 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use toml::Value;
+use toml::value::Table;
+
+/// Read a TOML config file, and parse it with `parse`, which gets the file's
+/// contents and the directory to resolve relative paths against.
+pub fn load_file<T, F>(path: &Path, parse: F) -> io::Result<T>
+    where F: FnOnce(&str, &Path) -> Result<T, String>
+{
+    let mut contents = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut contents)).map_err(|e| {
+        io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+    })?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    parse(&contents, dir).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+    })
+}
+
+/// Parse a config file, checking that it only has the given settings at its
+/// top level.
+pub fn parse_table(contents: &str, settings: &[&str]) -> Result<Table, String> {
+    let table = match contents.parse::<Value>().map_err(|e| e.to_string())? {
+        Value::Table(table) => table,
+        _ => return Err("expected a table".to_owned())
+    };
+    for name in table.keys() {
+        if !settings.contains(&name.as_str()) {
+            return Err(format!("unknown setting: {}", name));
+        }
+    }
+    Ok(table)
+}
+
+pub fn table_value<'a>(value: &'a Value, name: &str) -> Result<&'a Table, String> {
+    value.as_table().ok_or_else(|| format!("expected a table for {}", name))
+}
+
+pub fn string<'a>(value: &'a Value, name: &str) -> Result<&'a str, String> {
+    value.as_str().ok_or_else(|| bad_value(name))
+}
+
+pub fn integer<T: TryFrom<i64>>(value: &Value, name: &str) -> Result<T, String> {
+    value.as_integer().and_then(|x| T::try_from(x).ok()).ok_or_else(|| bad_value(name))
+}
+
+pub fn boolean(value: &Value, name: &str) -> Result<bool, String> {
+    value.as_bool().ok_or_else(|| bad_value(name))
+}
+
+/// Parse one address, or a list of them.
+pub fn addresses<T: FromStr>(value: &Value, name: &str) -> Result<Vec<T>, String> {
+    let values = match *value {
+        Value::Array(ref values) => values.iter().collect(),
+        _ => vec![value]
+    };
+    values.into_iter()
+        .map(|x| string(x, name)?.parse().map_err(|_| bad_value(name)))
+        .collect()
+}
+
+pub fn bad_value(name: &str) -> String {
+    format!("invalid value for {}", name)
+}
@@ -0,0 +1,50 @@
+use std::cell::{Cell, RefCell};
+
+use futures::{Future, IntoFuture};
+use futures::future::{Either, Shared, empty};
+use futures::sync::oneshot;
+
+/// Tracks whether a client or server is shutting down.
+///
+/// While draining, no new sessions are opened. When the sessions are closed,
+/// everything waiting on `closed()` is told to finish up.
+pub struct Drain {
+    draining: Cell<bool>,
+    close: RefCell<Option<oneshot::Sender<()>>>,
+    closed: Shared<oneshot::Receiver<()>>
+}
+
+impl Drain {
+    pub fn new() -> Drain {
+        let (close, closed) = oneshot::channel();
+        Drain{draining: Cell::new(false), close: RefCell::new(Some(close)), closed: closed.shared()}
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.get()
+    }
+
+    pub fn start(&self) {
+        self.draining.set(true);
+    }
+
+    /// Tell the sessions waiting on `closed()` to finish up.
+    pub fn close(&self) {
+        self.draining.set(true);
+        if let Some(close) = self.close.borrow_mut().take() {
+            close.send(()).ok();
+        }
+    }
+
+    /// Get a future which resolves once the sessions should be closed.
+    ///
+    /// It never resolves if the owner goes away without closing them.
+    pub fn closed(&self) -> Box<dyn Future<Item = (), Error = ()>> {
+        Box::new(self.closed.clone().then(|res| {
+            match res {
+                Ok(_) => Either::A(Ok(()).into_future()),
+                Err(_) => Either::B(empty())
+            }
+        }))
+    }
+}
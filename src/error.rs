@@ -0,0 +1,131 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use response::{ResponseFrame, Status};
+
+/// An error from any part of a tunnel.
+///
+/// The kind of error decides how to react to it. Transport errors are worth
+/// retrying, while the others mean the session can't go on as it is.
+#[derive(Debug)]
+pub enum Error {
+    /// A request didn't make it through the proxy, e.g. because the proxy
+    /// timed out or dropped the connection.
+    Transport(String),
+
+    /// A peer sent something malformed.
+    Protocol(String),
+
+    /// The peers have no protocol version or feature in common.
+    Unsupported(String),
+
+    /// The proof of the password was wrong or too old.
+    Auth(String),
+
+    /// The session doesn't exist, e.g. because it timed out.
+    Session(String),
+
+    /// Connecting to, reading from, or writing to the remote host failed.
+    Upstream(String),
+
+    /// The user is known but not allowed to do this.
+    Forbidden(String),
+
+    /// The server is turning clients away for now, e.g. after too many login
+    /// attempts or because it has too many sessions.
+    Busy(String),
+
+    /// A connection was reset, either to the remote host or on the client's
+    /// side, so the session is over.
+    Reset(String),
+
+    /// Reading from or writing to a local socket failed.
+    Io(io::Error)
+}
+
+impl Error {
+    /// Create the error for a failed API response.
+    pub fn from_status(status: Status, msg: &str) -> Error {
+        let msg = format!("error from server: {}", msg);
+        match status {
+            Status::AuthFailed => Error::Auth(msg),
+            Status::NoSession => Error::Session(msg),
+            Status::Unsupported => Error::Unsupported(msg),
+            Status::UpstreamError => Error::Upstream(msg),
+            Status::Forbidden => Error::Forbidden(msg),
+            Status::Busy => Error::Busy(msg),
+            Status::Reset => Error::Reset(msg),
+            _ => Error::Protocol(msg)
+        }
+    }
+
+    pub fn from_frame(frame: &ResponseFrame) -> Error {
+        Error::from_status(frame.status, &frame.message())
+    }
+
+    /// Get the status to report this error with.
+    pub fn status(&self) -> Status {
+        match *self {
+            Error::Transport(_) | Error::Protocol(_) => Status::InvalidRequest,
+            Error::Unsupported(_) => Status::Unsupported,
+            Error::Auth(_) => Status::AuthFailed,
+            Error::Session(_) => Status::NoSession,
+            Error::Upstream(_) | Error::Io(_) => Status::UpstreamError,
+            Error::Forbidden(_) => Status::Forbidden,
+            Error::Busy(_) => Status::Busy,
+            Error::Reset(_) => Status::Reset
+        }
+    }
+
+    pub fn to_frame(&self) -> ResponseFrame {
+        ResponseFrame::error(self.status(), &self.to_string())
+    }
+
+    /// Check if the operation which failed may succeed if it is tried again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(*self, Error::Transport(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Transport(ref msg) | Error::Protocol(ref msg) | Error::Unsupported(ref msg) |
+                Error::Auth(ref msg) | Error::Session(ref msg) | Error::Upstream(ref msg) |
+                Error::Forbidden(ref msg) | Error::Busy(ref msg) | Error::Reset(ref msg) => {
+                f.write_str(msg)
+            },
+            Error::Io(ref e) => write!(f, "local I/O error: {}", e)
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        // Errors which were passed through an I/O interface keep their kind.
+        if e.get_ref().is_some_and(|x| x.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            // Resets keep their kind, so they can be passed along as resets.
+            e @ Error::Reset(_) => io::Error::new(io::ErrorKind::ConnectionReset, e),
+            e => io::Error::other(e)
+        }
+    }
+}
+
+/// Check if an I/O error means that the peer reset the connection, rather
+/// than closing it normally.
+pub fn is_reset(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted)
+}
@@ -0,0 +1,174 @@
+use error::Error;
+use response::Status;
+
+/// The start of a raw tunnel handshake.
+///
+/// This can never be the start of an HTTP request, so the server can tell the
+/// two apart on the same listener.
+const MAGIC: &str = "SQUIDTUN/1 ";
+
+/// The longest handshake or reply line we accept.
+const MAX_LINE_SIZE: usize = 1024;
+
+/// The handshake which starts a raw tunnel, such as one made through an HTTP
+/// CONNECT request.
+///
+/// It is sent as a single line: the magic string, the proof, and the target.
+#[derive(Debug, PartialEq)]
+pub struct Handshake {
+    pub proof: String,
+
+    /// The address to connect to, or None for the server's default.
+    pub target: Option<String>
+}
+
+impl Handshake {
+    pub fn encode(&self) -> Vec<u8> {
+        let target = self.target.as_deref().unwrap_or("-");
+        format!("{}{} {}\r\n", MAGIC, self.proof, target).into_bytes()
+    }
+
+    /// Check if a buffer may hold the start of a handshake.
+    pub fn is_prefix(data: &[u8]) -> bool {
+        let size = data.len().min(MAGIC.len());
+        data[..size] == MAGIC.as_bytes()[..size]
+    }
+
+    /// Parse the handshake at the start of a buffer.
+    ///
+    /// Returns the handshake and its size, or None if it is incomplete. Fails
+    /// if the buffer does not hold a handshake.
+    pub fn parse(data: &[u8]) -> Result<Option<(Handshake, usize)>, Error> {
+        if !Handshake::is_prefix(data) {
+            return Err(Error::Protocol("not a handshake".to_owned()));
+        }
+        let (line, size) = match next_line(data)? {
+            Some(x) => x,
+            None => return Ok(None)
+        };
+        let mut parts = line[MAGIC.len()..].split(' ');
+        let proof = parts.next().unwrap_or("").to_owned();
+        let target = match parts.next() {
+            Some("-") => None,
+            Some(x) => Some(x.to_owned()),
+            None => return Err(Error::Protocol("invalid handshake".to_owned()))
+        };
+        Ok(Some((Handshake{proof, target}, size)))
+    }
+}
+
+/// The server's reply to a handshake.
+///
+/// After the handshake is accepted, the tunnel carries raw data in both
+/// directions. A rejection carries the same status an API request would get.
+#[derive(Debug, PartialEq)]
+pub enum HandshakeReply {
+    Accepted,
+    Rejected(Status, String)
+}
+
+impl HandshakeReply {
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            HandshakeReply::Accepted => format!("{}OK\r\n", MAGIC),
+            HandshakeReply::Rejected(status, ref e) => {
+                format!("{}ERR {} {}\r\n", MAGIC, status.code(), e.replace(['\r', '\n'], " "))
+            }
+        }.into_bytes()
+    }
+
+    /// Parse the reply at the start of a buffer.
+    ///
+    /// Returns the reply and its size, or None if it is incomplete.
+    pub fn parse(data: &[u8]) -> Result<Option<(HandshakeReply, usize)>, Error> {
+        if !Handshake::is_prefix(data) {
+            return Err(Error::Protocol("invalid handshake reply".to_owned()));
+        }
+        let (line, size) = match next_line(data)? {
+            Some(x) => x,
+            None => return Ok(None)
+        };
+        let status = &line[MAGIC.len()..];
+        if status == "OK" {
+            Ok(Some((HandshakeReply::Accepted, size)))
+        } else if let Some(rest) = status.strip_prefix("ERR ") {
+            let (code, msg) = rest.split_once(' ').unwrap_or((rest, ""));
+            let status = code.parse().ok().and_then(Status::from_code)
+                .ok_or_else(|| Error::Protocol("invalid handshake reply".to_owned()))?;
+            Ok(Some((HandshakeReply::Rejected(status, msg.to_owned()), size)))
+        } else {
+            Err(Error::Protocol("invalid handshake reply".to_owned()))
+        }
+    }
+}
+
+fn next_line(data: &[u8]) -> Result<Option<(String, usize)>, Error> {
+    match data.windows(2).position(|x| x == b"\r\n") {
+        Some(end) => Ok(Some((String::from_utf8_lossy(&data[..end]).into_owned(), end + 2))),
+        None if data.len() > MAX_LINE_SIZE => Err(Error::Protocol("handshake too long".to_owned())),
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_round_trip() {
+        let handshakes = vec![
+            Handshake{proof: "alice:abcd".to_owned(), target: Some("example.com:22".to_owned())},
+            Handshake{proof: "abcd".to_owned(), target: None}
+        ];
+        for handshake in handshakes {
+            let mut data = handshake.encode();
+            let size = data.len();
+            data.extend_from_slice(b"leftover");
+            assert_eq!(Handshake::parse(&data).unwrap(), Some((handshake, size)));
+        }
+    }
+
+    #[test]
+    fn incomplete_handshake() {
+        let data = Handshake{proof: "abcd".to_owned(), target: None}.encode();
+        assert!(Handshake::is_prefix(b"SQUID"));
+        assert_eq!(Handshake::parse(b"SQUID").unwrap(), None);
+        assert_eq!(Handshake::parse(&data[..data.len() - 1]).unwrap(), None);
+        assert!(Handshake::parse(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(Handshake::parse(b"SQUIDTUN/1 abcd\r\n").is_err());
+        let mut long = b"SQUIDTUN/1 ".to_vec();
+        long.resize(MAX_LINE_SIZE + 1, b'a');
+        assert!(Handshake::parse(&long).is_err());
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        let replies = vec![
+            HandshakeReply::Accepted,
+            HandshakeReply::Rejected(Status::Forbidden, "target not allowed".to_owned()),
+            HandshakeReply::Rejected(Status::Busy, String::new())
+        ];
+        for reply in replies {
+            let data = reply.encode();
+            let size = data.len();
+            assert_eq!(HandshakeReply::parse(&data).unwrap(), Some((reply, size)));
+        }
+    }
+
+    #[test]
+    fn reply_without_newlines() {
+        let reply = HandshakeReply::Rejected(Status::AuthFailed, "bad\r\nproof".to_owned());
+        let data = reply.encode();
+        let (reply, _) = HandshakeReply::parse(&data).unwrap().unwrap();
+        assert_eq!(reply, HandshakeReply::Rejected(Status::AuthFailed, "bad  proof".to_owned()));
+    }
+
+    #[test]
+    fn invalid_reply() {
+        assert_eq!(HandshakeReply::parse(b"SQUIDTUN/1 O").unwrap(), None);
+        assert!(HandshakeReply::parse(b"HTTP/1.1 200 OK\r\n").is_err());
+        assert!(HandshakeReply::parse(b"SQUIDTUN/1 MAYBE\r\n").is_err());
+        assert!(HandshakeReply::parse(b"SQUIDTUN/1 ERR 200 nope\r\n").is_err());
+        assert!(HandshakeReply::parse(b"SQUIDTUN/1 ERR x nope\r\n").is_err());
+    }
+}
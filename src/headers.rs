@@ -0,0 +1,16 @@
+header! {
+    /// The largest chunk the server accepts or sends per request.
+    (MaxChunkSize, "X-Squidtun-Max-Chunk") => [usize]
+}
+
+header! {
+    /// The client's Hello on a connect request, and the server's Agreement in
+    /// the response.
+    (ProtocolHeader, "X-Squidtun-Protocol") => [String]
+}
+
+header! {
+    /// The secret of a new session, sealed to the client's login, which the
+    /// session's key is derived from.
+    (SessionSecret, "X-Squidtun-Session-Secret") => [String]
+}
@@ -0,0 +1,172 @@
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use base64;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::{OsRng, Rng};
+use sha2::{Digest, Sha256};
+
+use uid::random_bytes;
+
+/// The key type at the start of each public key line.
+const PUBLIC_KEY_TYPE: &str = "squidtun-ed25519";
+
+/// The key type at the start of a private key file.
+const SECRET_KEY_TYPE: &str = "squidtun-ed25519-secret";
+
+/// An Ed25519 key pair which a client logs in with.
+#[derive(Clone)]
+pub struct KeyPair {
+    key: SigningKey
+}
+
+impl KeyPair {
+    /// Create a new random key pair.
+    pub fn generate() -> io::Result<KeyPair> {
+        let mut seed = [0u8; 32];
+        OsRng::new()?.fill_bytes(&mut seed);
+        Ok(KeyPair{key: SigningKey::from_bytes(&seed)})
+    }
+
+    /// Read a key pair from a file written by save().
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<KeyPair> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        let mut fields = contents.split_whitespace();
+        let seed = match (fields.next(), fields.next()) {
+            (Some(SECRET_KEY_TYPE), Some(data)) => base64::decode(data).ok(),
+            _ => None
+        };
+        match seed {
+            Some(ref seed) if seed.len() == 32 => {
+                let mut bytes = [0u8; 32];
+                bytes.copy_from_slice(seed);
+                Ok(KeyPair{key: SigningKey::from_bytes(&bytes)})
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid private key file"))
+        }
+    }
+
+    /// Write the key pair to a file which only the current user can read.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        writeln!(file, "{} {}", SECRET_KEY_TYPE, base64::encode(self.key.as_bytes()))
+    }
+
+    /// Get the line to add to the server's authorized keys file for `user`.
+    pub fn public_line(&self, user: &str) -> String {
+        format!("{} {} {}", PUBLIC_KEY_TYPE, base64::encode(self.key.verifying_key().as_bytes()),
+            user)
+    }
+
+    /// Sign a challenge from the server, yielding the proof to log in with,
+    /// without the user name.
+    pub fn sign_challenge(&self, user: &str, nonce: &str) -> String {
+        let signature = self.key.sign(&signed_message(user, nonce));
+        format!("{}-{}", nonce, to_hex(&signature.to_bytes()))
+    }
+
+    /// Work out the secret which agree_with() came up with for this key,
+    /// given the public half of its one-off key.
+    pub fn agree(&self, public: &[u8; 32]) -> [u8; 32] {
+        let shared = MontgomeryPoint(*public).mul_clamped(self.key.to_scalar_bytes());
+        agreed_secret(&shared, public)
+    }
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyPair({})", base64::encode(self.key.verifying_key().as_bytes()))
+    }
+}
+
+/// Parse a line of an authorized keys file, yielding the key and the user it
+/// belongs to.
+pub fn parse_public_line(line: &str) -> Option<(VerifyingKey, String)> {
+    let mut fields = line.split_whitespace();
+    if fields.next() != Some(PUBLIC_KEY_TYPE) {
+        return None;
+    }
+    let data = base64::decode(fields.next()?).ok()?;
+    let user = fields.next()?;
+    if data.len() != 32 || fields.next().is_some() {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&data);
+    Some((VerifyingKey::from_bytes(&bytes).ok()?, user.to_owned()))
+}
+
+/// Come up with a secret that only the holder of `key` can work out too,
+/// using a one-off X25519 key.
+///
+/// Yields the public half of the one-off key, which the holder needs to work
+/// out the secret, along with the secret.
+pub fn agree_with(key: &VerifyingKey) -> ([u8; 32], [u8; 32]) {
+    let mut ephemeral = [0u8; 32];
+    random_bytes(&mut ephemeral);
+    let public = MontgomeryPoint::mul_base_clamped(ephemeral).to_bytes();
+    let shared = key.to_montgomery().mul_clamped(ephemeral);
+    (public, agreed_secret(&shared, &public))
+}
+
+fn agreed_secret(shared: &MontgomeryPoint, public: &[u8; 32]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update(b"squidtun-agree:");
+    hash.update(shared.as_bytes());
+    hash.update(public);
+    hash.finalize().into()
+}
+
+/// Split a signed proof into its nonce and its signature.
+pub fn split_signed_proof(proof: &str) -> Option<(&str, Signature)> {
+    let index = proof.find('-')?;
+    let bytes = from_hex(&proof[index + 1..])?;
+    if bytes.len() != 64 {
+        return None;
+    }
+    let mut signature = [0u8; 64];
+    signature.copy_from_slice(&bytes);
+    Some((&proof[..index], Signature::from_bytes(&signature)))
+}
+
+/// Check a signature made by sign_challenge().
+pub fn verify_challenge(
+    key: &VerifyingKey,
+    user: &str,
+    nonce: &str,
+    signature: &Signature
+) -> bool {
+    key.verify_strict(&signed_message(user, nonce), signature).is_ok()
+}
+
+fn signed_message(user: &str, nonce: &str) -> Vec<u8> {
+    format!("squidtun-login:{}:{}", user, nonce).into_bytes()
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    let mut res = String::new();
+    for b in data {
+        write!(res, "{:02x}", b).unwrap();
+    }
+    res
+}
+
+pub fn from_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.is_ascii() || !data.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..data.len()).step_by(2).map(|i| u8::from_str_radix(&data[i..i + 2], 16).ok()).collect()
+}
@@ -0,0 +1,182 @@
+use std::fmt;
+
+use ed25519_dalek::VerifyingKey;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use keys::{KeyPair, agree_with, from_hex, to_hex};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The number of bytes of each MAC to send.
+pub const MAC_SIZE: usize = 16;
+
+/// A key which authenticates the requests on one session.
+///
+/// Both peers derive the key from a random secret which the server makes up
+/// for the session and seals to the client's login, so someone who only sees
+/// the session ID can't make requests for it.
+#[derive(Clone, Copy)]
+pub struct SessionKey([u8; 32]);
+
+impl SessionKey {
+    /// Derive the key for the session `id`, which was created by logging in
+    /// with `proof`.
+    pub fn derive(secret: &[u8; 32], id: &str, proof: &str) -> SessionKey {
+        let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+        mac.update(format!("squidtun-session:{}:{}", id, proof).as_bytes());
+        SessionKey(mac.finalize().into_bytes().into())
+    }
+
+    /// Sign an API request, given its query string without the MAC.
+    pub fn sign_request(&self, api: &str, id: &str, query: &str, body: &[u8]) -> String {
+        to_hex(&self.request_mac(api, id, query, body).finalize().into_bytes()[..MAC_SIZE])
+    }
+
+    pub fn check_request(&self, api: &str, id: &str, query: &str, body: &[u8], mac: &str) -> bool {
+        match from_hex(mac) {
+            Some(ref mac) if mac.len() == MAC_SIZE => {
+                self.request_mac(api, id, query, body).verify_truncated_left(mac).is_ok()
+            },
+            _ => false
+        }
+    }
+
+    /// Sign an encoded StreamFrame in a streaming upload.
+    pub fn sign_frame(&self, id: &str, frame: &[u8]) -> Vec<u8> {
+        self.frame_mac(id, frame).finalize().into_bytes()[..MAC_SIZE].to_vec()
+    }
+
+    pub fn check_frame(&self, id: &str, frame: &[u8], mac: &[u8]) -> bool {
+        mac.len() == MAC_SIZE && self.frame_mac(id, frame).verify_truncated_left(mac).is_ok()
+    }
+
+    fn request_mac(&self, api: &str, id: &str, query: &str, body: &[u8]) -> HmacSha256 {
+        let mut mac = self.mac();
+        mac.update(format!("request:{}:{}:{}:", api, id, query).as_bytes());
+        mac.update(body);
+        mac
+    }
+
+    fn frame_mac(&self, id: &str, frame: &[u8]) -> HmacSha256 {
+        let mut mac = self.mac();
+        mac.update(format!("frame:{}:", id).as_bytes());
+        mac.update(frame);
+        mac
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.0).unwrap()
+    }
+}
+
+/// What a new session's secret is sealed to, so that only the client which
+/// logged in can open it.
+#[derive(Clone)]
+pub enum SealKey {
+    /// A secret which the client shares with the server, such as a password.
+    Shared(Vec<u8>),
+
+    /// The public key which the client signed in with.
+    Public(VerifyingKey)
+}
+
+impl SealKey {
+    /// Seal the secret of the session `id`.
+    pub fn seal(&self, id: &str, secret: &[u8; 32]) -> String {
+        match *self {
+            SealKey::Shared(ref key) => seal_shared(key, id, secret),
+            SealKey::Public(ref key) => {
+                let (public, key) = agree_with(key);
+                format!("{}-{}", to_hex(&public), seal_shared(&key, id, secret))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SealKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SealKey::Shared(_) => write!(f, "SealKey::Shared(..)"),
+            SealKey::Public(ref key) => write!(f, "SealKey::Public({:?})", key)
+        }
+    }
+}
+
+/// What a client opens the sealed secrets of its sessions with.
+#[derive(Clone)]
+pub enum OpenKey {
+    Shared(Vec<u8>),
+    KeyPair(KeyPair)
+}
+
+impl OpenKey {
+    /// Open the sealed secret of the session `id`, failing if it wasn't
+    /// sealed to this key.
+    pub fn open(&self, id: &str, sealed: &str) -> Option<[u8; 32]> {
+        match *self {
+            OpenKey::Shared(ref key) => open_shared(key, id, sealed),
+            OpenKey::KeyPair(ref pair) => {
+                let index = sealed.find('-')?;
+                let public = from_hex(&sealed[..index]).filter(|x| x.len() == 32)?;
+                let mut bytes = [0u8; 32];
+                bytes.copy_from_slice(&public);
+                open_shared(&pair.agree(&bytes), id, &sealed[index + 1..])
+            }
+        }
+    }
+}
+
+/// Mask a secret with a MAC of the session ID, and add a MAC of the result
+/// so that the client can tell whether it was sealed to its key.
+fn seal_shared(key: &[u8], id: &str, secret: &[u8; 32]) -> String {
+    let pad = seal_mac(key, "pad", id, &[]).finalize().into_bytes();
+    let mut sealed: Vec<u8> = secret.iter().zip(pad.iter()).map(|(a, b)| a ^ b).collect();
+    let tag = seal_mac(key, "tag", id, &sealed).finalize().into_bytes();
+    sealed.extend_from_slice(&tag[..MAC_SIZE]);
+    to_hex(&sealed)
+}
+
+fn open_shared(key: &[u8], id: &str, sealed: &str) -> Option<[u8; 32]> {
+    let sealed = from_hex(sealed).filter(|x| x.len() == 32 + MAC_SIZE)?;
+    let (masked, tag) = sealed.split_at(32);
+    seal_mac(key, "tag", id, masked).verify_truncated_left(tag).ok()?;
+    let pad = seal_mac(key, "pad", id, &[]).finalize().into_bytes();
+    let mut secret = [0u8; 32];
+    for (i, b) in secret.iter_mut().enumerate() {
+        *b = masked[i] ^ pad[i];
+    }
+    Some(secret)
+}
+
+fn seal_mac(key: &[u8], label: &str, id: &str, data: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(format!("squidtun-seal-{}:{}:", label, id).as_bytes());
+    mac.update(data);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use keys::parse_public_line;
+    use super::*;
+
+    #[test]
+    fn shared_seals() {
+        let secret = [7u8; 32];
+        let sealed = SealKey::Shared(b"hunter2".to_vec()).seal("id", &secret);
+        assert_eq!(OpenKey::Shared(b"hunter2".to_vec()).open("id", &sealed), Some(secret));
+        assert_eq!(OpenKey::Shared(b"wrong".to_vec()).open("id", &sealed), None);
+        assert_eq!(OpenKey::Shared(b"hunter2".to_vec()).open("other", &sealed), None);
+    }
+
+    #[test]
+    fn public_key_seals() {
+        let (pair, other) = (KeyPair::generate().unwrap(), KeyPair::generate().unwrap());
+        let secret = [7u8; 32];
+        let (key, _) = parse_public_line(&pair.public_line("alice")).unwrap();
+        let sealed = SealKey::Public(key).seal("id", &secret);
+        assert_eq!(OpenKey::KeyPair(pair).open("id", &sealed), Some(secret));
+        assert_eq!(OpenKey::KeyPair(other).open("id", &sealed), None);
+    }
+}
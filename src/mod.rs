@@ -1,8 +1,51 @@
+extern crate base64;
+extern crate curve25519_dalek;
+extern crate ed25519_dalek;
+extern crate futures;
+extern crate hmac;
+#[macro_use]
+extern crate hyper;
+extern crate pbkdf2;
 extern crate rand;
 extern crate sha1;
+extern crate sha2;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate toml;
 
+#[macro_use]
+extern crate log;
+
+mod client;
+mod config;
+mod drain;
+mod error;
+mod handshake;
+mod headers;
+mod keys;
+mod mac;
 mod proof;
+mod protocol;
+mod resolve;
+mod response;
+mod server;
+mod stream;
+mod stream_util;
 mod uid;
+mod websocket;
 
-pub use proof::{current_proof, check_proof};
+pub use client::{ClientOptions, ClientProfile, TunnelClient, TunnelStream};
+pub use error::Error;
+pub use handshake::{Handshake, HandshakeReply};
+pub use keys::KeyPair;
+pub use mac::SealKey;
+pub use proof::{current_proof, current_user_proof, check_proof, split_proof, user_key};
+pub use protocol::{Agreement, Features, Hello, MAX_VERSION, MIN_VERSION};
+pub use resolve::RemoteAddr;
+pub use response::{ResponseFrame, Status};
+pub use server::{AuthFuture, Authenticator, CommandAuth, Credential, CredentialsAuth,
+    Limits, Login, PasswordAuth, Policies, Policy, PublicKeyAuth, ServerBuilder, ServerConfig,
+    TunnelService};
+pub use stream::{StreamDecoder, StreamFrame, decode_offset, encode_offset};
 pub use uid::generate_session_id;
+pub use websocket::{HttpHead, Message, MessageDecoder, websocket_accept, websocket_key};
@@ -1,7 +1,15 @@
 use std::fmt::Write;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2_hmac;
 use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use keys::{from_hex, to_hex};
+
+/// How many rounds of PBKDF2 new password verifiers use.
+pub const PASSWORD_ROUNDS: u32 = 4096;
 
 pub fn proof_for_time(password: &str, time: u64) -> String {
     let mut sh = Sha1::new();
@@ -15,12 +23,11 @@ pub fn proof_for_time(password: &str, time: u64) -> String {
 }
 
 pub fn current_proof(password: &str) -> String {
-    let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-    proof_for_time(password, epoch)
+    proof_for_time(password, unix_time())
 }
 
 pub fn check_proof(password: &str, proof: &str, allowed_diff: u64) -> bool {
-    let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let epoch = unix_time();
     for time in (epoch - allowed_diff)..(epoch + allowed_diff) {
         if proof_for_time(password, time) == proof {
             return true;
@@ -28,3 +35,130 @@ pub fn check_proof(password: &str, proof: &str, allowed_diff: u64) -> bool {
     }
     false
 }
+
+/// Get the current time in seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Derive the key that a named user proves they know from their password,
+/// for servers which check time-based user proofs themselves (such as with
+/// an auth command).
+///
+/// Anyone who has the key can log in as the user without knowing the
+/// password, so it must be kept as secret as the password itself.
+pub fn user_key(user: &str, password: &str) -> String {
+    let mut sh = Sha1::new();
+    sh.update(format!("squidtun:{}:{}", user, password).as_bytes());
+    sh.digest().to_string()
+}
+
+/// Create a proof that `user` knows their password, in the form
+/// "<user>:<proof>".
+pub fn current_user_proof(user: &str, password: &str) -> String {
+    format!("{}:{}", user, current_proof(&user_key(user, password)))
+}
+
+/// Stretch a password with the salt and number of rounds that the server
+/// keeps for the user, yielding the key that the user answers challenges
+/// with.
+pub fn client_key(password: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut salted = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut salted);
+    let mut mac = Hmac::<Sha256>::new_from_slice(&salted).unwrap();
+    mac.update(b"squidtun-client-key");
+    mac.finalize().into_bytes().into()
+}
+
+/// Get the verifier which the server stores in place of a client key.
+///
+/// A client key can't be recovered from its verifier, so the verifier isn't
+/// enough to answer challenges with.
+pub fn stored_key(client_key: &[u8; 32]) -> [u8; 32] {
+    Sha256::digest(client_key).into()
+}
+
+/// Answer a challenge from the server, yielding the proof to log in with,
+/// without the user name.
+///
+/// The answer is the client key masked by a MAC of the nonce keyed with the
+/// stored key, so the server can unmask it and check it against the stored
+/// key, while the answer is useless for any other nonce.
+pub fn answer_challenge(client_key: &[u8; 32], user: &str, nonce: &str) -> String {
+    let mask = challenge_mask(&stored_key(client_key), user, nonce);
+    let answer: Vec<u8> = client_key.iter().zip(mask.iter()).map(|(a, b)| a ^ b).collect();
+    format!("{}-{}", nonce, to_hex(&answer))
+}
+
+/// Check an answer made by answer_challenge(), yielding the client key which
+/// made it if it matches the stored key.
+pub fn check_answer(stored: &[u8; 32], user: &str, nonce: &str, answer: &str) -> Option<[u8; 32]> {
+    let answer = from_hex(answer)?;
+    if answer.len() != 32 {
+        return None;
+    }
+    let mask = challenge_mask(stored, user, nonce);
+    let mut key = [0u8; 32];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = answer[i] ^ mask[i];
+    }
+    // Compare in constant time, so that timing doesn't give the verifier away.
+    let diff = stored_key(&key).iter().zip(stored.iter()).fold(0, |acc, (a, b)| acc | (a ^ b));
+    if diff == 0 {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+fn challenge_mask(stored: &[u8; 32], user: &str, nonce: &str) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(stored).unwrap();
+    mac.update(format!("squidtun-password-login:{}:{}", user, nonce).as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+/// Split a proof into the user it names, if any, and the proof itself.
+pub fn split_proof(proof: &str) -> (Option<&str>, &str) {
+    match proof.rfind(':') {
+        Some(index) => (Some(&proof[..index]), &proof[index + 1..]),
+        None => (None, proof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_expire() {
+        let time = unix_time();
+        assert!(check_proof("secret", &proof_for_time("secret", time), 60));
+        assert!(check_proof("secret", &proof_for_time("secret", time - 30), 60));
+        assert!(!check_proof("secret", &proof_for_time("secret", time - 120), 60));
+        assert!(!check_proof("other", &proof_for_time("secret", time), 60));
+    }
+
+    #[test]
+    fn user_proofs() {
+        let proof = current_user_proof("alice", "hunter2");
+        let (user, proof) = split_proof(&proof);
+        assert_eq!(user, Some("alice"));
+        assert!(check_proof(&user_key("alice", "hunter2"), proof, 60));
+        assert!(!check_proof(&user_key("bob", "hunter2"), proof, 60));
+        assert_eq!(split_proof("abcd"), (None, "abcd"));
+    }
+
+    #[test]
+    fn challenge_answers() {
+        let key = client_key("hunter2", b"salt", 16);
+        let stored = stored_key(&key);
+        let answer = answer_challenge(&key, "alice", "1234");
+        let (nonce, answer) = answer.split_at(answer.find('-').unwrap());
+        assert_eq!(nonce, "1234");
+        assert_eq!(check_answer(&stored, "alice", "1234", &answer[1..]), Some(key));
+        assert_eq!(check_answer(&stored, "alice", "5678", &answer[1..]), None);
+        assert_eq!(check_answer(&stored, "bob", "1234", &answer[1..]), None);
+        let other = stored_key(&client_key("hunter2", b"pepper", 16));
+        assert_eq!(check_answer(&other, "alice", "1234", &answer[1..]), None);
+    }
+}
@@ -0,0 +1,270 @@
+use std::ops::BitOr;
+
+use error::Error;
+
+/// The oldest version of the wire protocol this build speaks.
+///
+/// Version 1 is the protocol as it was before versions were negotiated.
+/// Peers which don't negotiate at all are assumed to speak it.
+pub const MIN_VERSION: u16 = 1;
+
+/// The newest version of the wire protocol this build speaks.
+///
+/// Version 2 replaces the ad hoc response bodies of version 1 with
+/// ResponseFrames. Clients pass the version they use in the "v" query
+/// parameter of every request after connecting.
+pub const MAX_VERSION: u16 = 2;
+
+/// A set of optional protocol features.
+///
+/// A feature may only be used if both peers support it. Features which a peer
+/// doesn't recognize are ignored, so new ones can be added without breaking
+/// older peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    /// Uploads and downloads tagged with stream offsets, so they can be
+    /// pipelined and retried.
+    pub const ORDERED: Features = Features(1);
+
+    /// Downloads through long-lived streaming responses.
+    pub const STREAM_DOWNLOAD: Features = Features(1 << 1);
+
+    /// Uploads through streaming request bodies.
+    pub const STREAM_UPLOAD: Features = Features(1 << 2);
+
+    /// Sessions carried over WebSockets.
+    pub const WEBSOCKET: Features = Features(1 << 3);
+
+    /// Raw tunnels started with a handshake, e.g. through HTTP CONNECT.
+    pub const RAW_TUNNEL: Features = Features(1 << 4);
+
+    /// Requests on a session signed with a key derived from the login.
+    pub const MAC: Features = Features(1 << 5);
+
+    /// Resets passed along in both directions, as opposed to plain EOFs or
+    /// errors.
+    pub const RESET: Features = Features(1 << 6);
+
+    const NAMES: &'static [(Features, &'static str)] = &[
+        (Features::ORDERED, "ordered"),
+        (Features::STREAM_DOWNLOAD, "stream-download"),
+        (Features::STREAM_UPLOAD, "stream-upload"),
+        (Features::WEBSOCKET, "websocket"),
+        (Features::RAW_TUNNEL, "raw-tunnel"),
+        (Features::MAC, "mac"),
+        (Features::RESET, "reset")
+    ];
+
+    pub fn empty() -> Features {
+        Features(0)
+    }
+
+    /// Get every feature this build supports.
+    pub fn all() -> Features {
+        Features::NAMES.iter().fold(Features::empty(), |acc, &(x, _)| acc | x)
+    }
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    /// Get these features without any of `other`.
+    pub fn without(self, other: Features) -> Features {
+        Features(self.0 & !other.0)
+    }
+
+    /// Encode the features as a comma-separated list of names, or "-" if
+    /// there are none.
+    pub fn encode(self) -> String {
+        let names = Features::NAMES.iter()
+            .filter(|&&(x, _)| self.contains(x))
+            .map(|&(_, name)| name)
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            "-".to_owned()
+        } else {
+            names.join(",")
+        }
+    }
+
+    /// Parse a list of feature names, skipping any we don't know.
+    pub fn parse(s: &str) -> Features {
+        s.split(',').fold(Features::empty(), |acc, name| {
+            match Features::NAMES.iter().find(|&&(_, x)| x == name.trim()) {
+                Some(&(x, _)) => acc | x,
+                None => acc
+            }
+        })
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+/// What a client supports, sent when it establishes a session.
+///
+/// Encoded as the range of versions followed by the features, e.g.
+/// "1-2 ordered,stream-download".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub features: Features
+}
+
+impl Hello {
+    /// Create a hello for every version this build speaks.
+    pub fn new(features: Features) -> Hello {
+        Hello{min_version: MIN_VERSION, max_version: MAX_VERSION, features}
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}-{} {}", self.min_version, self.max_version, self.features.encode())
+    }
+
+    pub fn parse(s: &str) -> Result<Hello, Error> {
+        let mut parts = s.trim().splitn(2, ' ');
+        let versions = parts.next().unwrap_or("");
+        let (min, max) = versions.split_once('-').unwrap_or((versions, versions));
+        let min_version = min.parse().map_err(|_| invalid_version())?;
+        let max_version = max.parse().map_err(|_| invalid_version())?;
+        let features = Features::parse(parts.next().unwrap_or(""));
+        Ok(Hello{min_version, max_version, features})
+    }
+
+    /// Pick the newest version both sides speak, and the features both
+    /// sides support.
+    pub fn negotiate(&self, supported: Features) -> Result<Agreement, Error> {
+        let version = self.max_version.min(MAX_VERSION);
+        if version < self.min_version || version < MIN_VERSION {
+            return Err(Error::Unsupported(format!(
+                "unsupported protocol versions {}-{} (server speaks {}-{})",
+                self.min_version, self.max_version, MIN_VERSION, MAX_VERSION)));
+        }
+        Ok(Agreement{version, features: self.features.intersection(supported)})
+    }
+}
+
+/// The protocol version and features a session uses, sent back by the server.
+///
+/// Encoded as the version followed by the features, e.g. "1 ordered".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Agreement {
+    pub version: u16,
+    pub features: Features
+}
+
+impl Agreement {
+    /// The agreement assumed for a peer which doesn't negotiate.
+    ///
+    /// Such peers predate negotiation, so they get version 1 and are trusted
+    /// to only use features they know about. They can't sign requests, so
+    /// their sessions never get a key.
+    pub fn legacy() -> Agreement {
+        Agreement{version: 1, features: Features::all().without(Features::MAC)}
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{} {}", self.version, self.features.encode())
+    }
+
+    pub fn parse(s: &str) -> Result<Agreement, Error> {
+        let mut parts = s.trim().splitn(2, ' ');
+        let version = parts.next().unwrap_or("").parse::<u16>().map_err(|_| invalid_version())?;
+        if !(MIN_VERSION..=MAX_VERSION).contains(&version) {
+            return Err(Error::Unsupported(format!("unsupported protocol version {}", version)));
+        }
+        Ok(Agreement{version, features: Features::parse(parts.next().unwrap_or(""))})
+    }
+}
+
+fn invalid_version() -> Error {
+    Error::Protocol("invalid protocol version".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn features_round_trip() {
+        assert_eq!(Features::parse(&Features::all().encode()), Features::all());
+        let features = Features::ORDERED | Features::WEBSOCKET;
+        assert_eq!(features.encode(), "ordered,websocket");
+        assert_eq!(Features::parse("ordered,websocket"), features);
+        assert_eq!(Features::empty().encode(), "-");
+        assert_eq!(Features::parse("-"), Features::empty());
+    }
+
+    #[test]
+    fn unknown_features_are_skipped() {
+        assert_eq!(Features::parse("ordered, teleport ,mac"), Features::ORDERED | Features::MAC);
+        assert_eq!(Features::parse(""), Features::empty());
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = Hello::new(Features::STREAM_DOWNLOAD | Features::RESET);
+        assert_eq!(hello.encode(), "1-2 stream-download,reset");
+        assert_eq!(Hello::parse(&hello.encode()).unwrap(), hello);
+        let hello = Hello::parse("2").unwrap();
+        assert_eq!((hello.min_version, hello.max_version), (2, 2));
+        assert_eq!(hello.features, Features::empty());
+    }
+
+    #[test]
+    fn invalid_hello() {
+        assert!(Hello::parse("").is_err());
+        assert!(Hello::parse("1-").is_err());
+        assert!(Hello::parse("x-2 ordered").is_err());
+    }
+
+    #[test]
+    fn negotiate() {
+        let hello = Hello{min_version: 1, max_version: 7, features: Features::all()};
+        let agreement = hello.negotiate(Features::ORDERED | Features::MAC).unwrap();
+        assert_eq!(agreement.version, MAX_VERSION);
+        assert_eq!(agreement.features, Features::ORDERED | Features::MAC);
+        let hello = Hello{min_version: 1, max_version: 1, features: Features::empty()};
+        assert_eq!(hello.negotiate(Features::all()).unwrap().version, 1);
+    }
+
+    #[test]
+    fn negotiate_without_common_version() {
+        let hello = Hello{min_version: MAX_VERSION + 1, max_version: MAX_VERSION + 2,
+            features: Features::all()};
+        match hello.negotiate(Features::all()) {
+            Err(Error::Unsupported(_)) => (),
+            x => panic!("unexpected result: {:?}", x)
+        }
+    }
+
+    #[test]
+    fn agreement_round_trip() {
+        let agreement = Agreement{version: 2, features: Features::ORDERED};
+        assert_eq!(agreement.encode(), "2 ordered");
+        assert_eq!(Agreement::parse(&agreement.encode()).unwrap(), agreement);
+        assert_eq!(Agreement::parse("1").unwrap().features, Features::empty());
+        assert!(Agreement::parse("").is_err());
+        assert!(Agreement::parse(&format!("{} ordered", MAX_VERSION + 1)).is_err());
+    }
+
+    #[test]
+    fn legacy_agreement() {
+        let agreement = Agreement::legacy();
+        assert_eq!(agreement.version, 1);
+        assert!(agreement.features.contains(Features::ORDERED));
+        assert!(!agreement.features.contains(Features::MAC));
+    }
+}
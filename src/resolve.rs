@@ -0,0 +1,253 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{Future, IntoFuture};
+use futures::future::{Either, Loop, loop_fn};
+use futures::sync::oneshot;
+use tokio_core::net::TcpStream;
+
+use error::Error;
+
+/// How long to keep the addresses of a host before looking them up again.
+const LOOKUP_TTL: Duration = Duration::from_secs(60);
+
+/// The address of a host to connect to, which is either an IP address or a
+/// hostname to look up.
+///
+/// The addresses of a hostname are kept for a while, and shared by every
+/// clone. They are looked up again if connecting to all of them fails.
+#[derive(Clone)]
+pub struct RemoteAddr {
+    inner: Inner
+}
+
+#[derive(Clone)]
+enum Inner {
+    Addr(SocketAddr),
+    Host{
+        name: String,
+        port: u16,
+        found: Rc<RefCell<Option<Lookup>>>
+    }
+}
+
+/// The addresses that a hostname was found to have.
+struct Lookup {
+    addrs: Vec<SocketAddr>,
+    time: Instant
+}
+
+impl RemoteAddr {
+    /// Get the addresses to try, looking up the hostname if they aren't
+    /// known.
+    ///
+    /// Lookups run on a thread of their own, so they don't hold up the
+    /// reactor.
+    pub fn resolve(&self) -> Box<dyn Future<Item = Vec<SocketAddr>, Error = io::Error>> {
+        let (name, port, found) = match self.inner {
+            Inner::Addr(addr) => return Box::new(Ok(vec![addr]).into_future()),
+            Inner::Host{ref name, port, ref found} => (name.clone(), port, found.clone())
+        };
+        if let Some(ref lookup) = *found.borrow() {
+            if lookup.time.elapsed() < LOOKUP_TTL {
+                return Box::new(Ok(lookup.addrs.clone()).into_future());
+            }
+        }
+        let (sender, receiver) = oneshot::channel();
+        let host = name.clone();
+        thread::spawn(move || {
+            let res = (host.as_str(), port).to_socket_addrs().map(|x| x.collect::<Vec<_>>());
+            sender.send(res).ok();
+        });
+        Box::new(receiver
+            .map_err(|_| io::Error::other("lookup thread failed"))
+            .and_then(|res| res)
+            .then(move |res| {
+                match res {
+                    Ok(ref addrs) if addrs.is_empty() => {
+                        let msg = format!("no addresses for {}", name);
+                        Err(io::Error::new(io::ErrorKind::NotFound, msg))
+                    },
+                    Ok(addrs) => {
+                        let lookup = Lookup{addrs: addrs.clone(), time: Instant::now()};
+                        *found.borrow_mut() = Some(lookup);
+                        Ok(addrs)
+                    },
+                    Err(e) => {
+                        let msg = format!("failed to look up {}: {}", name, e);
+                        Err(io::Error::new(e.kind(), msg))
+                    }
+                }
+            }))
+    }
+
+    /// Drop the addresses that were looked up, so that the next connection
+    /// looks them up again.
+    pub fn forget(&self) {
+        if let Inner::Host{ref found, ..} = self.inner {
+            found.borrow_mut().take();
+        }
+    }
+
+    /// Look up the host if needed, and connect to the first of its addresses
+    /// which accepts.
+    pub fn connect(&self) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+        let remote = self.clone();
+        Box::new(self.resolve().and_then(move |addrs| {
+            connect_any(addrs).map_err(move |e| {
+                remote.forget();
+                io::Error::new(e.kind(), format!("{}: {}", remote, e))
+            })
+        }))
+    }
+}
+
+/// Parses addresses such as "127.0.0.1:22", "[::1]:22" or "example.com:22".
+impl FromStr for RemoteAddr {
+    type Err = Error;
+
+    fn from_str(addr: &str) -> Result<RemoteAddr, Error> {
+        if let Ok(addr) = addr.parse::<SocketAddr>() {
+            return Ok(RemoteAddr::from(addr));
+        }
+        let invalid = || Error::Protocol(format!("invalid address: {}", addr));
+        let index = addr.rfind(':').ok_or_else(invalid)?;
+        let (name, port) = (&addr[..index], &addr[index + 1..]);
+        if name.is_empty() || name.contains(|c: char| c == ':' || c.is_whitespace()) {
+            return Err(invalid());
+        }
+        Ok(RemoteAddr{
+            inner: Inner::Host{
+                name: name.to_owned(),
+                port: port.parse().map_err(|_| invalid())?,
+                found: Rc::new(RefCell::new(None))
+            }
+        })
+    }
+}
+
+impl From<SocketAddr> for RemoteAddr {
+    fn from(addr: SocketAddr) -> RemoteAddr {
+        RemoteAddr{inner: Inner::Addr(addr)}
+    }
+}
+
+/// Addresses are equal if they are the same IP address, or the same hostname
+/// (ignoring case) and port.
+impl PartialEq for RemoteAddr {
+    fn eq(&self, other: &RemoteAddr) -> bool {
+        match (&self.inner, &other.inner) {
+            (Inner::Addr(x), Inner::Addr(y)) => x == y,
+            (Inner::Host{name: x, port: x_port, ..}, Inner::Host{name: y, port: y_port, ..}) => {
+                x.eq_ignore_ascii_case(y) && x_port == y_port
+            },
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for RemoteAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.inner {
+            Inner::Addr(ref addr) => write!(f, "{}", addr),
+            Inner::Host{ref name, port, ..} => write!(f, "{}:{}", name, port)
+        }
+    }
+}
+
+impl fmt::Debug for RemoteAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RemoteAddr({})", self)
+    }
+}
+
+/// Connect to each address in turn until one of them accepts, yielding the
+/// last error if none do.
+pub fn connect_any(
+    addrs: Vec<SocketAddr>
+) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+    let no_addresses = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+    Box::new(loop_fn((addrs.into_iter(), no_addresses), |(mut addrs, last_error)| {
+        match addrs.next() {
+            Some(addr) => Either::A(TcpStream::connect2(&addr).then(move |res| {
+                match res {
+                    Ok(stream) => Ok(Loop::Break(stream)),
+                    Err(e) => {
+                        debug!("failed to connect to {}: {}", addr, e);
+                        Ok(Loop::Continue((addrs, e)))
+                    }
+                }
+            })),
+            None => Either::B(Err(last_error).into_future())
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    /// Get an address which refuses connections.
+    fn closed_addr() -> SocketAddr {
+        net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let addr: SocketAddr = "127.0.0.1:22".parse().unwrap();
+        assert_eq!("127.0.0.1:22".parse::<RemoteAddr>().unwrap(), RemoteAddr::from(addr));
+        let addr: SocketAddr = "[::1]:22".parse().unwrap();
+        assert_eq!("[::1]:22".parse::<RemoteAddr>().unwrap(), RemoteAddr::from(addr));
+        let host = "Example.com:22".parse::<RemoteAddr>().unwrap();
+        assert_eq!(host, "example.com:22".parse().unwrap());
+        assert_eq!(host.to_string(), "Example.com:22");
+        assert!(host != "example.com:23".parse().unwrap());
+        for addr in &["example.com", ":22", "example.com:", "example.com:x", "::1:22",
+                "exa mple.com:22", "example.com:70000"] {
+            assert!(addr.parse::<RemoteAddr>().is_err(), "parsed {}", addr);
+        }
+    }
+
+    #[test]
+    fn connect_to_any_address() {
+        let mut core = Core::new().unwrap();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = core.run(connect_any(vec![closed_addr(), addr])).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+
+        let err = core.run(connect_any(vec![closed_addr()])).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        let err = core.run(connect_any(Vec::new())).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn connect_to_hostname() {
+        let mut core = Core::new().unwrap();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let remote: RemoteAddr = format!("localhost:{}", port).parse().unwrap();
+        let addrs = core.run(remote.resolve()).unwrap();
+        assert!(addrs.contains(&SocketAddr::from(([127, 0, 0, 1], port))), "{:?}", addrs);
+        // Any other address for localhost refuses, since only 127.0.0.1 is
+        // listening.
+        let stream = core.run(remote.connect()).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+
+        drop(listener);
+        assert!(core.run(remote.connect()).is_err());
+        let missing: RemoteAddr = "squidtun.invalid:22".parse().unwrap();
+        assert!(core.run(missing.resolve()).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use error::Error;
+use stream::{decode_offset, encode_offset};
+
+const HEADER_SIZE: usize = 14;
+
+const FLAG_EOF: u8 = 1;
+const FLAG_MORE_DATA: u8 = 1 << 1;
+
+/// The outcome of an API request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+
+    /// Nothing could be done right now, but the request may be retried.
+    WouldBlock,
+
+    /// The request was malformed or used an unknown API.
+    InvalidRequest,
+
+    /// The proof of the password was wrong or too old.
+    AuthFailed,
+
+    /// The session doesn't exist, e.g. because it timed out.
+    NoSession,
+
+    /// The request body was larger than the server allows.
+    TooLarge,
+
+    /// The request needs a protocol version or feature the server lacks.
+    Unsupported,
+
+    /// Connecting to, reading from, or writing to the remote host failed.
+    UpstreamError,
+
+    /// The user isn't allowed to do this, e.g. because of their policy.
+    Forbidden,
+
+    /// The server is turning clients away for now.
+    Busy,
+
+    /// The connection to the remote host was reset, and the session is gone.
+    Reset
+}
+
+impl Status {
+    const CODES: &'static [(Status, u8)] = &[
+        (Status::Ok, 0),
+        (Status::WouldBlock, 1),
+        (Status::InvalidRequest, 2),
+        (Status::AuthFailed, 3),
+        (Status::NoSession, 4),
+        (Status::TooLarge, 5),
+        (Status::Unsupported, 6),
+        (Status::UpstreamError, 7),
+        (Status::Forbidden, 8),
+        (Status::Busy, 9),
+        (Status::Reset, 10)
+    ];
+
+    pub fn code(self) -> u8 {
+        Status::CODES.iter().find(|&&(x, _)| x == self).unwrap().1
+    }
+
+    pub fn from_code(code: u8) -> Option<Status> {
+        Status::CODES.iter().find(|&&(_, x)| x == code).map(|&(x, _)| x)
+    }
+
+    /// Check if the request failed, as opposed to succeeding or blocking.
+    pub fn is_error(self) -> bool {
+        self != Status::Ok && self != Status::WouldBlock
+    }
+}
+
+/// A typed response to an API request, used from protocol version 2 on.
+///
+/// Encoded as a status byte, a flags byte, an 8-byte stream offset, a 4-byte
+/// window, and the payload, which runs to the end of the body. All integers
+/// are big-endian. Errors carry a message as their payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResponseFrame {
+    pub status: Status,
+
+    /// The session's data ends at `offset`.
+    pub eof: bool,
+
+    /// The server had more data ready than fit in the response.
+    pub more_data: bool,
+
+    /// For downloads, the stream offset of the payload (or of the EOF). For
+    /// uploads and EOFs, the offset of the first byte the server is missing.
+    pub offset: u64,
+
+    /// How many more bytes the server can buffer for the session.
+    pub window: u32,
+
+    pub payload: Vec<u8>
+}
+
+impl ResponseFrame {
+    pub fn new(status: Status) -> ResponseFrame {
+        ResponseFrame{status, eof: false, more_data: false, offset: 0, window: 0,
+            payload: Vec::new()}
+    }
+
+    pub fn ok(payload: Vec<u8>) -> ResponseFrame {
+        ResponseFrame{payload, ..ResponseFrame::new(Status::Ok)}
+    }
+
+    pub fn error(status: Status, msg: &str) -> ResponseFrame {
+        ResponseFrame{payload: msg.as_bytes().to_vec(), ..ResponseFrame::new(status)}
+    }
+
+    pub fn with_offset(self, offset: u64) -> ResponseFrame {
+        ResponseFrame{offset, ..self}
+    }
+
+    pub fn with_window(self, window: usize) -> ResponseFrame {
+        ResponseFrame{window: window.min(u32::MAX as usize) as u32, ..self}
+    }
+
+    /// Get the payload of an error as text.
+    pub fn message(&self) -> String {
+        String::from_utf8_lossy(&self.payload).into_owned()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.eof {
+            flags |= FLAG_EOF;
+        }
+        if self.more_data {
+            flags |= FLAG_MORE_DATA;
+        }
+        let mut res = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        res.push(self.status.code());
+        res.push(flags);
+        res.extend_from_slice(&encode_offset(self.offset));
+        res.extend_from_slice(&encode_offset(u64::from(self.window))[4..]);
+        res.extend_from_slice(&self.payload);
+        res
+    }
+
+    pub fn decode(data: &[u8]) -> Result<ResponseFrame, Error> {
+        if data.len() < HEADER_SIZE {
+            return Err(Error::Protocol("truncated response".to_owned()));
+        }
+        let status = Status::from_code(data[0])
+            .ok_or_else(|| Error::Protocol(format!("unknown response status: {}", data[0])))?;
+        Ok(ResponseFrame{
+            status,
+            eof: data[1] & FLAG_EOF != 0,
+            more_data: data[1] & FLAG_MORE_DATA != 0,
+            offset: decode_offset(&data[2..10]),
+            window: decode_offset(&data[10..14]) as u32,
+            payload: data[HEADER_SIZE..].to_vec()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let frame = ResponseFrame{
+            eof: true,
+            more_data: true,
+            ..ResponseFrame::ok(b"data".to_vec()).with_offset(1 << 33).with_window(65536)
+        };
+        assert_eq!(ResponseFrame::decode(&frame.encode()).unwrap(), frame);
+        let frame = ResponseFrame::error(Status::Forbidden, "not allowed");
+        let decoded = ResponseFrame::decode(&frame.encode()).unwrap();
+        assert_eq!(decoded.message(), "not allowed");
+        assert_eq!(decoded, frame);
+    }
+
+    #[test]
+    fn header_layout() {
+        let frame = ResponseFrame{
+            more_data: true,
+            ..ResponseFrame::new(Status::WouldBlock).with_offset(0x0102030405060708)
+                .with_window(0x0a0b0c0d)
+        };
+        assert_eq!(frame.encode(), vec![1, 2, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13]);
+        assert_eq!(ResponseFrame::new(Status::Ok).with_window(usize::MAX).window, u32::MAX);
+    }
+
+    #[test]
+    fn empty_payload() {
+        let data = [0u8; HEADER_SIZE];
+        assert_eq!(ResponseFrame::decode(&data).unwrap(), ResponseFrame::new(Status::Ok));
+    }
+
+    #[test]
+    fn short_bodies() {
+        let data = ResponseFrame::ok(Vec::new()).encode();
+        for size in 0..HEADER_SIZE {
+            assert!(ResponseFrame::decode(&data[..size]).is_err());
+        }
+    }
+
+    #[test]
+    fn unknown_status() {
+        let mut data = ResponseFrame::ok(Vec::new()).encode();
+        data[0] = 200;
+        match ResponseFrame::decode(&data) {
+            Err(Error::Protocol(msg)) => assert_eq!(msg, "unknown response status: 200"),
+            x => panic!("unexpected result: {:?}", x)
+        }
+    }
+
+    #[test]
+    fn status_codes() {
+        for &(status, code) in Status::CODES {
+            assert_eq!(status.code(), code);
+            assert_eq!(Status::from_code(code), Some(status));
+        }
+        assert_eq!(Status::from_code(Status::CODES.len() as u8), None);
+        assert!(!Status::WouldBlock.is_error());
+        assert!(Status::TooLarge.is_error());
+    }
+}
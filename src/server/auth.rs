@@ -0,0 +1,620 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ed25519_dalek::VerifyingKey;
+use futures::{Future, IntoFuture};
+use futures::sync::oneshot;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use error::Error;
+use keys::{from_hex, parse_public_line, split_signed_proof, to_hex, verify_challenge};
+use mac::SealKey;
+use proof::{PASSWORD_ROUNDS, check_answer, check_proof, client_key, split_proof, stored_key};
+use uid::{generate_session_id, random_bytes};
+
+/// How long an unused nonce is kept.
+const MAX_NONCE_AGE: u64 = 300;
+
+/// The most unused nonces to keep at once, beyond which the oldest are
+/// dropped.
+const MAX_NONCES: usize = 4096;
+
+/// The size of the salts in new password verifiers.
+const SALT_SIZE: usize = 16;
+
+/// Yields the accepted login.
+pub type AuthFuture = Box<dyn Future<Item = Login, Error = Error>>;
+
+/// A login which an Authenticator accepted.
+#[derive(Clone, Debug, Default)]
+pub struct Login {
+    /// The name of the authenticated user, if the client gave one.
+    pub user: Option<String>,
+
+    /// What to seal the secret of a new session to, so that only the client
+    /// which logged in can sign the session's requests. Sessions can't be
+    /// protected by MACs without one.
+    pub seal: Option<SealKey>
+}
+
+/// A client's claim to be allowed in.
+#[derive(Clone, Debug)]
+pub struct Credential {
+    /// The user the client claims to be, if it gave a name.
+    pub user: Option<String>,
+
+    /// The proof that the client knows the user's key or the shared password.
+    pub proof: String,
+
+    /// How old the proof may be, in seconds.
+    pub max_age: u64
+}
+
+impl Credential {
+    /// Parse a proof as sent by a client, which is either "<user>:<proof>" or
+    /// a bare proof of the shared password.
+    pub fn parse(proof: &str, max_age: u64) -> Credential {
+        let (user, proof) = split_proof(proof);
+        Credential{user: user.map(str::to_owned), proof: proof.to_owned(), max_age}
+    }
+}
+
+/// Decides which clients may open sessions.
+pub trait Authenticator {
+    /// Check a client's credential, failing with an Auth error if it isn't
+    /// accepted.
+    fn authenticate(&self, credential: &Credential) -> AuthFuture;
+
+    /// Issue a challenge for `user` to answer, if this authenticator uses them.
+    fn challenge(&self, _user: &str) -> Option<String> {
+        None
+    }
+}
+
+impl<A: Authenticator + ?Sized> Authenticator for Box<A> {
+    fn authenticate(&self, credential: &Credential) -> AuthFuture {
+        (**self).authenticate(credential)
+    }
+
+    fn challenge(&self, user: &str) -> Option<String> {
+        (**self).challenge(user)
+    }
+}
+
+/// Accepts clients which know a single shared password.
+pub struct PasswordAuth {
+    password: String
+}
+
+impl PasswordAuth {
+    pub fn new(password: &str) -> PasswordAuth {
+        PasswordAuth{password: password.to_owned()}
+    }
+}
+
+impl Authenticator for PasswordAuth {
+    fn authenticate(&self, credential: &Credential) -> AuthFuture {
+        let res = if credential.user.is_none() &&
+            check_proof(&self.password, &credential.proof, credential.max_age)
+        {
+            Ok(Login{user: None, seal: Some(SealKey::Shared(self.password.clone().into_bytes()))})
+        } else {
+            Err(Error::Auth("incorrect password".to_owned()))
+        };
+        Box::new(res.into_future())
+    }
+}
+
+/// Accepts users which know their passwords.
+///
+/// Rather than the passwords, the server keeps a salted verifier for each
+/// user, as in a credentials file with a "<user>:<rounds>:<salt>:<verifier>"
+/// line for each user. Clients log in by answering a nonce with a key
+/// stretched from the password, which the verifier isn't enough to do, so a
+/// leaked credentials file only lets someone guess the passwords offline.
+pub struct CredentialsAuth {
+    verifiers: HashMap<String, Verifier>,
+    nonces: Nonces,
+
+    /// Picks the salts handed out for unknown users, so that challenges
+    /// don't tell who has an account.
+    decoy_key: [u8; 32]
+}
+
+impl Default for CredentialsAuth {
+    fn default() -> CredentialsAuth {
+        let mut decoy_key = [0u8; 32];
+        random_bytes(&mut decoy_key);
+        CredentialsAuth{verifiers: HashMap::new(), nonces: Nonces::new(), decoy_key}
+    }
+}
+
+impl CredentialsAuth {
+    pub fn new() -> CredentialsAuth {
+        CredentialsAuth::default()
+    }
+
+    /// Load the verifiers from a credentials file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<CredentialsAuth> {
+        let path = path.as_ref();
+        let mut auth = CredentialsAuth::new();
+        for (i, user, verifier) in read_user_file(path)? {
+            match Verifier::parse(&verifier) {
+                Some(verifier) => auth.verifiers.insert(user, verifier),
+                None => return Err(invalid_line(path, i, "<user>:<rounds>:<salt>:<verifier>"))
+            };
+        }
+        Ok(auth)
+    }
+
+    /// Load plaintext passwords from a file with a "<user>:<password>" line
+    /// for each user.
+    pub fn load_passwords<P: AsRef<Path>>(path: P) -> io::Result<CredentialsAuth> {
+        let mut auth = CredentialsAuth::new();
+        for (_, user, password) in read_user_file(path.as_ref())? {
+            auth.add(&user, &password);
+        }
+        Ok(auth)
+    }
+
+    /// Accept `user` when they know `password`.
+    pub fn add(&mut self, user: &str, password: &str) {
+        self.verifiers.insert(user.to_owned(), Verifier::new(password));
+    }
+
+    /// Accept `user` when they know the password behind a verifier, as in a
+    /// credentials file line.
+    pub fn add_verifier(&mut self, user: &str, verifier: &str) -> Result<(), String> {
+        let verifier = Verifier::parse(verifier)
+            .ok_or_else(|| format!("invalid verifier for user {}", user))?;
+        self.verifiers.insert(user.to_owned(), verifier);
+        Ok(())
+    }
+
+    /// Stop accepting `user`.
+    pub fn remove(&mut self, user: &str) {
+        self.verifiers.remove(user);
+    }
+
+    /// Create the credentials file line for a user, with a new salt.
+    pub fn entry(user: &str, password: &str) -> String {
+        format!("{}:{}", user, Verifier::new(password).encode())
+    }
+
+    /// Check an answer to a challenge, yielding the client key behind it.
+    /// This doesn't use up the nonce.
+    fn client_key(&self, credential: &Credential) -> Option<[u8; 32]> {
+        let user = credential.user.as_ref()?;
+        let verifier = self.verifiers.get(user)?;
+        let index = credential.proof.find('-')?;
+        let (nonce, answer) = (&credential.proof[..index], &credential.proof[index + 1..]);
+        check_answer(&verifier.stored_key, user, nonce, answer)
+    }
+}
+
+impl Authenticator for CredentialsAuth {
+    fn authenticate(&self, credential: &Credential) -> AuthFuture {
+        let nonce = credential.proof.split('-').next().unwrap_or("");
+        let user = credential.user.as_deref().unwrap_or("");
+        let fresh = self.nonces.take(nonce, user, credential.max_age);
+        Box::new(match self.client_key(credential) {
+            Some(key) if fresh => {
+                Ok(Login{user: credential.user.clone(), seal: Some(SealKey::Shared(key.to_vec()))})
+            },
+            _ => Err(Error::Auth("incorrect credentials".to_owned()))
+        }.into_future())
+    }
+
+    fn challenge(&self, user: &str) -> Option<String> {
+        let (rounds, salt) = match self.verifiers.get(user) {
+            Some(verifier) => (verifier.rounds, verifier.salt.clone()),
+            None => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.decoy_key).unwrap();
+                mac.update(user.as_bytes());
+                (PASSWORD_ROUNDS, mac.finalize().into_bytes()[..SALT_SIZE].to_vec())
+            }
+        };
+        Some(format!("{}:{}:{}", self.nonces.issue(user), rounds, to_hex(&salt)))
+    }
+}
+
+/// What the server keeps to check a user's password.
+#[derive(Clone)]
+struct Verifier {
+    rounds: u32,
+    salt: Vec<u8>,
+    stored_key: [u8; 32]
+}
+
+impl Verifier {
+    /// Create a verifier for a password with a new random salt.
+    fn new(password: &str) -> Verifier {
+        let mut salt = vec![0u8; SALT_SIZE];
+        random_bytes(&mut salt);
+        let key = client_key(password, &salt, PASSWORD_ROUNDS);
+        Verifier{rounds: PASSWORD_ROUNDS, salt, stored_key: stored_key(&key)}
+    }
+
+    /// Parse a verifier in the form "<rounds>:<salt>:<verifier>", with the
+    /// salt and verifier in hex.
+    fn parse(value: &str) -> Option<Verifier> {
+        let mut fields = value.split(':');
+        let rounds = fields.next()?.parse().ok().filter(|&x| x > 0)?;
+        let salt = from_hex(fields.next()?).filter(|x| !x.is_empty())?;
+        let key = from_hex(fields.next()?).filter(|x| x.len() == 32)?;
+        if fields.next().is_some() {
+            return None;
+        }
+        let mut stored_key = [0u8; 32];
+        stored_key.copy_from_slice(&key);
+        Some(Verifier{rounds, salt, stored_key})
+    }
+
+    fn encode(&self) -> String {
+        format!("{}:{}:{}", self.rounds, to_hex(&self.salt), to_hex(&self.stored_key))
+    }
+}
+
+/// Asks an external command whether to accept each client.
+///
+/// The command gets the credential in the SQUIDTUN_USER, SQUIDTUN_PROOF and
+/// SQUIDTUN_MAX_AGE environment variables, and accepts it by exiting with
+/// status 0. It runs on its own thread, so it may take its time.
+///
+/// To let the client sign its session's requests, the command prints the
+/// key which the proof was made with: the shared password, or user_key() of
+/// the user's password. Sessions of logins without one have no MAC.
+pub struct CommandAuth {
+    program: String,
+    args: Vec<String>
+}
+
+impl CommandAuth {
+    pub fn new(program: &str, args: &[&str]) -> CommandAuth {
+        CommandAuth{
+            program: program.to_owned(),
+            args: args.iter().map(|x| (*x).to_owned()).collect()
+        }
+    }
+}
+
+impl Authenticator for CommandAuth {
+    fn authenticate(&self, credential: &Credential) -> AuthFuture {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args)
+            .env("SQUIDTUN_USER", credential.user.as_deref().unwrap_or(""))
+            .env("SQUIDTUN_PROOF", &credential.proof)
+            .env("SQUIDTUN_MAX_AGE", credential.max_age.to_string())
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit());
+        let user = credential.user.clone();
+        let (sender, receiver) = oneshot::channel();
+        thread::spawn(move || {
+            sender.send(command.output()).ok();
+        });
+        Box::new(receiver
+            .map_err(|_| Error::Auth("auth command failed".to_owned()))
+            .and_then(move |res| {
+                match res {
+                    Ok(output) if output.status.success() => {
+                        let key = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+                        let seal = Some(key).filter(|x| !x.is_empty())
+                            .map(|x| SealKey::Shared(x.into_bytes()));
+                        Ok(Login{user, seal})
+                    },
+                    Ok(_) => Err(Error::Auth("incorrect credentials".to_owned())),
+                    Err(e) => {
+                        warn!("failed to run auth command: {}", e);
+                        Err(Error::Auth("auth command failed".to_owned()))
+                    }
+                }
+            }))
+    }
+}
+
+/// Accepts users which sign a nonce with an Ed25519 key.
+///
+/// Keys are read from an authorized keys file, with a
+/// "squidtun-ed25519 <base64 key> <user>" line for each key. A user may have
+/// several keys. Logins by users without keys go to a fallback authenticator
+/// if there is one.
+pub struct PublicKeyAuth {
+    keys: HashMap<String, Vec<VerifyingKey>>,
+    nonces: Nonces,
+    fallback: Option<Box<dyn Authenticator>>
+}
+
+impl PublicKeyAuth {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<PublicKeyAuth> {
+        let path = path.as_ref();
+        let mut keys: HashMap<String, Vec<VerifyingKey>> = HashMap::new();
+        for (i, line) in read_lines(path)? {
+            match parse_public_line(&line) {
+                Some((key, ref user)) if is_valid_user(user) => {
+                    keys.entry(user.clone()).or_default().push(key);
+                },
+                _ => return Err(invalid_line(path, i, "squidtun-ed25519 <key> <user>"))
+            }
+        }
+        Ok(PublicKeyAuth{keys, nonces: Nonces::new(), fallback: None})
+    }
+
+    /// Pass logins which don't use keys on to another authenticator.
+    pub fn fallback<A: Authenticator + 'static>(mut self, auth: A) -> PublicKeyAuth {
+        self.fallback = Some(Box::new(auth));
+        self
+    }
+
+    /// Check a signed nonce, using up the nonce, and yield the key which
+    /// signed it.
+    fn check_signature(&self, user: &str, proof: &str, max_age: u64) -> Option<VerifyingKey> {
+        let (nonce, signature) = split_signed_proof(proof)?;
+        if !self.nonces.take(nonce, user, max_age) {
+            return None;
+        }
+        self.keys[user].iter().find(|key| verify_challenge(key, user, nonce, &signature)).cloned()
+    }
+}
+
+impl Authenticator for PublicKeyAuth {
+    fn authenticate(&self, credential: &Credential) -> AuthFuture {
+        let user = match credential.user {
+            Some(ref user) if self.keys.contains_key(user) => user,
+            _ => {
+                return match self.fallback {
+                    Some(ref fallback) => fallback.authenticate(credential),
+                    None => Box::new(Err(Error::Auth("incorrect credentials".to_owned()))
+                        .into_future())
+                };
+            }
+        };
+        Box::new(match self.check_signature(user, &credential.proof, credential.max_age) {
+            Some(key) => Ok(Login{user: Some(user.clone()), seal: Some(SealKey::Public(key))}),
+            None => Err(Error::Auth("incorrect signature".to_owned()))
+        }.into_future())
+    }
+
+    fn challenge(&self, user: &str) -> Option<String> {
+        match self.fallback {
+            Some(ref fallback) if !self.keys.contains_key(user) => fallback.challenge(user),
+            _ => Some(self.nonces.issue(user))
+        }
+    }
+}
+
+/// Nonces handed out for clients to answer, each of which can be used once.
+struct Nonces {
+    issued: RefCell<HashMap<String, (String, Instant)>>
+}
+
+impl Nonces {
+    fn new() -> Nonces {
+        Nonces{issued: RefCell::new(HashMap::new())}
+    }
+
+    /// Issue a nonce for `user` to answer.
+    fn issue(&self, user: &str) -> String {
+        // Nonces are handed out to anyone, so old ones must be cleaned up.
+        let mut issued = self.issued.borrow_mut();
+        let lifetime = Duration::from_secs(MAX_NONCE_AGE);
+        issued.retain(|_, &mut (_, time)| time.elapsed() <= lifetime);
+        if issued.len() >= MAX_NONCES {
+            // Make room rather than refusing, so that a flood of challenges
+            // can't lock everyone out. Clients use their nonces right away,
+            // and the LoginGuard keeps a flood from cycling through the
+            // table that fast.
+            let oldest = issued.iter()
+                .min_by_key(|&(_, &(_, time))| time)
+                .map(|(nonce, _)| nonce.clone());
+            if let Some(oldest) = oldest {
+                issued.remove(&oldest);
+            }
+        }
+        let nonce = generate_session_id();
+        issued.insert(nonce.clone(), (user.to_owned(), Instant::now()));
+        nonce
+    }
+
+    /// Use up a nonce, checking that it was issued to `user` no more than
+    /// `max_age` seconds ago.
+    fn take(&self, nonce: &str, user: &str, max_age: u64) -> bool {
+        match self.issued.borrow_mut().remove(nonce) {
+            Some((ref issued_user, time)) if issued_user == user => {
+                time.elapsed() <= Duration::from_secs(max_age)
+            },
+            _ => false
+        }
+    }
+}
+
+/// Read a file of "<user>:<value>" lines, along with their line numbers.
+fn read_user_file(path: &Path) -> io::Result<Vec<(usize, String, String)>> {
+    let mut entries = Vec::new();
+    for (i, line) in read_lines(path)? {
+        match line.find(':') {
+            Some(index) if is_valid_user(&line[..index]) => {
+                entries.push((i, line[..index].to_owned(), line[index + 1..].to_owned()));
+            },
+            _ => return Err(invalid_line(path, i, "<user>:<value>"))
+        }
+    }
+    Ok(entries)
+}
+
+/// Read the lines of a file along with their line numbers, skipping blank
+/// lines and comments.
+fn read_lines(path: &Path) -> io::Result<Vec<(usize, String)>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(contents.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|&(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| (i, line.to_owned()))
+        .collect())
+}
+
+fn invalid_line(path: &Path, line: usize, expected: &str) -> io::Error {
+    let msg = format!("{}:{}: expected {}", path.display(), line, expected);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Check that a user name can be sent in a request path.
+pub fn is_valid_user(user: &str) -> bool {
+    !user.is_empty() && user.chars().all(|c| c.is_ascii_alphanumeric() || "-_.@".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use keys::KeyPair;
+    use proof::{answer_challenge, current_proof, current_user_proof};
+    use super::*;
+
+    fn public_key_auth(user: &str, key: &KeyPair) -> PublicKeyAuth {
+        let path = write_file(&format!("authorized-{}", user), &key.public_line(user));
+        let auth = PublicKeyAuth::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        auth
+    }
+
+    fn write_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("squidtun-{}-{}", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn check(auth: &dyn Authenticator, proof: &str) -> Result<Option<String>, Error> {
+        auth.authenticate(&Credential::parse(proof, 60)).wait().map(|login| login.user)
+    }
+
+    /// Log in as `user` with `password` the way a client does.
+    fn answer(auth: &dyn Authenticator, user: &str, password: &str) -> String {
+        let challenge = auth.challenge(user).unwrap();
+        let fields: Vec<&str> = challenge.split(':').collect();
+        let key = client_key(password, &from_hex(fields[2]).unwrap(), fields[1].parse().unwrap());
+        format!("{}:{}", user, answer_challenge(&key, user, fields[0]))
+    }
+
+    #[test]
+    fn credentials_file() {
+        let contents = format!("# users\n{}\n\n  {}  \n",
+            CredentialsAuth::entry("alice", "hunter2"), CredentialsAuth::entry("bob", "pw"));
+        let path = write_file("credentials", &contents);
+        let auth = CredentialsAuth::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(auth.verifiers.len(), 2);
+        assert!(check(&auth, &answer(&auth, "alice", "hunter2")).is_ok());
+        assert!(check(&auth, &answer(&auth, "bob", "pw")).is_ok());
+        assert!(!contents.contains("hunter2"));
+    }
+
+    #[test]
+    fn invalid_credentials_file() {
+        let cases = [("alice", "<user>:<value>"), ("bad user:1:ab:cd", "<user>:<value>"),
+            (":1:ab:cd", "<user>:<value>"), ("alice:1:ab", "<user>:<rounds>:<salt>:<verifier>"),
+            ("alice:0:ab:00", "<user>:<rounds>:<salt>:<verifier>")];
+        for &(contents, expected) in &cases {
+            let path = write_file("bad-credentials", contents);
+            let err = CredentialsAuth::load(&path).err().unwrap();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().ends_with(&format!(":1: expected {}", expected)));
+        }
+    }
+
+    #[test]
+    fn password_file() {
+        let path = write_file("passwords", "alice:hunter2\n");
+        let auth = CredentialsAuth::load_passwords(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(check(&auth, &answer(&auth, "alice", "hunter2")).is_ok());
+    }
+
+    #[test]
+    fn credentials_proofs() {
+        let mut auth = CredentialsAuth::new();
+        auth.add("alice", "hunter2");
+        let proof = answer(&auth, "alice", "hunter2");
+        let login = auth.authenticate(&Credential::parse(&proof, 60)).wait().unwrap();
+        assert_eq!(login.user, Some("alice".to_owned()));
+        assert!(login.seal.is_some());
+        // Each nonce can only be used once.
+        assert!(check(&auth, &proof).is_err());
+        assert!(check(&auth, &answer(&auth, "alice", "wrong")).is_err());
+        assert!(check(&auth, &answer(&auth, "bob", "hunter2")).is_err());
+        assert!(check(&auth, &current_user_proof("alice", "hunter2")).is_err());
+        assert!(check(&auth, &current_proof("hunter2")).is_err());
+    }
+
+    #[test]
+    fn verifier_is_not_a_password() {
+        let mut auth = CredentialsAuth::new();
+        auth.add("alice", "hunter2");
+        let verifier = auth.verifiers["alice"].encode();
+        assert!(check(&auth, &answer(&auth, "alice", &verifier)).is_err());
+        let stored = auth.verifiers["alice"].stored_key;
+        let nonce = auth.challenge("alice").unwrap();
+        let nonce = nonce.split(':').next().unwrap();
+        let proof = format!("alice:{}", answer_challenge(&stored, "alice", nonce));
+        assert!(check(&auth, &proof).is_err());
+    }
+
+    #[test]
+    fn unknown_users_get_steady_salts() {
+        let mut auth = CredentialsAuth::new();
+        auth.add("alice", "hunter2");
+        let salt = |user| auth.challenge(user).unwrap().split(':').skip(1)
+            .collect::<Vec<_>>().join(":");
+        assert_eq!(salt("bob"), salt("bob"));
+        assert_ne!(salt("bob"), salt("carol"));
+        assert_eq!(salt("bob").split(':').count(), salt("alice").split(':').count());
+    }
+
+    #[test]
+    fn password_proofs() {
+        let auth = PasswordAuth::new("secret");
+        assert_eq!(check(&auth, &current_proof("secret")).unwrap(), None);
+        assert!(check(&auth, &current_proof("wrong")).is_err());
+        assert!(check(&auth, &current_user_proof("alice", "secret")).is_err());
+    }
+
+    #[test]
+    fn signed_nonces() {
+        let key = KeyPair::generate().unwrap();
+        let auth = public_key_auth("alice", &key);
+        let nonce = auth.challenge("alice").unwrap();
+        let proof = format!("alice:{}", key.sign_challenge("alice", &nonce));
+        assert_eq!(check(&auth, &proof).unwrap(), Some("alice".to_owned()));
+        // Each nonce can only be used once.
+        assert!(check(&auth, &proof).is_err());
+        let nonce = auth.challenge("bob").unwrap();
+        assert!(check(&auth, &format!("alice:{}", key.sign_challenge("alice", &nonce))).is_err());
+    }
+
+    #[test]
+    fn nonce_flood() {
+        let key = KeyPair::generate().unwrap();
+        let auth = public_key_auth("alice", &key);
+        let first = auth.challenge("alice").unwrap();
+        for _ in 0..MAX_NONCES {
+            assert!(auth.challenge("mallory").is_some());
+        }
+        assert_eq!(auth.nonces.issued.borrow().len(), MAX_NONCES);
+        assert!(!auth.nonces.issued.borrow().contains_key(&first));
+        let nonce = auth.challenge("alice").unwrap();
+        let proof = format!("alice:{}", key.sign_challenge("alice", &nonce));
+        assert!(check(&auth, &proof).is_ok());
+    }
+}
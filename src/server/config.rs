@@ -0,0 +1,238 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use toml::Value;
+use toml::value::Table;
+
+use config::{addresses, bad_value, boolean, integer, load_file, parse_table, string, table_value};
+use server::{Limits, ServerBuilder};
+use server::auth::{Authenticator, CommandAuth, CredentialsAuth, PasswordAuth,
+    PublicKeyAuth, is_valid_user};
+use server::policy::{Policies, Policy, parse_hours};
+
+/// Server settings read from a TOML file.
+///
+/// The file may refer to credentials, keys and policy files, which are read
+/// along with it. Relative paths are relative to the file's directory.
+pub struct ServerConfig {
+    /// The addresses to listen on.
+    pub listen: Vec<SocketAddr>,
+
+    /// How long to let open sessions finish when shutting down.
+    pub drain_time: Duration,
+
+    /// The address to serve metrics on, if any.
+    pub metrics: Option<SocketAddr>,
+
+    /// A builder for a service with the rest of the settings.
+    pub builder: ServerBuilder
+}
+
+impl ServerConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ServerConfig> {
+        load_file(path.as_ref(), ServerConfig::parse)
+    }
+
+    /// Parse the contents of a config file, reading the files it refers to
+    /// relative to `dir`.
+    pub fn parse(contents: &str, dir: &Path) -> Result<ServerConfig, String> {
+        let table = parse_table(contents, SETTINGS)?;
+        let listen = match table.get("listen") {
+            Some(value) => addresses(value, "listen")?,
+            None => Vec::new()
+        };
+        if listen.is_empty() {
+            return Err("no listen addresses".to_owned());
+        }
+        let targets = match table.get("targets") {
+            Some(value) => addresses(value, "targets")?,
+            None => vec!["127.0.0.1:22".parse().unwrap()]
+        };
+        let users = match table.get("users") {
+            Some(value) => table_value(value, "users")?.clone(),
+            None => Table::new()
+        };
+        for user in users.keys() {
+            if user != "*" && !is_valid_user(user) {
+                return Err(format!("invalid user name: {}", user));
+            }
+        }
+        let drain_time = match table.get("drain_time") {
+            Some(value) => seconds(value, "drain_time")?,
+            None => Duration::from_secs(30)
+        };
+        let metrics = match table.get("metrics") {
+            Some(value) => {
+                Some(string(value, "metrics")?.parse().map_err(|_| bad_value("metrics"))?)
+            },
+            None => None
+        };
+        let limits = match table.get("limits") {
+            Some(value) => parse_limits(table_value(value, "limits")?)?,
+            None => Limits::default()
+        };
+        let auth = parse_auth(&table, &users, dir)?;
+        let auth: Box<dyn Authenticator> = match table.get("authorized_keys") {
+            Some(value) => {
+                let path = dir.join(string(value, "authorized_keys")?);
+                Box::new(PublicKeyAuth::load(&path).map_err(|e| e.to_string())?.fallback(auth))
+            },
+            None => auth
+        };
+        let builder = ServerBuilder::new()
+            .targets(targets)
+            .auth(auth)
+            .policies(parse_policies(&table, &users, dir)?)
+            .limits(limits);
+        Ok(ServerConfig{listen, drain_time, metrics, builder})
+    }
+}
+
+/// The settings at the top level of a config file.
+const SETTINGS: &[&str] = &["listen", "targets", "drain_time", "metrics", "password",
+    "credentials", "keys", "auth_command", "authorized_keys", "policy", "users", "limits"];
+
+/// Pick the authenticator, from which of "password", "credentials", "keys"
+/// and "auth_command" is set. Users with a "hash" or "key" of their own are
+/// added to the credentials.
+fn parse_auth(
+    table: &Table,
+    users: &Table,
+    dir: &Path
+) -> Result<Box<dyn Authenticator>, String> {
+    let mut credentials = match table.get("credentials") {
+        Some(value) => {
+            let path = dir.join(string(value, "credentials")?);
+            Some(CredentialsAuth::load(&path).map_err(|e| e.to_string())?)
+        },
+        None => None
+    };
+    for (user, settings) in users {
+        let settings = table_value(settings, user)?;
+        match (settings.get("hash"), settings.get("key")) {
+            (None, None) => (),
+            _ if user == "*" => return Err("user * can't have a hash or key".to_owned()),
+            (Some(_), Some(_)) => return Err(format!("user {} has both a hash and a key", user)),
+            (Some(hash), None) => {
+                let hash = string(hash, "hash")?;
+                credentials.get_or_insert_with(CredentialsAuth::new).add_verifier(user, hash)?;
+            },
+            (None, Some(key)) => {
+                credentials.get_or_insert_with(CredentialsAuth::new).add(user, string(key, "key")?);
+            }
+        }
+    }
+    let others = ["password", "keys", "auth_command"].iter()
+        .filter(|x| table.contains_key(**x))
+        .collect::<Vec<_>>();
+    if others.len() + credentials.is_some() as usize > 1 {
+        return Err("only one of password, credentials (or user hashes and keys), keys and \
+            auth_command may be set".to_owned());
+    }
+    if let Some(credentials) = credentials {
+        return Ok(Box::new(credentials));
+    }
+    Ok(if let Some(value) = table.get("keys") {
+        let path = dir.join(string(value, "keys")?);
+        Box::new(CredentialsAuth::load_passwords(&path).map_err(|e| e.to_string())?)
+    } else if let Some(value) = table.get("auth_command") {
+        Box::new(CommandAuth::new(string(value, "auth_command")?, &[]))
+    } else {
+        let password = table.get("password").map(|x| string(x, "password")).unwrap_or(Ok(""));
+        Box::new(PasswordAuth::new(password?))
+    })
+}
+
+/// Read the policy file, if any, and then the policies of the users. A user
+/// named "*" sets the policy for everyone else.
+fn parse_policies(table: &Table, users: &Table, dir: &Path) -> Result<Policies, String> {
+    let mut policies = match table.get("policy") {
+        Some(value) => {
+            let path = dir.join(string(value, "policy")?);
+            Policies::load(&path).map_err(|e| e.to_string())?
+        },
+        None => Policies::new()
+    };
+    for (user, settings) in users {
+        let settings = table_value(settings, user)?;
+        let mut policy = Policy::default();
+        let mut has_policy = false;
+        for (name, value) in settings {
+            match name.as_str() {
+                "hash" | "key" => continue,
+                "targets" => policy.targets = Some(addresses(value, name)?),
+                "sessions" => policy.max_sessions = Some(integer(value, name)?),
+                "rate" => policy.max_rate = Some(integer(value, name)?),
+                "hours" => {
+                    let hours = parse_hours(string(value, name)?);
+                    policy.hours = Some(hours.ok_or_else(|| bad_value(name))?);
+                },
+                _ => return Err(format!("unknown setting for user {}: {}", user, name))
+            }
+            has_policy = true;
+        }
+        if !has_policy {
+            continue;
+        } else if user == "*" {
+            policies.set_default(policy);
+        } else {
+            policies.set(user, policy);
+        }
+    }
+    Ok(policies)
+}
+
+fn parse_limits(table: &Table) -> Result<Limits, String> {
+    let mut limits = Limits::default();
+    for (name, value) in table {
+        match name.as_str() {
+            "max_chunk" => limits.max_chunk_size = integer(value, name)?,
+            "session_timeout" => limits.session_timeout = seconds(value, name)?,
+            "max_buffered_upload" => limits.max_buffered_upload = integer(value, name)?,
+            "max_unacked_download" => limits.max_unacked_download = integer(value, name)?,
+            "max_proof_age" => limits.max_proof_age = integer(value, name)?,
+            "max_sessions" => limits.max_sessions = limit(value, name)?,
+            "login_rate" => limits.max_logins_per_address = limit(value, name)?,
+            "global_login_rate" => limits.max_logins = limit(value, name)?,
+            "max_login_failures" => limits.max_login_failures = limit(value, name)?,
+            "ban_time" => limits.ban_duration = seconds(value, name)?,
+            "require_mac" => limits.require_mac = boolean(value, name)?,
+            _ => return Err(format!("unknown limit: {}", name))
+        }
+    }
+    limits.check()?;
+    Ok(limits)
+}
+
+/// Parse a limit, where 0 means no limit.
+fn limit<T: TryFrom<i64>>(value: &Value, name: &str) -> Result<Option<T>, String> {
+    if value.as_integer() == Some(0) {
+        Ok(None)
+    } else {
+        integer(value, name).map(Some)
+    }
+}
+
+fn seconds(value: &Value, name: &str) -> Result<Duration, String> {
+    integer(value, name).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn chunks_larger_than_upload_buffer() {
+        let contents = "listen = [\"127.0.0.1:8080\"]\n\
+            [limits]\nmax_chunk = 4096\nmax_buffered_upload = 1024\n";
+        let err = ServerConfig::parse(contents, Path::new(".")).err().unwrap();
+        assert_eq!(err, "max_chunk (4096) is larger than max_buffered_upload (1024)");
+        let contents = "listen = [\"127.0.0.1:8080\"]\n[limits]\nmax_chunk = 1024\n";
+        assert!(ServerConfig::parse(contents, Path::new(".")).is_ok());
+    }
+}
@@ -0,0 +1,368 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use error::Error;
+use server::Limits;
+
+/// The most client addresses, and users at addresses, to keep track of at
+/// once.
+const MAX_ADDRESSES: usize = 65536;
+
+/// How many times `max_login_failures` failed logins, as any users, ban an
+/// address for everyone. This catches clients which try a few passwords for
+/// each of many users, while leaving room for the typos of everyone behind a
+/// shared proxy.
+const ADDRESS_FAILURE_FACTOR: u32 = 10;
+
+/// Slows down password guessing by limiting how often clients may try to log
+/// in, and banning users which keep failing from the addresses they fail
+/// from.
+///
+/// Clients which reach the server through the same proxy share an address,
+/// so the rate limit for each address should leave room for all of them.
+/// Bans only apply to the user who failed, so one user's typos don't lock
+/// out everyone else behind the same proxy, unless the address fails far
+/// more often than one user could. Failures are forgotten once there have
+/// been none for the ban duration.
+pub struct LoginGuard {
+    limits: Cell<Limits>,
+    global: RefCell<Bucket>,
+
+    /// The login attempts left for each address.
+    addresses: RefCell<HashMap<IpAddr, Bucket>>,
+
+    /// The failed logins of each user from each address, where no user means
+    /// the shared password.
+    failures: RefCell<HashMap<(IpAddr, Option<String>), Failures>>,
+
+    /// The failed logins of all users from each address.
+    address_failures: RefCell<HashMap<IpAddr, Failures>>
+}
+
+impl LoginGuard {
+    pub fn new(limits: Limits) -> LoginGuard {
+        let global = Bucket::new(limits.max_logins.unwrap_or(0));
+        LoginGuard{
+            limits: Cell::new(limits),
+            global: RefCell::new(global),
+            addresses: RefCell::new(HashMap::new()),
+            failures: RefCell::new(HashMap::new()),
+            address_failures: RefCell::new(HashMap::new())
+        }
+    }
+
+    /// Apply new limits from now on, without lifting any bans.
+    pub fn set_limits(&self, limits: Limits) {
+        self.limits.set(limits);
+        self.global.borrow_mut().resize(limits.max_logins.unwrap_or(0));
+        for attempts in self.addresses.borrow_mut().values_mut() {
+            attempts.resize(limits.max_logins_per_address.unwrap_or(0));
+        }
+    }
+
+    /// Check that a client at `addr` may try to log in as `user` now, using
+    /// up one of its attempts. The address is unknown for requests which
+    /// didn't come through TunnelService::serve(), so only the global limit
+    /// applies.
+    pub fn check(&self, addr: Option<IpAddr>, user: Option<&str>) -> Result<(), Error> {
+        let limits = self.limits.get();
+        if let Some(addr) = addr {
+            let key = (addr, user.map(str::to_owned));
+            let now = Instant::now();
+            if self.failures.borrow().get(&key).is_some_and(|x| x.is_banned(now)) ||
+                self.address_failures.borrow().get(&addr).is_some_and(|x| x.is_banned(now)) {
+                return Err(Error::Busy("too many failed logins".to_owned()));
+            }
+            let mut addresses = self.addresses.borrow_mut();
+            if let Some(attempts) = self.lookup(&mut addresses, addr) {
+                let rate = limits.max_logins_per_address.map(|x| x as f64 / 60.0);
+                if !attempts.take(rate) {
+                    info!("too many logins from {}", addr);
+                    return Err(Error::Busy("too many logins".to_owned()));
+                }
+            }
+        }
+        if !self.global.borrow_mut().take(limits.max_logins.map(f64::from)) {
+            info!("too many logins from all clients");
+            return Err(Error::Busy("server is busy".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Record whether a login as `user` from `addr` succeeded, banning the
+    /// user from the address if they have failed too many times in a row, or
+    /// everyone if the address has.
+    pub fn record(&self, addr: Option<IpAddr>, user: Option<&str>, success: bool) {
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return
+        };
+        let key = (addr, user.map(str::to_owned));
+        if success {
+            self.failures.borrow_mut().remove(&key);
+            return;
+        }
+        let limits = self.limits.get();
+        let duration = limits.ban_duration;
+        let max = limits.max_login_failures;
+        if let Some(count) = fail(&mut self.failures.borrow_mut(), key, duration, max) {
+            let who = user.map(|x| format!("user {} from ", x)).unwrap_or_default();
+            warn!("banned {}{} for {}s after {} failed logins", who, addr, duration.as_secs(),
+                count);
+        }
+        let max = max.map(|x| x.saturating_mul(ADDRESS_FAILURE_FACTOR));
+        if let Some(count) = fail(&mut self.address_failures.borrow_mut(), addr, duration, max) {
+            warn!("banned {} for {}s after {} failed logins as any user", addr,
+                duration.as_secs(), count);
+        }
+    }
+
+    /// Get the attempts left for an address, making room for it if it's new.
+    /// Yields None if there are too many addresses to track another.
+    fn lookup<'a>(
+        &self,
+        addresses: &'a mut HashMap<IpAddr, Bucket>,
+        addr: IpAddr
+    ) -> Option<&'a mut Bucket> {
+        let limits = self.limits.get();
+        if !addresses.contains_key(&addr) && addresses.len() >= MAX_ADDRESSES {
+            // Forget the addresses which are back where they started.
+            let rate = limits.max_logins_per_address.map(|x| x as f64 / 60.0);
+            addresses.retain(|_, x| !x.is_full(rate));
+            if addresses.len() >= MAX_ADDRESSES {
+                return None;
+            }
+        }
+        let capacity = limits.max_logins_per_address.unwrap_or(0);
+        Some(addresses.entry(addr).or_insert_with(|| Bucket::new(capacity)))
+    }
+}
+
+/// Count a failed login under `key`, starting a ban of `duration` once there
+/// have been `max` failures. Yields the number of failures if it did.
+fn fail<K: Clone + Hash + Eq>(
+    failures: &mut HashMap<K, Failures>,
+    key: K,
+    duration: Duration,
+    max: Option<u32>
+) -> Option<u32> {
+    let now = Instant::now();
+    if !failures.contains_key(&key) && failures.len() >= MAX_ADDRESSES {
+        failures.retain(|_, x| !x.is_stale(now, duration));
+        if failures.len() >= MAX_ADDRESSES {
+            // Make room by forgetting the oldest failures, keeping bans for
+            // as long as possible.
+            let oldest = failures.iter()
+                .min_by_key(|&(_, x)| (x.is_banned(now), x.last_failed))
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                failures.remove(&oldest);
+            }
+        }
+    }
+    let entry = failures.entry(key).or_insert(Failures{count: 0, last_failed: now,
+        banned_until: None});
+    if entry.is_stale(now, duration) {
+        entry.count = 0;
+    }
+    entry.count += 1;
+    entry.last_failed = now;
+    if max.is_some_and(|x| entry.count >= x) {
+        entry.banned_until = Some(now + duration);
+        let count = entry.count;
+        entry.count = 0;
+        return Some(count);
+    }
+    None
+}
+
+/// The failed logins of one user, or of everyone, from one address.
+struct Failures {
+    /// The failed logins since the last successful one, unless they've gone
+    /// stale.
+    count: u32,
+    last_failed: Instant,
+    banned_until: Option<Instant>
+}
+
+impl Failures {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|x| x > now)
+    }
+
+    /// Whether there have been no failures for `expiry`, and no ban is left,
+    /// so the entry can be forgotten.
+    fn is_stale(&self, now: Instant, expiry: Duration) -> bool {
+        !self.is_banned(now) && now.duration_since(self.last_failed) >= expiry
+    }
+}
+
+/// A token bucket which holds up to `capacity` attempts.
+struct Bucket {
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant
+}
+
+impl Bucket {
+    fn new(capacity: u32) -> Bucket {
+        let capacity = f64::from(capacity.max(1));
+        Bucket{capacity, tokens: capacity, refilled: Instant::now()}
+    }
+
+    /// Take an attempt from the bucket, which refills at `rate` attempts per
+    /// second, or never runs out if there is no rate.
+    fn take(&mut self, rate: Option<f64>) -> bool {
+        if rate.is_none() {
+            return true;
+        }
+        self.refill(rate);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Change how many attempts the bucket holds, keeping the ones it has up
+    /// to the new capacity.
+    fn resize(&mut self, capacity: u32) {
+        self.capacity = f64::from(capacity.max(1));
+        self.tokens = self.tokens.min(self.capacity);
+    }
+
+    fn is_full(&mut self, rate: Option<f64>) -> bool {
+        self.refill(rate);
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self, rate: Option<f64>) {
+        let elapsed = self.refilled.elapsed();
+        self.refilled = Instant::now();
+        let added = rate.unwrap_or(0.0) * elapsed.as_secs_f64();
+        self.tokens = (self.tokens + added).min(self.capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn guard(limits: Limits) -> LoginGuard {
+        LoginGuard::new(Limits{max_logins: None, ..limits})
+    }
+
+    #[test]
+    fn bans_only_the_failing_user() {
+        let guard = guard(Limits{max_login_failures: Some(3), max_logins_per_address: None,
+            ..Limits::default()});
+        let addr = Some("10.0.0.1".parse().unwrap());
+        let other_addr = Some("10.0.0.2".parse().unwrap());
+        for _ in 0..3 {
+            assert!(guard.check(addr, Some("alice")).is_ok());
+            guard.record(addr, Some("alice"), false);
+        }
+        assert!(guard.check(addr, Some("alice")).is_err());
+        assert!(guard.check(addr, Some("bob")).is_ok());
+        assert!(guard.check(addr, None).is_ok());
+        assert!(guard.check(other_addr, Some("alice")).is_ok());
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let guard = guard(Limits{max_login_failures: Some(2), max_logins_per_address: None,
+            ..Limits::default()});
+        let addr = Some("10.0.0.1".parse().unwrap());
+        guard.record(addr, None, false);
+        guard.record(addr, None, true);
+        guard.record(addr, None, false);
+        assert!(guard.check(addr, None).is_ok());
+        guard.record(addr, None, false);
+        assert!(guard.check(addr, None).is_err());
+    }
+
+    #[test]
+    fn bans_expire() {
+        let guard = guard(Limits{max_login_failures: Some(1), max_logins_per_address: None,
+            ban_duration: Duration::from_millis(50), ..Limits::default()});
+        let addr = Some("10.0.0.1".parse().unwrap());
+        guard.record(addr, Some("alice"), false);
+        assert!(guard.check(addr, Some("alice")).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(guard.check(addr, Some("alice")).is_ok());
+    }
+
+    #[test]
+    fn failures_expire() {
+        let guard = guard(Limits{max_login_failures: Some(2), max_logins_per_address: None,
+            ban_duration: Duration::from_millis(50), ..Limits::default()});
+        let addr = Some("10.0.0.1".parse().unwrap());
+        guard.record(addr, Some("alice"), false);
+        std::thread::sleep(Duration::from_millis(60));
+        guard.record(addr, Some("alice"), false);
+        assert!(guard.check(addr, Some("alice")).is_ok());
+        guard.record(addr, Some("alice"), false);
+        assert!(guard.check(addr, Some("alice")).is_err());
+    }
+
+    #[test]
+    fn spraying_bans_the_address() {
+        let guard = guard(Limits{max_login_failures: Some(2), max_logins_per_address: None,
+            ..Limits::default()});
+        let addr = Some("10.0.0.1".parse().unwrap());
+        for i in 0..2 * ADDRESS_FAILURE_FACTOR {
+            assert!(guard.check(addr, Some(&format!("user{}", i))).is_ok());
+            guard.record(addr, Some(&format!("user{}", i)), false);
+        }
+        assert!(guard.check(addr, Some("alice")).is_err());
+        assert!(guard.check(addr, None).is_err());
+        assert!(guard.check(Some("10.0.0.2".parse().unwrap()), Some("alice")).is_ok());
+    }
+
+    #[test]
+    fn full_tables_forget_the_oldest_failures() {
+        let guard = guard(Limits{max_login_failures: Some(2), max_logins_per_address: None,
+            ..Limits::default()});
+        let addr = Some("10.0.0.1".parse().unwrap());
+        guard.record(addr, Some("alice"), false);
+        guard.record(addr, Some("alice"), false);
+        {
+            let mut failures = guard.failures.borrow_mut();
+            let now = Instant::now();
+            for i in failures.len()..MAX_ADDRESSES {
+                let key = ("10.0.0.2".parse().unwrap(), Some(i.to_string()));
+                failures.insert(key, Failures{count: 1, last_failed: now, banned_until: None});
+            }
+        }
+        // Bob's failure still counts, and Alice stays banned.
+        guard.record(addr, Some("bob"), false);
+        guard.record(addr, Some("bob"), false);
+        assert!(guard.check(addr, Some("bob")).is_err());
+        assert!(guard.check(addr, Some("alice")).is_err());
+        assert_eq!(guard.failures.borrow().len(), MAX_ADDRESSES);
+    }
+
+    #[test]
+    fn rate_limit_is_per_address() {
+        let guard = guard(Limits{max_logins_per_address: Some(2), ..Limits::default()});
+        let addr = Some("10.0.0.1".parse().unwrap());
+        assert!(guard.check(addr, Some("alice")).is_ok());
+        assert!(guard.check(addr, Some("bob")).is_ok());
+        assert!(guard.check(addr, Some("carol")).is_err());
+        assert!(guard.check(Some("10.0.0.2".parse().unwrap()), Some("alice")).is_ok());
+        // Requests without an address only count against the global limit.
+        assert!(guard.check(None, Some("alice")).is_ok());
+    }
+
+    #[test]
+    fn global_rate_limit() {
+        let guard = LoginGuard::new(Limits{max_logins: Some(1), ..Limits::default()});
+        assert!(guard.check(None, None).is_ok());
+        assert!(guard.check(Some("10.0.0.1".parse().unwrap()), None).is_err());
+    }
+}
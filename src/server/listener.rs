@@ -0,0 +1,139 @@
+use std::io;
+use std::io::{Read, Write};
+
+use futures::{Async, Future, IntoFuture, Poll};
+use hyper::Chunk;
+use hyper::server::Http;
+use tokio_core::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use handshake::Handshake;
+use server::TunnelService;
+use websocket::HttpHead;
+
+/// The most data to read while looking for the end of a request head.
+const MAX_HEAD_SIZE: usize = 8192;
+
+/// Serve a new connection, which is either a raw tunnel, a WebSocket upgrade,
+/// or a series of regular HTTP requests.
+pub fn serve_connection(
+    conn: TcpStream,
+    service: TunnelService
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    Box::new(ReadHead::new(conn)
+        .map_err(|e| info!("connection error: {}", e))
+        .and_then(move |(conn, data)| -> Box<dyn Future<Item = (), Error = ()>> {
+            if !data.is_empty() && Handshake::is_prefix(&data) {
+                return match Handshake::parse(&data) {
+                    Ok(Some((handshake, size))) => {
+                        service.tunnel(conn, handshake, data[size..].to_vec())
+                    },
+                    _ => Box::new(Ok(()).into_future())
+                };
+            }
+            match HttpHead::parse(&data) {
+                Some((ref head, size)) if head.is_websocket_upgrade() => {
+                    let rest = data[size..].to_vec();
+                    service.upgrade(PrefixedIo::new(conn, rest), head)
+                },
+                _ => {
+                    let io = PrefixedIo::new(conn, data);
+                    Box::new(Http::<Chunk>::new().serve_connection(io, service)
+                        .map(|_| ())
+                        .map_err(|e| info!("connection error: {}", e)))
+                }
+            }
+        }))
+}
+
+/// A future which reads the first request head or tunnel handshake from a
+/// connection.
+///
+/// Yields the connection and everything that was read from it. Reading stops
+/// early if the connection ends or the head is too large, in which case the
+/// data is left for the HTTP server to deal with.
+struct ReadHead<T: AsyncRead> {
+    conn: Option<T>,
+    data: Vec<u8>
+}
+
+impl<T: AsyncRead> ReadHead<T> {
+    fn new(conn: T) -> ReadHead<T> {
+        ReadHead{conn: Some(conn), data: Vec::new()}
+    }
+}
+
+impl<T: AsyncRead> Future for ReadHead<T> {
+    type Item = (T, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if is_complete(&self.data) {
+                break;
+            }
+            let mut buf = [0u8; 1024];
+            let size = match self.conn.as_mut().unwrap().poll_read(&mut buf)? {
+                Async::Ready(size) => size,
+                Async::NotReady => return Ok(Async::NotReady)
+            };
+            if size == 0 {
+                break;
+            }
+            self.data.extend_from_slice(&buf[..size]);
+        }
+        Ok(Async::Ready((self.conn.take().unwrap(), self.data.split_off(0))))
+    }
+}
+
+fn is_complete(data: &[u8]) -> bool {
+    if Handshake::is_prefix(data) {
+        Handshake::parse(data).map(|x| x.is_some()).unwrap_or(true)
+    } else {
+        HttpHead::parse(data).is_some() || data.len() >= MAX_HEAD_SIZE
+    }
+}
+
+/// A connection with some data that was already read from it.
+pub struct PrefixedIo<T> {
+    inner: T,
+    prefix: Vec<u8>,
+    offset: usize
+}
+
+impl<T> PrefixedIo<T> {
+    pub fn new(inner: T, prefix: Vec<u8>) -> PrefixedIo<T> {
+        PrefixedIo{inner, prefix, offset: 0}
+    }
+}
+
+impl<T: Read> Read for PrefixedIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset < self.prefix.len() {
+            let size = buf.len().min(self.prefix.len() - self.offset);
+            buf[..size].copy_from_slice(&self.prefix[self.offset..self.offset + size]);
+            self.offset += size;
+            Ok(size)
+        } else {
+            self.inner.read(buf)
+        }
+    }
+}
+
+impl<T: Write> Write for PrefixedIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for PrefixedIo<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for PrefixedIo<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
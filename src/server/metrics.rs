@@ -0,0 +1,244 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::{Future, IntoFuture};
+use hyper;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper::header::ContentType;
+use hyper::server::Service;
+
+use server::TunnelService;
+
+/// The APIs which requests are counted for. WebSocket upgrades and raw
+/// tunnels count as requests too.
+const APIS: [&str; 12] = ["challenge", "time", "connect", "upload", "upstream", "download",
+    "stream", "close", "reset", "websocket", "tunnel", "invalid"];
+
+/// Counters of what a TunnelService has done since it was built, shared by
+/// every clone of the service and every session.
+#[derive(Default)]
+pub struct Metrics {
+    sessions_created: AtomicU64,
+    sessions_timed_out: AtomicU64,
+
+    /// Bytes written to remote hosts.
+    uploaded: AtomicU64,
+
+    /// Bytes read from remote hosts.
+    downloaded: AtomicU64,
+
+    /// Requests to each of `APIS`.
+    requests: [AtomicU64; 12],
+    auth_failures: AtomicU64,
+    connect_errors: AtomicU64
+}
+
+impl Metrics {
+    pub fn session_created(&self) {
+        self.sessions_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_timed_out(&self) {
+        self.sessions_timed_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn uploaded(&self, size: usize) {
+        self.uploaded.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn downloaded(&self, size: usize) {
+        self.downloaded.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn request(&self, api: &str) {
+        if let Some(i) = APIS.iter().position(|x| *x == api) {
+            self.requests[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn auth_failed(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connect_failed(&self) {
+        self.connect_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Write the metrics in the Prometheus text format, along with the number
+    /// of sessions which are open.
+    pub fn render(&self, open_sessions: usize) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("sessions_created_total", "Sessions created, including WebSockets and raw tunnels.",
+                &self.sessions_created),
+            ("sessions_timed_out_total", "Sessions removed after going idle.",
+                &self.sessions_timed_out),
+            ("upload_bytes_total", "Bytes written to remote hosts.", &self.uploaded),
+            ("download_bytes_total", "Bytes read from remote hosts.", &self.downloaded),
+            ("auth_failures_total", "Failed logins.", &self.auth_failures),
+            ("connect_errors_total", "Failed lookups of and connections to remote hosts.",
+                &self.connect_errors)
+        ];
+        header(&mut out, "sessions_open", "gauge",
+            "Sessions open, including WebSockets and raw tunnels.");
+        writeln!(out, "squidtun_sessions_open {}", open_sessions).unwrap();
+        for &(name, help, counter) in &counters {
+            header(&mut out, name, "counter", help);
+            writeln!(out, "squidtun_{} {}", name, counter.load(Ordering::Relaxed)).unwrap();
+        }
+        header(&mut out, "requests_total", "counter", "Requests by API.");
+        for (api, count) in APIS.iter().zip(&self.requests) {
+            writeln!(out, "squidtun_requests_total{{api=\"{}\"}} {}", api,
+                count.load(Ordering::Relaxed)).unwrap();
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP squidtun_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE squidtun_{} {}", name, kind).unwrap();
+}
+
+/// Answers "GET /metrics" with a service's metrics, for an admin listener.
+pub struct MetricsService {
+    pub service: TunnelService
+}
+
+impl Service for MetricsService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let response = if *req.method() != Method::Get || req.path() != "/metrics" {
+            Response::new().with_status(StatusCode::NotFound)
+        } else {
+            Response::new()
+                .with_header(ContentType("text/plain; version=0.0.4".parse().unwrap()))
+                .with_body(self.service.metrics())
+        };
+        Box::new(Ok(response).into_future())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::Stream;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+
+    use proof::current_proof;
+    use server::ServerBuilder;
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.session_created();
+        metrics.session_created();
+        metrics.session_timed_out();
+        metrics.uploaded(100);
+        metrics.uploaded(23);
+        metrics.downloaded(456);
+        metrics.request("connect");
+        metrics.request("upload");
+        metrics.request("upload");
+        metrics.request("nonsense");
+        metrics.auth_failed();
+        metrics.connect_failed();
+
+        let text = metrics.render(3);
+        let lines = text.lines().collect::<Vec<_>>();
+        for line in &["# HELP squidtun_sessions_open Sessions open, including WebSockets and \
+                raw tunnels.", "# TYPE squidtun_sessions_open gauge", "squidtun_sessions_open 3",
+                "# TYPE squidtun_sessions_created_total counter",
+                "squidtun_sessions_created_total 2", "squidtun_sessions_timed_out_total 1",
+                "squidtun_upload_bytes_total 123", "squidtun_download_bytes_total 456",
+                "squidtun_auth_failures_total 1", "squidtun_connect_errors_total 1",
+                "# TYPE squidtun_requests_total counter",
+                "squidtun_requests_total{api=\"connect\"} 1",
+                "squidtun_requests_total{api=\"upload\"} 2",
+                "squidtun_requests_total{api=\"invalid\"} 0"] {
+            assert!(lines.contains(line), "missing {:?} in:\n{}", line, text);
+        }
+        // Every line is a comment or a sample.
+        assert!(lines.iter().all(|x| x.starts_with('#') || x.starts_with("squidtun_")));
+        assert_eq!(lines.iter().filter(|x| x.starts_with("squidtun_requests_total")).count(),
+            APIS.len());
+    }
+
+    #[test]
+    fn counts_service_requests() {
+        let mut core = Core::new().unwrap();
+        let service = ServerBuilder::new().password("secret").build(&core.handle()).unwrap();
+        let req = Request::new(Method::Get, "/time/x/y".parse().unwrap());
+        core.run(service.call(req)).unwrap();
+        let uri = format!("/connect/{}/x", current_proof("wrong"));
+        core.run(service.call(Request::new(Method::Get, uri.parse().unwrap()))).unwrap();
+
+        let metrics = service.metrics();
+        assert!(metrics.contains("squidtun_requests_total{api=\"time\"} 1\n"));
+        assert!(metrics.contains("squidtun_requests_total{api=\"connect\"} 1\n"));
+        assert!(metrics.contains("squidtun_auth_failures_total 1\n"));
+        assert!(metrics.contains("squidtun_sessions_open 0\n"));
+    }
+
+    #[test]
+    fn only_get_metrics_is_served() {
+        let mut core = Core::new().unwrap();
+        let service = ServerBuilder::new().password("secret").build(&core.handle()).unwrap();
+        let metrics = MetricsService{service};
+        for &(ref method, path) in &[(Method::Post, "/metrics"), (Method::Get, "/"),
+                (Method::Get, "/metrics/x"), (Method::Get, "/time/x/y")] {
+            let resp = core.run(metrics.call(Request::new(method.clone(), path.parse().unwrap())))
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::NotFound, "{} {}", method, path);
+        }
+        let resp = core.run(metrics.call(Request::new(Method::Get, "/metrics".parse().unwrap())))
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::Ok);
+        let body = core.run(resp.body().concat2()).unwrap();
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("squidtun_sessions_open 0"));
+    }
+
+    #[test]
+    fn serve_metrics() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let service = ServerBuilder::new().password("secret").build(&handle).unwrap();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let get = |path: &str| {
+                let mut conn = net::TcpStream::connect(addr).unwrap();
+                write!(conn, "GET {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", path)
+                    .unwrap();
+                let mut resp = String::new();
+                conn.read_to_string(&mut resp).unwrap();
+                resp
+            };
+            (get("/metrics"), get("/connect/x/y"))
+        });
+        let conns = listener.incoming().take(2).for_each(|(conn, _)| {
+            handle.spawn(service.serve_metrics(conn));
+            Ok(())
+        });
+        core.run(conns).unwrap();
+        let (metrics, other) = loop {
+            core.turn(Some(Duration::from_millis(10)));
+            if client.is_finished() {
+                break client.join().unwrap();
+            }
+        };
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(metrics.contains("squidtun_sessions_open 0\n"));
+        assert!(other.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
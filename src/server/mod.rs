@@ -0,0 +1,262 @@
+mod auth;
+mod config;
+mod guard;
+mod listener;
+mod metrics;
+mod policy;
+mod relay;
+mod service;
+mod session;
+mod stream;
+mod throttle;
+mod websocket;
+
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use tokio_core::reactor::{Handle, Interval};
+
+use error::Error;
+use resolve::RemoteAddr;
+use server::guard::LoginGuard;
+use server::metrics::Metrics;
+use server::policy::Enforcer;
+use server::session::Session;
+
+pub use server::auth::{AuthFuture, Authenticator, CommandAuth, Credential, CredentialsAuth,
+    Login, PasswordAuth, PublicKeyAuth};
+pub use server::config::ServerConfig;
+pub use server::policy::{Policies, Policy};
+pub use server::service::TunnelService;
+
+/// Decides which clients may open sessions.
+type Auth = Rc<dyn Authenticator>;
+
+/// Picks the host to connect a new session to, given the target that a raw
+/// tunnel asked for, if any.
+type Resolver = Rc<dyn Fn(Option<&str>) -> Result<RemoteAddr, Error>>;
+
+/// The settings a TunnelService gives to new sessions, which can be swapped
+/// out while it runs.
+struct Settings {
+    auth: Auth,
+    resolver: Resolver,
+    limits: Limits
+}
+
+/// Limits on the resources a TunnelService gives to clients.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// The largest chunk to accept or send per request.
+    pub max_chunk_size: usize,
+
+    /// How long an idle session is kept.
+    pub session_timeout: Duration,
+
+    /// The most upload data to hold on to for a session while waiting for
+    /// earlier chunks or for the remote end to accept it.
+    pub max_buffered_upload: usize,
+
+    /// The most download data to keep around for a session in case the
+    /// client needs it again.
+    pub max_unacked_download: usize,
+
+    /// How old a proof of the password may be, in seconds.
+    pub max_proof_age: u64,
+
+    /// The most sessions to have open at once, across all users.
+    pub max_sessions: Option<usize>,
+
+    /// The most login attempts to allow per minute from each client address.
+    pub max_logins_per_address: Option<u32>,
+
+    /// The most login attempts to allow per second from all clients.
+    pub max_logins: Option<u32>,
+
+    /// How many failed logins in a row get a user banned from the client
+    /// address they failed from.
+    pub max_login_failures: Option<u32>,
+
+    /// How long a banned user is turned away for.
+    pub ban_duration: Duration,
+
+    /// If true, refuse to create sessions whose requests aren't signed with
+    /// MACs, such as those of version 1 clients. Raw tunnels and WebSockets
+    /// don't have session IDs, so they are still allowed.
+    pub require_mac: bool
+}
+
+impl Limits {
+    /// Check that the limits can be used together.
+    pub fn check(&self) -> Result<(), String> {
+        // A chunk which can't be buffered would be refused forever.
+        if self.max_chunk_size > self.max_buffered_upload {
+            return Err(format!("max_chunk ({}) is larger than max_buffered_upload ({})",
+                self.max_chunk_size, self.max_buffered_upload));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits{
+            max_chunk_size: 65536,
+            session_timeout: Duration::from_secs(30),
+            max_buffered_upload: 1 << 20,
+            max_unacked_download: 4 << 20,
+            max_proof_age: 60,
+            max_sessions: Some(1024),
+            max_logins_per_address: Some(60),
+            max_logins: Some(50),
+            max_login_failures: Some(10),
+            ban_duration: Duration::from_secs(300),
+            require_mac: false
+        }
+    }
+}
+
+/// Configures a TunnelService.
+///
+/// By default, clients must prove they know an empty password, and sessions
+/// have nowhere to connect to.
+pub struct ServerBuilder {
+    password: String,
+    auth: Option<Auth>,
+    resolver: Option<Resolver>,
+    policies: Policies,
+    limits: Limits
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder{
+            password: String::new(),
+            auth: None,
+            resolver: None,
+            policies: Policies::new(),
+            limits: Limits::default()
+        }
+    }
+
+    /// Accept clients which prove they know `password`.
+    pub fn password(mut self, password: &str) -> ServerBuilder {
+        self.password = password.to_owned();
+        self
+    }
+
+    /// Decide which clients to accept with a custom authenticator instead of
+    /// the password.
+    pub fn auth<A: Authenticator + 'static>(mut self, auth: A) -> ServerBuilder {
+        self.auth = Some(Rc::new(auth));
+        self
+    }
+
+    /// Connect every session to `addr`, which may be a SocketAddr or a
+    /// RemoteAddr with a hostname.
+    ///
+    /// Raw tunnels which ask for any other target are turned away.
+    pub fn remote<A: Into<RemoteAddr>>(self, addr: A) -> ServerBuilder {
+        self.targets(vec![addr.into()])
+    }
+
+    /// Connect sessions to the first of `targets`, unless a raw tunnel asks
+    /// for one of the others.
+    ///
+    /// Raw tunnels which ask for any other target are turned away.
+    pub fn targets(self, targets: Vec<RemoteAddr>) -> ServerBuilder {
+        self.resolver(move |target| {
+            match target {
+                None if !targets.is_empty() => Ok(targets[0].clone()),
+                None => Err(Error::Upstream("no remote host".to_owned())),
+                Some(target) => {
+                    let found = target.parse::<RemoteAddr>()
+                        .ok()
+                        .and_then(|x| targets.iter().find(|y| **y == x).cloned());
+                    found.ok_or_else(|| {
+                        Error::Forbidden(format!("target not allowed: {}", target))
+                    })
+                }
+            }
+        })
+    }
+
+    /// Pick the host for each new session with a custom function, which
+    /// yields a SocketAddr or a RemoteAddr.
+    ///
+    /// The function gets the target that a raw tunnel asked for, if any.
+    pub fn resolver<F, A>(mut self, resolver: F) -> ServerBuilder
+        where F: Fn(Option<&str>) -> Result<A, Error> + 'static,
+              A: Into<RemoteAddr>
+    {
+        self.resolver = Some(Rc::new(move |target| resolver(target).map(Into::into)));
+        self
+    }
+
+    /// Restrict what each user may do.
+    pub fn policies(mut self, policies: Policies) -> ServerBuilder {
+        self.policies = policies;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> ServerBuilder {
+        self.limits = limits;
+        self
+    }
+
+    /// Create the service, and start expiring its idle sessions on the
+    /// reactor behind `handle`.
+    ///
+    /// Fails if the limits can't be used together.
+    pub fn build(self, handle: &Handle) -> Result<TunnelService, String> {
+        let (settings, policies) = self.into_settings()?;
+        let sessions = Arc::new(RwLock::new(Vec::new()));
+        let metrics = Arc::new(Metrics::default());
+        handle.spawn(timeout_loop(sessions.clone(), metrics.clone(), handle));
+        let enforcer = Rc::new(Enforcer::new(policies, settings.limits.max_sessions));
+        let guard = Rc::new(LoginGuard::new(settings.limits));
+        Ok(TunnelService::new(handle.clone(), sessions, settings, enforcer, guard, metrics))
+    }
+
+    fn into_settings(self) -> Result<(Settings, Policies), String> {
+        self.limits.check()?;
+        let password = self.password;
+        let auth = self.auth.unwrap_or_else(|| Rc::new(PasswordAuth::new(&password)));
+        let resolver = self.resolver.unwrap_or_else(|| {
+            Rc::new(|_| Err(Error::Upstream("no remote host".to_owned())))
+        });
+        Ok((Settings{auth, resolver, limits: self.limits}, self.policies))
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+}
+
+fn timeout_loop(
+    sessions: Arc<RwLock<Vec<Session>>>,
+    metrics: Arc<Metrics>,
+    handle: &Handle
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    Box::new(Interval::new(Duration::from_secs(1), handle).unwrap()
+        .map_err(|_| ())
+        .for_each(move |_| {
+            let sessions: &mut Vec<Session> = &mut sessions.write().unwrap();
+            for i in (0..sessions.len()).rev() {
+                sessions[i].flush_writes().ok();
+                if sessions[i].is_timed_out() {
+                    info!("session timed out: {}", sessions[i].id);
+                    metrics.session_timed_out();
+                    sessions.remove(i);
+                } else if sessions[i].is_finished() {
+                    info!("removed session: {}", sessions[i].id);
+                    sessions.remove(i);
+                }
+            }
+            Ok(())
+        }))
+}
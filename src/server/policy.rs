@@ -0,0 +1,453 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use error::Error;
+use resolve::RemoteAddr;
+
+/// What a user is allowed to do.
+///
+/// Every limit is optional, and the default policy allows everything.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    /// The addresses the user may connect to.
+    pub targets: Option<Vec<SocketAddr>>,
+
+    /// The most sessions the user may have open at once.
+    pub max_sessions: Option<usize>,
+
+    /// The most bytes per second that the user's sessions may carry, counting
+    /// both directions together.
+    pub max_rate: Option<u64>,
+
+    /// The time of day when the user may connect, as a start and an end in
+    /// minutes after midnight UTC. The window wraps around midnight if the
+    /// end is before the start.
+    pub hours: Option<(u32, u32)>
+}
+
+impl Policy {
+    /// Parse a list of settings such as "targets=127.0.0.1:22 sessions=2
+    /// rate=65536 hours=08:00-18:00".
+    pub fn parse(settings: &str) -> Result<Policy, String> {
+        let mut policy = Policy::default();
+        for setting in settings.split_whitespace() {
+            let mut parts = setting.splitn(2, '=');
+            let (name, value) = (parts.next().unwrap(), parts.next().unwrap_or(""));
+            let bad_value = || format!("invalid value for {}: {}", name, value);
+            match name {
+                "targets" => {
+                    let targets: Result<Vec<SocketAddr>, _> = value.split(',')
+                        .map(|x| x.parse())
+                        .collect();
+                    policy.targets = Some(targets.map_err(|_| bad_value())?);
+                },
+                "sessions" => policy.max_sessions = Some(value.parse().map_err(|_| bad_value())?),
+                "rate" => policy.max_rate = Some(value.parse().map_err(|_| bad_value())?),
+                "hours" => policy.hours = Some(parse_hours(value).ok_or_else(bad_value)?),
+                _ => return Err(format!("unknown setting: {}", name))
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Check if the current time is inside the user's window.
+    fn in_hours(&self) -> bool {
+        self.in_hours_at(minute_of_day())
+    }
+
+    /// Check if a time, in minutes after midnight UTC, is inside the user's
+    /// window.
+    fn in_hours_at(&self, now: u32) -> bool {
+        let (start, end) = match self.hours {
+            Some(x) => x,
+            None => return true
+        };
+        if start <= end {
+            start <= now && now < end
+        } else {
+            now >= start || now < end
+        }
+    }
+}
+
+/// The policies for every user.
+#[derive(Clone, Debug, Default)]
+pub struct Policies {
+    users: HashMap<String, Policy>,
+    default: Policy
+}
+
+impl Policies {
+    pub fn new() -> Policies {
+        Policies::default()
+    }
+
+    /// Load policies from a file with a "<user> <settings>" line for each
+    /// user, in the format of Policy::parse(). A user of "*" sets the policy
+    /// for everyone without their own line, including shared password logins.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Policies> {
+        let path = path.as_ref();
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        let mut policies = Policies::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let user = parts.next().unwrap();
+            let policy = Policy::parse(parts.next().unwrap_or("")).map_err(|e| {
+                let msg = format!("{}:{}: {}", path.display(), i + 1, e);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?;
+            if user == "*" {
+                policies.set_default(policy);
+            } else {
+                policies.set(user, policy);
+            }
+        }
+        Ok(policies)
+    }
+
+    pub fn set(&mut self, user: &str, policy: Policy) {
+        self.users.insert(user.to_owned(), policy);
+    }
+
+    /// Set the policy for users without their own.
+    pub fn set_default(&mut self, policy: Policy) {
+        self.default = policy;
+    }
+
+    /// Get the policy for a user, or for a shared password login.
+    pub fn get(&self, user: Option<&str>) -> &Policy {
+        user.and_then(|x| self.users.get(x)).unwrap_or(&self.default)
+    }
+}
+
+/// Admits sessions according to each user's policy, and keeps track of what
+/// the users are using.
+pub struct Enforcer {
+    policies: RefCell<Policies>,
+    usage: RefCell<HashMap<Option<String>, Arc<Mutex<Usage>>>>,
+
+    /// The most sessions to have open at once, across all users.
+    max_sessions: Cell<Option<usize>>,
+    sessions: Arc<AtomicUsize>
+}
+
+impl Enforcer {
+    pub fn new(policies: Policies, max_sessions: Option<usize>) -> Enforcer {
+        Enforcer{
+            policies: RefCell::new(policies),
+            usage: RefCell::new(HashMap::new()),
+            max_sessions: Cell::new(max_sessions),
+            sessions: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    /// Admit new sessions according to different policies. Sessions which
+    /// are already open keep the policies they were admitted with.
+    pub fn reconfigure(&self, policies: Policies, max_sessions: Option<usize>) {
+        *self.policies.borrow_mut() = policies;
+        self.max_sessions.set(max_sessions);
+    }
+
+    /// Get how many sessions are open across all users.
+    pub fn open_sessions(&self) -> usize {
+        self.sessions.load(Ordering::SeqCst)
+    }
+
+    /// Check that a user may open a session to `remote`, yielding the ones
+    /// of its addresses which they may connect to, and a ticket which holds
+    /// their place until it is dropped.
+    pub fn admit(
+        &self,
+        user: Option<String>,
+        remote: &RemoteAddr,
+        addrs: Vec<SocketAddr>
+    ) -> Result<(Vec<SocketAddr>, Arc<Ticket>), Error> {
+        let policy = self.policies.borrow().get(user.as_deref()).clone();
+        let name = user_name(&user).to_owned();
+        let deny = |msg: &str| {
+            info!("denied {}: {}", name, msg);
+            Err(Error::Forbidden(msg.to_owned()))
+        };
+        if self.max_sessions.get().is_some_and(|x| self.sessions.load(Ordering::SeqCst) >= x) {
+            info!("turned away {}: too many sessions on the server", name);
+            return Err(Error::Busy("server is full".to_owned()));
+        }
+        let addrs = match policy.targets {
+            Some(ref targets) => addrs.into_iter().filter(|x| targets.contains(x)).collect(),
+            None => addrs
+        };
+        if addrs.is_empty() {
+            return deny(&format!("target not allowed: {}", remote));
+        } else if !policy.in_hours() {
+            return deny("outside allowed hours");
+        }
+        let tokens = policy.max_rate.unwrap_or(0) as f64;
+        let usage = self.usage.borrow_mut().entry(user.clone()).or_insert_with(|| {
+            Arc::new(Mutex::new(Usage{sessions: 0, tokens, refilled: Instant::now()}))
+        }).clone();
+        {
+            let mut usage = usage.lock().unwrap();
+            if policy.max_sessions.is_some_and(|x| usage.sessions >= x) {
+                return deny("too many sessions");
+            }
+            usage.sessions += 1;
+        }
+        self.sessions.fetch_add(1, Ordering::SeqCst);
+        Ok((addrs, Arc::new(Ticket{user, policy, usage, sessions: self.sessions.clone()})))
+    }
+}
+
+/// Why a ticket can't carry any more data right now.
+pub enum Limited {
+    /// The user is out of bandwidth until the duration has passed.
+    Wait(Duration),
+
+    /// The user may no longer use the session.
+    Denied(Error)
+}
+
+/// One session's place in its user's policy.
+pub struct Ticket {
+    user: Option<String>,
+    policy: Policy,
+    usage: Arc<Mutex<Usage>>,
+
+    /// The count of sessions across all users.
+    sessions: Arc<AtomicUsize>
+}
+
+impl Ticket {
+    /// Get the name of the user, for logging.
+    pub fn user(&self) -> &str {
+        user_name(&self.user)
+    }
+
+    /// Take up to `size` bytes of the user's bandwidth.
+    pub fn take(&self, size: usize) -> Result<usize, Limited> {
+        if !self.policy.in_hours() {
+            info!("cut off {}: outside allowed hours", self.user());
+            return Err(Limited::Denied(Error::Forbidden("outside allowed hours".to_owned())));
+        }
+        let rate = match self.policy.max_rate {
+            Some(rate) => rate.max(1) as f64,
+            None => return Ok(size)
+        };
+        let mut usage = self.usage.lock().unwrap();
+        // Allow bursts of up to a second's worth of data.
+        let elapsed = usage.refilled.elapsed();
+        usage.refilled = Instant::now();
+        usage.tokens = (usage.tokens + rate * elapsed.as_secs_f64()).min(rate);
+        if usage.tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - usage.tokens) / rate);
+            return Err(Limited::Wait(wait.max(Duration::from_millis(1))));
+        }
+        let allowed = (size as f64).min(usage.tokens.floor());
+        usage.tokens -= allowed;
+        Ok(allowed as usize)
+    }
+
+    /// Give back bandwidth that was taken but not used.
+    pub fn refund(&self, size: usize) {
+        if self.policy.max_rate.is_some() && size > 0 {
+            self.usage.lock().unwrap().tokens += size as f64;
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.usage.lock().unwrap().sessions -= 1;
+        self.sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// What one user is currently using.
+struct Usage {
+    sessions: usize,
+
+    /// The bytes the user may send right away.
+    tokens: f64,
+    refilled: Instant
+}
+
+fn user_name(user: &Option<String>) -> &str {
+    user.as_deref().unwrap_or("(shared password)")
+}
+
+/// Get the current time in minutes after midnight UTC.
+fn minute_of_day() -> u32 {
+    let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    ((epoch % 86400) / 60) as u32
+}
+
+/// Parse a window such as "08:00-18:00".
+pub fn parse_hours(value: &str) -> Option<(u32, u32)> {
+    let mut parts = value.splitn(2, '-');
+    Some((parse_time(parts.next()?)?, parse_time(parts.next()?)?))
+}
+
+fn parse_time(value: &str) -> Option<u32> {
+    let mut parts = value.splitn(2, ':');
+    let hours: u32 = parts.next()?.parse().ok()?;
+    let minutes: u32 = parts.next()?.parse().ok()?;
+    if hours > 24 || minutes >= 60 || hours * 60 + minutes > 24 * 60 {
+        return None;
+    }
+    Some(hours * 60 + minutes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A window which the current time is outside of.
+    fn closed_hours() -> Option<(u32, u32)> {
+        let now = minute_of_day();
+        Some(((now + 60) % 1440, (now + 120) % 1440))
+    }
+
+    fn ticket(policy: Policy) -> Arc<Ticket> {
+        let mut policies = Policies::new();
+        policies.set_default(policy);
+        let enforcer = Enforcer::new(policies, None);
+        let remote = "127.0.0.1:22".parse().unwrap();
+        enforcer.admit(None, &remote, vec!["127.0.0.1:22".parse().unwrap()]).unwrap().1
+    }
+
+    #[test]
+    fn parse_settings() {
+        let policy = Policy::parse("targets=127.0.0.1:22,10.0.0.1:80 sessions=2 rate=65536 \
+            hours=08:00-18:30").unwrap();
+        assert_eq!(policy.targets, Some(vec!["127.0.0.1:22".parse().unwrap(),
+            "10.0.0.1:80".parse().unwrap()]));
+        assert_eq!(policy.max_sessions, Some(2));
+        assert_eq!(policy.max_rate, Some(65536));
+        assert_eq!(policy.hours, Some((480, 1110)));
+
+        let policy = Policy::parse("").unwrap();
+        assert!(policy.targets.is_none() && policy.max_sessions.is_none());
+    }
+
+    #[test]
+    fn malformed_policies() {
+        for settings in &["sessions=x", "sessions", "rate=-1", "targets=example.com:22",
+                "targets=", "hours=08:00", "hours=8-18", "colour=red"] {
+            assert!(Policy::parse(settings).is_err(), "{}", settings);
+        }
+    }
+
+    #[test]
+    fn parse_windows() {
+        assert_eq!(parse_hours("00:00-24:00"), Some((0, 1440)));
+        assert_eq!(parse_hours("22:30-06:15"), Some((1350, 375)));
+        assert_eq!(parse_hours("9:05-17:00"), Some((545, 1020)));
+        for value in &["24:01-01:00", "25:00-01:00", "08:60-09:00", "08:00", "08-09", "a:b-c:d",
+                "08:00-"] {
+            assert_eq!(parse_hours(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn windows() {
+        let policy = Policy{hours: Some((480, 1080)), ..Policy::default()};
+        assert!(policy.in_hours_at(480));
+        assert!(policy.in_hours_at(1079));
+        assert!(!policy.in_hours_at(1080));
+        assert!(!policy.in_hours_at(0));
+
+        // The window wraps around midnight.
+        let policy = Policy{hours: Some((1320, 360)), ..Policy::default()};
+        assert!(policy.in_hours_at(1320));
+        assert!(policy.in_hours_at(1439));
+        assert!(policy.in_hours_at(0));
+        assert!(policy.in_hours_at(359));
+        assert!(!policy.in_hours_at(360));
+        assert!(!policy.in_hours_at(720));
+
+        assert!(Policy::default().in_hours_at(720));
+        assert!(!Policy{hours: closed_hours(), ..Policy::default()}.in_hours());
+    }
+
+    #[test]
+    fn admission() {
+        let mut policies = Policies::new();
+        policies.set("alice", Policy::parse("targets=127.0.0.1:22 sessions=1").unwrap());
+        policies.set("bob", Policy{hours: closed_hours(), ..Policy::default()});
+        let enforcer = Enforcer::new(policies, Some(2));
+        let remote = "localhost:22".parse().unwrap();
+        let addrs = || vec!["[::1]:22".parse().unwrap(), "127.0.0.1:22".parse().unwrap()];
+        let alice = Some("alice".to_owned());
+
+        let (allowed, ticket) = enforcer.admit(alice.clone(), &remote, addrs()).unwrap();
+        assert_eq!(allowed, vec!["127.0.0.1:22".parse().unwrap()]);
+        match enforcer.admit(alice.clone(), &remote, addrs()) {
+            Err(Error::Forbidden(_)) => (),
+            _ => panic!("second session admitted")
+        }
+        drop(ticket);
+        let (_, ticket) = enforcer.admit(alice.clone(), &remote, addrs()).unwrap();
+        match enforcer.admit(alice, &remote, vec!["10.0.0.1:22".parse().unwrap()]) {
+            Err(Error::Forbidden(_)) => (),
+            _ => panic!("target admitted")
+        }
+        match enforcer.admit(Some("bob".to_owned()), &remote, addrs()) {
+            Err(Error::Forbidden(_)) => (),
+            _ => panic!("admitted outside hours")
+        }
+
+        // The server's own limit counts everyone's sessions.
+        let _other = enforcer.admit(None, &remote, addrs()).unwrap();
+        assert_eq!(enforcer.open_sessions(), 2);
+        match enforcer.admit(Some("carol".to_owned()), &remote, addrs()) {
+            Err(Error::Busy(_)) => (),
+            _ => panic!("server limit ignored")
+        }
+        drop(ticket);
+        assert_eq!(enforcer.open_sessions(), 1);
+    }
+
+    #[test]
+    fn bandwidth_runs_out() {
+        let limited = ticket(Policy{max_rate: Some(1000), ..Policy::default()});
+        assert_eq!(limited.take(600).ok(), Some(600));
+        assert_eq!(limited.take(600).ok(), Some(400));
+        match limited.take(1) {
+            Err(Limited::Wait(wait)) => assert!(wait > Duration::from_millis(0)),
+            _ => panic!("bandwidth not exhausted")
+        }
+        limited.refund(100);
+        assert_eq!(limited.take(600).ok(), Some(100));
+
+        let unlimited = ticket(Policy::default());
+        assert_eq!(unlimited.take(1 << 30).ok(), Some(1 << 30));
+    }
+
+    #[test]
+    fn tickets_expire_with_hours() {
+        // Sessions are only admitted inside the window, but may outlast it.
+        let usage = Usage{sessions: 1, tokens: 0.0, refilled: Instant::now()};
+        let ticket = Ticket{
+            user: None,
+            policy: Policy{hours: closed_hours(), ..Policy::default()},
+            usage: Arc::new(Mutex::new(usage)),
+            sessions: Arc::new(AtomicUsize::new(1))
+        };
+        match ticket.take(1) {
+            Err(Limited::Denied(Error::Forbidden(_))) => (),
+            _ => panic!("ticket used outside hours")
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::io::Write;
+use std::net::Shutdown;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Future, Poll};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::AsyncWrite;
+use tokio_io::io::{copy, shutdown};
+
+use error::is_reset;
+use server::metrics::Metrics;
+use server::policy::Ticket;
+use server::throttle::Throttled;
+use stream_util::SharedStream;
+
+/// Copy data both ways between two connections until both directions have
+/// reached EOF.
+///
+/// Each EOF is passed along as a write shutdown, so half-closed connections
+/// keep working. Both directions count against the user's policy through
+/// `ticket`. Yields the number of bytes copied from `a` to `b` and from `b`
+/// to `a`, which `metrics` counts as uploads and downloads as they go.
+///
+/// If `closed` resolves first, both connections get an EOF and the relay
+/// fails. If either connection is reset, the other one is reset too.
+pub fn relay(
+    a: TcpStream,
+    b: TcpStream,
+    ticket: Arc<Ticket>,
+    closed: Box<dyn Future<Item = (), Error = ()>>,
+    metrics: Arc<Metrics>,
+    handle: &Handle
+) -> Box<dyn Future<Item = (u64, u64), Error = io::Error>> {
+    let a = Rc::new(a);
+    let b = Rc::new(b);
+    let (close_a, close_b) = (a.clone(), b.clone());
+    let (reset_a, reset_b) = (a.clone(), b.clone());
+    let close = closed.then(move |_| {
+        close_a.shutdown(Shutdown::Write).ok();
+        close_b.shutdown(Shutdown::Write).ok();
+        Err(io::Error::other("server is shutting down"))
+    });
+    let a_reader = Throttled::new(SharedStream(a.clone()), ticket.clone(), handle.remote().clone());
+    let b_reader = Throttled::new(SharedStream(b.clone()), ticket, handle.remote().clone());
+    let upload_metrics = metrics.clone();
+    let b_writer = Counted{stream: SharedStream(b), count: move |x| upload_metrics.uploaded(x)};
+    let a_writer = Counted{stream: SharedStream(a), count: move |x| metrics.downloaded(x)};
+    let forward = copy(a_reader, b_writer)
+        .and_then(|(size, _, b)| shutdown(b).map(move |_| size));
+    let backward = copy(b_reader, a_writer)
+        .and_then(|(size, _, a)| shutdown(a).map(move |_| size));
+    Box::new(forward.join(backward).select(close).map(|(x, _)| x).map_err(move |(e, _)| {
+        // Closing a connection without lingering resets it.
+        if is_reset(&e) {
+            reset_a.set_linger(Some(Duration::from_secs(0))).ok();
+            reset_b.set_linger(Some(Duration::from_secs(0))).ok();
+        }
+        e
+    }))
+}
+
+/// A connection which reports how much is written to it.
+struct Counted<F: Fn(usize)> {
+    stream: SharedStream,
+    count: F
+}
+
+impl<F: Fn(usize)> Write for Counted<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.stream.write(buf)?;
+        (self.count)(size);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<F: Fn(usize)> AsyncWrite for Counted<F> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
@@ -0,0 +1,1212 @@
+use std::cell::RefCell;
+use std::iter::Iterator;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, Duration};
+
+use futures::{Future, IntoFuture, Sink, Stream};
+use futures::future::Either;
+use hyper;
+use hyper::{Body, Chunk, Request, Response, StatusCode};
+use hyper::header::{CacheControl, CacheDirective, ContentLength, ContentType, Expires, Pragma};
+use hyper::server::{Http, Service};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::write_all;
+
+use drain::Drain;
+use error::Error;
+use handshake::{Handshake, HandshakeReply};
+use headers::{MaxChunkSize, ProtocolHeader, SessionSecret};
+use mac::{MAC_SIZE, SessionKey};
+use proof::unix_time;
+use protocol::{Agreement, Features, Hello};
+use resolve::connect_any;
+use response::{ResponseFrame, Status};
+use server::{Auth, Limits, ServerBuilder, Settings};
+use server::auth::{AuthFuture, Credential, Login};
+use server::guard::LoginGuard;
+use server::listener::serve_connection;
+use server::metrics::{Metrics, MetricsService};
+use server::policy::{Enforcer, Ticket};
+use server::relay::relay;
+use server::session::{NonBlocking, Session, upstream_error};
+use server::stream::{DownloadStream, UploadStream};
+use server::websocket::WebSocketTunnel;
+use stream::encode_offset;
+use uid::{generate_session_id, random_bytes};
+use websocket::{HttpHead, websocket_accept};
+
+type ApiFuture = Box<dyn Future<Item = Reply, Error = Error>>;
+
+/// Serves the tunnel API and raw tunnels.
+///
+/// Every clone shares the same sessions and settings, so a clone can be made
+/// for each connection. Create one with a ServerBuilder.
+#[derive(Clone)]
+pub struct TunnelService {
+    handle: Handle,
+    sessions: Arc<RwLock<Vec<Session>>>,
+    settings: Rc<RefCell<Settings>>,
+    enforcer: Rc<Enforcer>,
+    guard: Rc<LoginGuard>,
+    drain: Rc<Drain>,
+    metrics: Arc<Metrics>,
+
+    /// The address of the client on the connection being served, if known.
+    peer: Option<IpAddr>
+}
+
+impl TunnelService {
+    pub(super) fn new(
+        handle: Handle,
+        sessions: Arc<RwLock<Vec<Session>>>,
+        settings: Settings,
+        enforcer: Rc<Enforcer>,
+        guard: Rc<LoginGuard>,
+        metrics: Arc<Metrics>
+    ) -> TunnelService {
+        let settings = Rc::new(RefCell::new(settings));
+        let drain = Rc::new(Drain::new());
+        TunnelService{handle, sessions, settings, enforcer, guard, drain, metrics, peer: None}
+    }
+
+    /// Switch to the settings of `builder`, for every clone of the service.
+    ///
+    /// Only new sessions get the new settings. Open sessions keep running
+    /// with their old limits and policies, but still count towards the new
+    /// session limits, and banned addresses stay banned. If the limits can't
+    /// be used together, the old settings are kept.
+    pub fn reconfigure(&self, builder: ServerBuilder) -> Result<(), String> {
+        let (settings, policies) = builder.into_settings()?;
+        self.enforcer.reconfigure(policies, settings.limits.max_sessions);
+        self.guard.set_limits(settings.limits);
+        *self.settings.borrow_mut() = settings;
+        Ok(())
+    }
+
+    /// Stop opening new sessions, for every clone of the service. Open
+    /// sessions keep running until they end or are closed.
+    pub fn drain(&self) {
+        self.drain.start();
+    }
+
+    /// Get how many sessions are open, including raw tunnels and WebSocket
+    /// sessions.
+    pub fn open_sessions(&self) -> usize {
+        self.enforcer.open_sessions()
+    }
+
+    /// Close every open session, sending an EOF to its remote host after
+    /// whatever the client had already uploaded. No more sessions are opened
+    /// afterwards.
+    ///
+    /// This flushes pending writes, so it has to be called from within a task.
+    pub fn close_sessions(&self) {
+        self.drain.close();
+        let sessions: &mut Vec<Session> = &mut self.sessions.write().unwrap();
+        for mut session in sessions.drain(..) {
+            session.flush_writes().ok();
+            session.send_eof();
+            info!("closed session: {}", session.id);
+        }
+    }
+
+    /// Get the service's metrics in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        self.metrics.render(self.open_sessions())
+    }
+
+    /// Serve a connection to an admin listener, which answers "GET /metrics"
+    /// with the service's metrics.
+    ///
+    /// The admin listener should not be reachable by clients.
+    pub fn serve_metrics(&self, conn: TcpStream) -> Box<dyn Future<Item = (), Error = ()>> {
+        Box::new(Http::<Chunk>::new().serve_connection(conn, MetricsService{service: self.clone()})
+            .map(|_| ())
+            .map_err(|e| info!("metrics connection error: {}", e)))
+    }
+
+    /// Serve a new connection, which may be a raw tunnel, a WebSocket upgrade,
+    /// or a series of API requests.
+    pub fn serve(&self, conn: TcpStream) -> Box<dyn Future<Item = (), Error = ()>> {
+        let mut service = self.clone();
+        service.peer = conn.peer_addr().ok().map(|x| x.ip());
+        serve_connection(conn, service)
+    }
+
+    /// Check if a request is meant for the tunnel API.
+    ///
+    /// This lets an application pass only the tunnel's requests to call().
+    /// WebSocket upgrades and raw tunnels need the whole connection, so they
+    /// are only handled by serve().
+    pub fn handles<B>(&self, req: &Request<B>) -> bool {
+        !matches!(RequestInfo::from_request(req), RequestInfo::Invalid)
+    }
+
+    /// Handle a WebSocket upgrade request of the form "/ws/<proof>/unused".
+    ///
+    /// On success, the connection carries a new session until either side
+    /// closes it.
+    pub(super) fn upgrade<T: AsyncRead + AsyncWrite + 'static>(
+        &self,
+        conn: T,
+        head: &HttpHead
+    ) -> Box<dyn Future<Item = (), Error = ()>> {
+        self.metrics.request("websocket");
+        let components = head.path().unwrap_or("").split('/').collect::<Vec<&str>>();
+        let key = head.header("sec-websocket-key").unwrap_or("").to_owned();
+        if components.len() < 3 || components[1] != "ws" {
+            return respond_and_close(conn, "404 Not Found");
+        }
+        let service = self.clone();
+        let limits = self.limits();
+        Box::new(self.authenticate(components[2]).then(move |res| {
+            let user = match res {
+                Ok(login) => login.user,
+                Err(Error::Busy(_)) => return respond_and_close(conn, "503 Service Unavailable"),
+                Err(e) => {
+                    info!("WebSocket login failed: {}", e);
+                    return respond_and_close(conn, "403 Forbidden");
+                }
+            };
+            let closed = service.drain.closed();
+            Box::new(service.open_remote(user, None).then(move |res| {
+                let (stream, ticket) = match res {
+                    Ok(x) => x,
+                    Err(Error::Forbidden(_)) => return respond_and_close(conn, "403 Forbidden"),
+                    Err(Error::Busy(_)) => {
+                        return respond_and_close(conn, "503 Service Unavailable");
+                    },
+                    Err(e) => {
+                        info!("WebSocket connect error: {}", e);
+                        return respond_and_close(conn, "502 Bad Gateway");
+                    }
+                };
+                let id = generate_session_id();
+                let metrics = service.metrics.clone();
+                let session = Session::new(id, stream, limits, ticket, metrics, &service.handle);
+                info!("created new WebSocket session: {} for {}", session.id, session.user());
+                service.metrics.session_created();
+                let id = session.id.clone();
+                let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                    Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                    Sec-WebSocket-Accept: {}\r\n\r\n", websocket_accept(&key));
+                let max_chunk_size = limits.max_chunk_size;
+                Box::new(write_all(conn, response)
+                    .map_err(Error::from)
+                    .and_then(move |(conn, _)| {
+                        WebSocketTunnel::new(conn, session, max_chunk_size, closed)
+                    })
+                    .then(move |res| {
+                        if let Err(e) = res {
+                            info!("WebSocket session {}: {}", id, e);
+                        }
+                        info!("closed WebSocket session: {}", id);
+                        Ok(())
+                    }))
+            }))
+        }))
+    }
+
+    /// Handle a raw tunnel, such as one made through an HTTP CONNECT request.
+    ///
+    /// Once the handshake is accepted, `leftover` (anything the client sent
+    /// after the handshake) is written to the remote host, and then data is
+    /// copied both ways until both sides are done.
+    pub(super) fn tunnel(
+        &self,
+        conn: TcpStream,
+        handshake: Handshake,
+        leftover: Vec<u8>
+    ) -> Box<dyn Future<Item = (), Error = ()>> {
+        self.metrics.request("tunnel");
+        let service = self.clone();
+        let handle = self.handle.clone();
+        Box::new(self.authenticate(&handshake.proof).then(move |res| {
+            let user = match res {
+                Ok(login) => login.user,
+                Err(e) => {
+                    info!("tunnel login failed: {}", e);
+                    return reply_and_close(conn, e);
+                }
+            };
+            let closed = service.drain.closed();
+            Box::new(service.open_remote(user, handshake.target.as_deref()).then(move |res| {
+                let (remote, ticket) = match res {
+                    Ok(x) => x,
+                    Err(e) => {
+                        info!("tunnel connect error: {}", e);
+                        return reply_and_close(conn, e);
+                    }
+                };
+                let id = generate_session_id();
+                info!("created new tunnel: {} for {}", id, ticket.user());
+                service.metrics.session_created();
+                let metrics = service.metrics.clone();
+                Box::new(write_all(remote, leftover)
+                    .join(write_all(conn, HandshakeReply::Accepted.encode()))
+                    .and_then(move |((remote, _), (conn, _))| {
+                        relay(conn, remote, ticket, closed, metrics, &handle)
+                    })
+                    .then(move |res| {
+                        if let Err(e) = res {
+                            info!("tunnel {}: {}", id, e);
+                        }
+                        info!("closed tunnel: {}", id);
+                        Ok(())
+                    }))
+            }))
+        }))
+    }
+
+    /// Create a session, with a key to sign its requests if the client asked
+    /// for one and the login has something to seal its secret to.
+    ///
+    /// The secret is made up for the session and sent back sealed, so only
+    /// the client which logged in can derive the key.
+    fn connect(&self, proof: &str, agreement: Agreement) -> ApiFuture {
+        let service = self.clone();
+        let limits = self.limits();
+        let signed = agreement.features.contains(Features::MAC);
+        if limits.require_mac && !signed {
+            return Box::new(Err(mac_required()).into_future());
+        }
+        let proof = proof.to_owned();
+        Box::new(self.authenticate(&proof).and_then(move |login| {
+            let seal = login.seal.filter(|_| signed);
+            if seal.is_none() && limits.require_mac {
+                return Either::A(Err(mac_required()).into_future());
+            }
+            Either::B(service.open_remote(login.user, None).map(move |x| (service, seal, x)))
+        }).map(move |(service, seal, (stream, ticket))| {
+            let sessions = service.sessions.clone();
+            let id = generate_session_id();
+            let mut agreement = agreement;
+            if seal.is_none() {
+                agreement.features = agreement.features.without(Features::MAC);
+            }
+            let metrics = service.metrics.clone();
+            let mut session = Session::new(id.clone(), stream, limits, ticket, metrics,
+                &service.handle);
+            info!("created new session: {} for {}", session.id, session.user());
+            service.metrics.session_created();
+            let sealed = seal.map(|seal| {
+                let mut secret = [0u8; 32];
+                random_bytes(&mut secret);
+                session.key = Some(SessionKey::derive(&secret, &id, &proof));
+                seal.seal(&id, &secret)
+            });
+            {
+                let sessions: &mut Vec<Session> = &mut sessions.write().unwrap();
+                sessions.push(session);
+            }
+            let reply = Reply::new(ResponseFrame::ok(id.as_bytes().to_vec()), id.into_bytes());
+            Reply{secret: sealed, ..reply.with_agreement(agreement)}
+        }))
+    }
+
+    /// Pick the remote host for a new session, check that the user may
+    /// connect to it, and connect to the first of its addresses which the
+    /// user is allowed to and which accepts.
+    fn open_remote(
+        &self,
+        user: Option<String>,
+        target: Option<&str>
+    ) -> Box<dyn Future<Item = (TcpStream, Arc<Ticket>), Error = Error>> {
+        if self.drain.is_draining() {
+            return Box::new(Err(Error::Busy("server is shutting down".to_owned())).into_future());
+        }
+        let resolver = self.settings.borrow().resolver.clone();
+        let remote = match resolver(target) {
+            Ok(remote) => remote,
+            Err(e) => return Box::new(Err(e).into_future())
+        };
+        let enforcer = self.enforcer.clone();
+        let (lookup_metrics, metrics) = (self.metrics.clone(), self.metrics.clone());
+        let lookup = remote.resolve().map_err(move |e| {
+            lookup_metrics.connect_failed();
+            Error::Upstream(e.to_string())
+        });
+        Box::new(lookup.and_then(move |addrs| {
+            let (addrs, ticket) = enforcer.admit(user, &remote, addrs)?;
+            Ok((remote, addrs, ticket))
+        }).and_then(|(remote, addrs, ticket)| {
+            connect_any(addrs).then(move |res| {
+                match res {
+                    Ok(stream) => Ok((stream, ticket)),
+                    Err(e) => {
+                        // The host may have moved, so look it up again next time.
+                        remote.forget();
+                        metrics.connect_failed();
+                        Err(Error::Upstream(format!("failed to connect to {}: {}", remote, e)))
+                    }
+                }
+            })
+        }))
+    }
+
+    /// Issue a challenge for a user to answer when logging in with a key or
+    /// a password.
+    ///
+    /// Asking for a nonce counts as a login attempt, so clients can't issue
+    /// nonces faster than they could log in.
+    fn challenge(&self, user: &str) -> ApiFuture {
+        if let Err(e) = self.guard.check(self.peer, Some(user)) {
+            return Box::new(Err(e).into_future());
+        }
+        Box::new(match self.auth().challenge(user) {
+            Some(nonce) => Ok(Reply::new(ResponseFrame::ok(nonce.clone().into_bytes()),
+                nonce.into_bytes())),
+            None => Err(Error::Unsupported("server does not issue challenges".to_owned()))
+        }.into_future())
+    }
+
+    fn auth(&self) -> Auth {
+        self.settings.borrow().auth.clone()
+    }
+
+    fn limits(&self) -> Limits {
+        self.settings.borrow().limits
+    }
+
+    /// Check a client's proof, yielding the name of the user if it gave one.
+    ///
+    /// Clients which try to log in too often are turned away before their
+    /// proof is checked.
+    fn authenticate(&self, proof: &str) -> AuthFuture {
+        let credential = Credential::parse(proof, self.limits().max_proof_age);
+        if let Err(e) = self.guard.check(self.peer, credential.user.as_deref()) {
+            return Box::new(Err(e).into_future());
+        }
+        let (guard, peer, metrics) = (self.guard.clone(), self.peer, self.metrics.clone());
+        let from = peer.map(|x| format!(" from {}", x)).unwrap_or_default();
+        Box::new(self.auth().authenticate(&credential).then(move |res| {
+            match res {
+                Ok(Login{user: Some(ref user), ..}) => {
+                    info!("authenticated user: {}{}", user, from)
+                },
+                Ok(_) => (),
+                Err(ref e) => {
+                    metrics.auth_failed();
+                    match credential.user {
+                        Some(ref user) => info!("failed login for user {}{}: {}", user, from, e),
+                        None => info!("failed login{}: {}", from, e)
+                    }
+                }
+            }
+            guard.record(peer, credential.user.as_deref(), res.is_ok());
+            res
+        }))
+    }
+
+    fn upload(
+        &self,
+        req: Request,
+        id: String,
+        params: QueryParams,
+        signature: Signature
+    ) -> ApiFuture {
+        if params.version >= 2 && params.offset.is_none() {
+            return invalid_request("uploads must have an offset");
+        }
+        let sessions = self.sessions.clone();
+        Box::new(req.body().concat2()
+            .map_err(|e| Error::Transport(format!("read error: {}", e)))
+            .and_then(move |data| {
+                if let Err(e) = signature.check(&sessions, "upload", &id, &data) {
+                    return Box::new(Err(e).into_future()) as Box<dyn Future<Item = _, Error = _>>;
+                }
+                TunnelService::with_session(&sessions, &id, |sess| {
+                    let res = match params.offset {
+                        Some(offset) => sess.write_ordered_chunk(offset, &data),
+                        None => sess.write_chunk(&data)
+                    };
+                    (res, sess.upload_offset(), sess.upload_window())
+                })
+            })
+            .and_then(move |(res, upload_offset, window)| {
+                let frame = |status| {
+                    ResponseFrame::new(status).with_offset(upload_offset).with_window(window)
+                };
+                match res {
+                    NonBlocking::Success(size) => {
+                        Ok(Reply::new(frame(Status::Ok), format!("{}", size).into_bytes()))
+                    },
+                    NonBlocking::Err(err) => Err(upstream_error("write", err)),
+                    // Ordered uploads may simply be retried later.
+                    NonBlocking::WouldBlock if params.offset.is_some() => {
+                        Ok(Reply::new(frame(Status::WouldBlock), b"0".to_vec()))
+                    },
+                    NonBlocking::WouldBlock => Err(Error::Upstream("blocked".to_owned()))
+                }.into_future()
+            }))
+    }
+
+    /// Feed a streaming request body into the session as it arrives.
+    ///
+    /// The response has the offset of the first byte the server is missing.
+    fn upstream(&self, req: Request, id: String, signature: &Signature) -> ApiFuture {
+        let key = match signature.check(&self.sessions, "upstream", &id, &[]) {
+            Ok(key) => key,
+            Err(e) => return Box::new(Err(e).into_future())
+        };
+        let max_frame_size = self.limits().max_chunk_size + MAC_SIZE;
+        Box::new(UploadStream::new(self.sessions.clone(), id, key, req.body(), max_frame_size,
+                &self.handle)
+            .map(|(offset, window)| {
+                let frame = ResponseFrame::ok(Vec::new()).with_offset(offset).with_window(window);
+                Reply::new(frame, format!("{}", offset).into_bytes())
+            }))
+    }
+
+    fn download(&self, id: &str, params: QueryParams, signature: &Signature) -> ApiFuture {
+        if let Err(e) = signature.check(&self.sessions, "download", id, &[]) {
+            return Box::new(Err(e).into_future());
+        }
+        let size = self.chunk_size(params);
+        if let Some(offset) = params.offset {
+            return self.download_ordered(id, offset, size);
+        } else if params.version >= 2 {
+            return invalid_request("downloads must have an offset");
+        }
+        Box::new(TunnelService::with_session(&self.sessions, id, |sess| {
+            sess.read_chunk(size)
+        }).and_then(|res| {
+            match res {
+                NonBlocking::Success(data) => {
+                    Ok(Reply::legacy(if data.is_empty() {
+                        vec![0]
+                    } else {
+                        vec![1].into_iter().chain(data).collect()
+                    }))
+                },
+                NonBlocking::Err(err) => Err(upstream_error("io", err)),
+                NonBlocking::WouldBlock => Ok(Reply::legacy(vec![1])),
+            }.into_future()
+        }))
+    }
+
+    /// Read a chunk for a client that reorders downloads, after acknowledging
+    /// everything before `ack`.
+    ///
+    /// The legacy response is like an unordered download, except that the
+    /// flag byte is followed by the big-endian stream offset of the chunk (or
+    /// of the EOF) whenever there is something to deliver.
+    fn download_ordered(&self, id: &str, ack: u64, size: usize) -> ApiFuture {
+        Box::new(TunnelService::with_session(&self.sessions, id, |sess| {
+            sess.ack(ack);
+            // Anything the client asks for again was lost on the way, so send
+            // it again rather than skipping ahead.
+            let replay = if ack < sess.download_offset() {
+                sess.unacked_from(ack).into_iter().next()
+            } else {
+                None
+            };
+            let res = match replay {
+                Some((offset, mut data)) => {
+                    data.truncate(size);
+                    NonBlocking::Success((offset, data))
+                },
+                None => sess.read_ordered_chunk(size)
+            };
+            (res, sess.download_window())
+        }).and_then(move |(res, window)| {
+            match res {
+                NonBlocking::Success((offset, data)) => {
+                    let flag = if data.is_empty() { 0 } else { 1 };
+                    let mut legacy = vec![flag];
+                    legacy.extend_from_slice(&encode_offset(offset));
+                    legacy.extend_from_slice(&data);
+                    let frame = ResponseFrame{
+                        eof: data.is_empty(),
+                        more_data: data.len() == size,
+                        ..ResponseFrame::ok(data).with_offset(offset).with_window(window)
+                    };
+                    Ok(Reply::new(frame, legacy))
+                },
+                NonBlocking::Err(err) => Err(upstream_error("io", err)),
+                NonBlocking::WouldBlock => {
+                    let frame = ResponseFrame::new(Status::WouldBlock).with_window(window);
+                    Ok(Reply::new(frame, vec![1]))
+                }
+            }.into_future()
+        }))
+    }
+
+    /// Stream downloads in a long-lived chunked response body.
+    ///
+    /// The body consists of StreamFrames, starting with any unacknowledged
+    /// data at or after the requested offset.
+    fn stream(&self, id: String, params: QueryParams) -> Response {
+        let (sender, body) = Body::pair();
+        let frames = DownloadStream::new(self.sessions.clone(), id, params.offset.unwrap_or(0),
+            self.chunk_size(params), &self.handle);
+        self.handle.spawn(sender
+            .sink_map_err(|_| ())
+            .send_all(frames.map(|frame| Ok(Chunk::from(frame))))
+            .map(|_| ()));
+        Response::new()
+            .with_status(StatusCode::Ok)
+            .with_header(ContentType("application/octet-stream".parse().unwrap()))
+            .with_header(MaxChunkSize(self.limits().max_chunk_size))
+            .with_body(body)
+    }
+
+    fn close(&self, id: &str, params: QueryParams, signature: &Signature) -> ApiFuture {
+        if let Err(e) = signature.check(&self.sessions, "close", id, &[]) {
+            return Box::new(Err(e).into_future());
+        }
+        Box::new(TunnelService::with_session(&self.sessions, id, |sess| {
+            info!("sent EOF on session: {}", sess.id);
+            match params.offset {
+                Some(offset) => sess.send_eof_at(offset),
+                None => sess.send_eof()
+            }
+            let frame = ResponseFrame::ok(Vec::new()).with_offset(sess.upload_offset());
+            Reply::new(frame, b"closed stdout".to_vec())
+        }))
+    }
+
+    /// Abort a session, so that the remote host gets a reset rather than an
+    /// EOF. The session is gone right away.
+    fn reset(&self, id: &str, signature: &Signature) -> ApiFuture {
+        if let Err(e) = signature.check(&self.sessions, "reset", id, &[]) {
+            return Box::new(Err(e).into_future());
+        }
+        let sessions: &mut Vec<Session> = &mut self.sessions.write().unwrap();
+        let mut sess = match sessions.iter().position(|x| x.id == id) {
+            Some(i) => sessions.remove(i),
+            None => return Box::new(Err(Error::Session("no session".to_owned())).into_future())
+        };
+        sess.abort();
+        info!("reset session: {}", sess.id);
+        Box::new(Ok(Reply::new(ResponseFrame::ok(Vec::new()), b"reset".to_vec())).into_future())
+    }
+
+    fn chunk_size(&self, params: QueryParams) -> usize {
+        let max_chunk_size = self.limits().max_chunk_size;
+        params.max_size.unwrap_or(max_chunk_size).min(max_chunk_size).max(1)
+    }
+
+    fn with_session<R: 'static, F>(
+        sessions: &RwLock<Vec<Session>>,
+        id: &str,
+        f: F
+    ) -> Box<dyn Future<Item = R, Error = Error>> where F: FnOnce(&mut Session) -> R {
+        let sessions: &mut Vec<Session> = &mut sessions.write().unwrap();
+        for i in 0..sessions.len() {
+            if sessions[i].id == id {
+                // Give buffered uploads a chance to drain on every request.
+                sessions[i].flush_writes().ok();
+                let result = Box::new(Ok(f(&mut sessions[i])).into_future());
+                if sessions[i].is_timed_out() {
+                    info!("removed session: {}", sessions[i].id);
+                    sessions.remove(i);
+                }
+                return result;
+            }
+        }
+        Box::new(Err(Error::Session("no session".to_owned())).into_future())
+    }
+}
+
+impl Service for TunnelService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let info = RequestInfo::from_request(&req);
+        self.metrics.request(info.api());
+        let mut params = QueryParams::from_request(&req);
+        let signature = Signature::from_request(&req);
+        let max_chunk_size = self.limits().max_chunk_size;
+        let mut agreement = None;
+        let result = match info {
+            RequestInfo::Challenge(user) => self.challenge(&user),
+            RequestInfo::Time => time(),
+            RequestInfo::Connect(proof) => {
+                match negotiate_protocol(&req) {
+                    Ok(x) => {
+                        params.version = x.version;
+                        agreement = Some(x);
+                        self.connect(&proof, x)
+                    },
+                    Err(e) => Box::new(Err(e).into_future())
+                }
+            },
+            RequestInfo::Upload(sess_id) => {
+                let size = req.headers().get::<ContentLength>().map(|x| x.0).unwrap_or(0);
+                // Let the client know it should shrink its chunks without
+                // consuming any of the uploaded data.
+                if size > max_chunk_size as u64 && params.version < 2 {
+                    return Box::new(Ok(too_large_response(max_chunk_size)).into_future());
+                } else if size > max_chunk_size as u64 {
+                    let frame = ResponseFrame::error(Status::TooLarge, "chunk too large")
+                        .with_window(max_chunk_size);
+                    Box::new(Ok(Reply::new(frame, Vec::new())).into_future())
+                } else {
+                    self.upload(req, sess_id, params, signature)
+                }
+            },
+            RequestInfo::Upstream(sess_id) => self.upstream(req, sess_id, &signature),
+            RequestInfo::Download(sess_id) => self.download(&sess_id, params, &signature),
+            RequestInfo::Stream(sess_id) => {
+                match signature.check(&self.sessions, "stream", &sess_id, &[]) {
+                    Ok(_) => {
+                        let response = disable_caching(self.stream(sess_id, params));
+                        return Box::new(Ok(response).into_future());
+                    },
+                    Err(e) => Box::new(Err(e).into_future())
+                }
+            },
+            RequestInfo::Close(sess_id) => self.close(&sess_id, params, &signature),
+            RequestInfo::Reset(sess_id) => self.reset(&sess_id, &signature),
+            RequestInfo::Invalid => invalid_request("invalid request")
+        };
+        let version = params.version;
+        Box::new(result.then(move |res| {
+            // A new session may use fewer features than were negotiated.
+            let (agreement, secret) = match res {
+                Ok(ref reply) => (reply.agreement.or(agreement), reply.secret.clone()),
+                Err(_) => (agreement, None)
+            };
+            // Version 1 clients get their legacy bodies, with errors as plain
+            // text in a 400 response.
+            let (status, content_type, body) = match res {
+                Ok(reply) if version >= 2 => (StatusCode::Ok, "application/octet-stream",
+                    reply.frame.encode()),
+                Ok(reply) => (StatusCode::Ok, "application/octet-stream", reply.legacy),
+                Err(e) if version >= 2 => (StatusCode::Ok, "application/octet-stream",
+                    e.to_frame().encode()),
+                Err(e) => (StatusCode::BadRequest, "text/plain", e.to_string().into_bytes())
+            };
+            let mut resp = Response::new()
+                .with_status(status)
+                .with_header(ContentType(content_type.parse().unwrap()))
+                .with_header(MaxChunkSize(max_chunk_size))
+                .with_body(body);
+            if let Some(agreement) = agreement {
+                resp.headers_mut().set(ProtocolHeader(agreement.encode()));
+            }
+            if let Some(secret) = secret {
+                resp.headers_mut().set(SessionSecret(secret));
+            }
+            Ok(disable_caching(resp))
+        }))
+    }
+}
+
+/// The response to an API request which was handled, even if only to turn
+/// it away.
+struct Reply {
+    /// The response for clients using protocol version 2 or later.
+    frame: ResponseFrame,
+
+    /// The body of the response for version 1 clients.
+    legacy: Vec<u8>,
+
+    /// The protocol agreement for a new session, if it differs from the one
+    /// that was negotiated.
+    agreement: Option<Agreement>,
+
+    /// The sealed secret of a new session which has a key.
+    secret: Option<String>
+}
+
+impl Reply {
+    fn new(frame: ResponseFrame, legacy: Vec<u8>) -> Reply {
+        Reply{frame, legacy, agreement: None, secret: None}
+    }
+
+    /// Create a reply for a request which only version 1 clients make.
+    fn legacy(legacy: Vec<u8>) -> Reply {
+        Reply::new(ResponseFrame::new(Status::InvalidRequest), legacy)
+    }
+
+    fn with_agreement(mut self, agreement: Agreement) -> Reply {
+        self.agreement = Some(agreement);
+        self
+    }
+}
+
+/// Tell the client the server's time, in seconds since the Unix epoch, so it
+/// can make proofs which match the server's clock.
+fn time() -> ApiFuture {
+    let time = unix_time().to_string().into_bytes();
+    Box::new(Ok(Reply::new(ResponseFrame::ok(time.clone()), time)).into_future())
+}
+
+fn mac_required() -> Error {
+    Error::Unsupported("server only accepts sessions with MACs".to_owned())
+}
+
+fn invalid_request(msg: &str) -> ApiFuture {
+    Box::new(Err(Error::Protocol(msg.to_owned())).into_future())
+}
+
+/// Work out the protocol for a new session from the client's hello.
+fn negotiate_protocol(req: &Request) -> Result<Agreement, Error> {
+    match req.headers().get::<ProtocolHeader>() {
+        Some(ProtocolHeader(hello)) => Hello::parse(hello)?.negotiate(Features::all()),
+        None => Ok(Agreement::legacy())
+    }
+}
+
+/// Write a bodyless response to a raw connection and close it.
+fn respond_and_close<T: AsyncWrite + 'static>(
+    conn: T,
+    status: &str
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status);
+    Box::new(write_all(conn, response).map(|_| ()).map_err(|_| ()))
+}
+
+/// Reject a raw tunnel handshake and close the connection.
+fn reply_and_close<T: AsyncWrite + 'static>(
+    conn: T,
+    error: Error
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    let reply = HandshakeReply::Rejected(error.status(), error.to_string()).encode();
+    Box::new(write_all(conn, reply).map(|_| ()).map_err(|_| ()))
+}
+
+fn too_large_response(max_chunk_size: usize) -> Response {
+    disable_caching(Response::new()
+        .with_status(StatusCode::PayloadTooLarge)
+        .with_header(ContentType("text/plain".parse().unwrap()))
+        .with_header(MaxChunkSize(max_chunk_size))
+        .with_body("chunk too large".as_bytes().to_vec()))
+}
+
+fn disable_caching(response: Response) -> Response {
+    // https://stackoverflow.com/questions/49547/how-to-control-web-page-caching-across-all-browsers
+    let yesterday = SystemTime::now() - Duration::from_secs(60 * 60 * 24);
+    response
+        .with_header(CacheControl(vec![CacheDirective::NoCache, CacheDirective::NoStore,
+            CacheDirective::MustRevalidate]))
+        .with_header(Pragma::NoCache)
+        .with_header(Expires(yesterday.into()))
+}
+
+type RequestConstructor = Box<dyn Fn(String) -> RequestInfo>;
+
+enum RequestInfo {
+    Challenge(String),
+    Time,
+    Connect(String),
+    Upload(String),
+    Upstream(String),
+    Download(String),
+    Stream(String),
+    Close(String),
+    Reset(String),
+    Invalid
+}
+
+impl RequestInfo {
+    /// Get the name of the API, which requests are counted by.
+    fn api(&self) -> &'static str {
+        match *self {
+            RequestInfo::Challenge(_) => "challenge",
+            RequestInfo::Time => "time",
+            RequestInfo::Connect(_) => "connect",
+            RequestInfo::Upload(_) => "upload",
+            RequestInfo::Upstream(_) => "upstream",
+            RequestInfo::Download(_) => "download",
+            RequestInfo::Stream(_) => "stream",
+            RequestInfo::Close(_) => "close",
+            RequestInfo::Reset(_) => "reset",
+            RequestInfo::Invalid => "invalid"
+        }
+    }
+
+    pub fn from_request<B>(req: &Request<B>) -> RequestInfo {
+        // Requests are of the form "/<api>/<argument>/unused_data_for_caching".
+        let components = req.path().split('/').collect::<Vec<&str>>();
+        if components.len() < 3 || !components[0].is_empty() {
+            return RequestInfo::Invalid;
+        };
+        let prefixes: Vec<(&str, RequestConstructor)> = vec![
+            ("challenge", Box::new(RequestInfo::Challenge)),
+            ("time", Box::new(|_| RequestInfo::Time)),
+            ("connect", Box::new(RequestInfo::Connect)),
+            ("upload", Box::new(RequestInfo::Upload)),
+            ("upstream", Box::new(RequestInfo::Upstream)),
+            ("download", Box::new(RequestInfo::Download)),
+            ("stream", Box::new(RequestInfo::Stream)),
+            ("close", Box::new(RequestInfo::Close)),
+            ("reset", Box::new(RequestInfo::Reset))
+        ];
+        for (prefix, f) in prefixes {
+            if components[1] == prefix {
+                return f(components[2].to_owned());
+            }
+        }
+        RequestInfo::Invalid
+    }
+}
+
+/// Optional arguments passed in the query string.
+#[derive(Clone, Copy)]
+struct QueryParams {
+    /// The largest chunk the client wants to download.
+    max_size: Option<usize>,
+
+    /// The stream offset for ordered uploads, downloads, and EOFs.
+    offset: Option<u64>,
+
+    /// The protocol version the client uses, which is 1 if not given.
+    version: u16
+}
+
+impl QueryParams {
+    pub fn from_request<B>(req: &Request<B>) -> QueryParams {
+        QueryParams{
+            max_size: query_param(req, "max").and_then(|x| x.parse().ok()),
+            offset: query_param(req, "offset").and_then(|x| x.parse().ok()),
+            version: query_param(req, "v").and_then(|x| x.parse().ok()).unwrap_or(1)
+        }
+    }
+}
+
+/// The MAC on a request, and the query string which it covers.
+struct Signature {
+    /// The query string without the MAC.
+    query: String,
+    mac: Option<String>
+}
+
+impl Signature {
+    pub fn from_request<B>(req: &Request<B>) -> Signature {
+        let query = req.query().unwrap_or("").split('&')
+            .filter(|pair| !pair.starts_with("mac="))
+            .collect::<Vec<_>>()
+            .join("&");
+        Signature{query, mac: query_param(req, "mac").map(str::to_owned)}
+    }
+
+    /// Check the MAC for a request to `api` on a session, if the session has
+    /// a key. Yields the session's key.
+    fn check(
+        &self,
+        sessions: &RwLock<Vec<Session>>,
+        api: &str,
+        id: &str,
+        body: &[u8]
+    ) -> Result<Option<SessionKey>, Error> {
+        let key = match sessions.read().unwrap().iter().find(|x| x.id == id) {
+            Some(sess) => sess.key,
+            None => return Ok(None)
+        };
+        match (key, self.mac.as_ref()) {
+            (None, _) => Ok(None),
+            (Some(key), Some(mac)) if key.check_request(api, id, &self.query, body, mac) => {
+                Ok(Some(key))
+            },
+            _ => {
+                info!("rejected {} request with an invalid MAC for session: {}", api, id);
+                Err(Error::Auth("invalid MAC".to_owned()))
+            }
+        }
+    }
+}
+
+/// Find the value of a query string parameter.
+fn query_param<'a, B>(req: &'a Request<B>, name: &str) -> Option<&'a str> {
+    req.query()?.split('&').filter_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(name) {
+            Some(parts.next().unwrap_or(""))
+        } else {
+            None
+        }
+    }).next()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    use hyper::Method;
+    use tokio_core::reactor::{Core, Timeout};
+
+    use mac::OpenKey;
+    use proof::current_proof;
+    use server::ServerBuilder;
+    use server::auth::Authenticator;
+    use stream::{StreamDecoder, StreamFrame};
+    use super::*;
+
+    fn run_request(core: &mut Core, service: &TunnelService, req: Request) -> Vec<u8> {
+        let resp = core.run(service.call(req)).unwrap();
+        assert_eq!(resp.status(), StatusCode::Ok);
+        core.run(resp.body().concat2()).unwrap().to_vec()
+    }
+
+    #[test]
+    fn headerless_client_round_trip() {
+        let remote = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = remote.local_addr().unwrap();
+        let received = thread::spawn(move || {
+            let mut data = Vec::new();
+            remote.accept().unwrap().0.read_to_end(&mut data).unwrap();
+            data
+        });
+
+        let mut core = Core::new().unwrap();
+        let service = ServerBuilder::new().password("secret").remote(addr).build(&core.handle())
+            .unwrap();
+        let uri = format!("/connect/{}/x", current_proof("secret"));
+        let id = run_request(&mut core, &service, Request::new(Method::Get, uri.parse().unwrap()));
+        let id = String::from_utf8(id).unwrap();
+
+        let mut req = Request::new(Method::Post, format!("/upload/{}/x", id).parse().unwrap());
+        req.set_body("hello");
+        assert_eq!(run_request(&mut core, &service, req), b"5");
+        let req = Request::new(Method::Get, format!("/close/{}/x", id).parse().unwrap());
+        assert_eq!(run_request(&mut core, &service, req), b"closed stdout");
+        assert_eq!(received.join().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn stream_resumes_after_ack() {
+        let remote = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = remote.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut conn = remote.accept().unwrap().0;
+            conn.write_all(&[1; 3000]).unwrap();
+            conn.read_to_end(&mut Vec::new()).ok();
+        });
+
+        let mut core = Core::new().unwrap();
+        let limits = Limits{max_chunk_size: 1000, max_unacked_download: 1000,
+            ..Limits::default()};
+        let service = ServerBuilder::new().password("secret").remote(addr).limits(limits)
+            .build(&core.handle()).unwrap();
+        let uri = format!("/connect/{}/x", current_proof("secret"));
+        let id = run_request(&mut core, &service, Request::new(Method::Get, uri.parse().unwrap()));
+        let id = String::from_utf8(id).unwrap();
+
+        let uri = format!("/stream/{}/x?offset=0", id);
+        let body = core.run(service.call(Request::new(Method::Get, uri.parse().unwrap())))
+            .unwrap().body();
+        let mut decoder = StreamDecoder::new(1000);
+        let body = match next_frame(&mut core, body, &mut decoder) {
+            (StreamFrame::Data(0, ref data), body) if data.len() == 1000 => body,
+            (x, _) => panic!("unexpected frame: {:?}", x)
+        };
+
+        // The window is full until the first chunk is acknowledged, so the
+        // stream has to wait for the ack.
+        core.run(Timeout::new(Duration::from_millis(100), &core.handle()).unwrap()).unwrap();
+        let uri = format!("/download/{}/x?offset=1000&max=1", id);
+        run_request(&mut core, &service, Request::new(Method::Get, uri.parse().unwrap()));
+        match next_frame(&mut core, body, &mut decoder) {
+            (StreamFrame::Data(1001, _), _) => (),
+            (x, _) => panic!("unexpected frame: {:?}", x)
+        }
+    }
+
+    /// Read the next frame of a streaming download, well before the stream
+    /// would end on its own.
+    fn next_frame(
+        core: &mut Core,
+        mut body: Body,
+        decoder: &mut StreamDecoder
+    ) -> (StreamFrame, Body) {
+        loop {
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                return (frame, body);
+            }
+            let timeout = Timeout::new(Duration::from_secs(5), &core.handle()).unwrap();
+            let (chunk, rest) = match core.run(body.into_future().select2(timeout)) {
+                Ok(Either::A((x, _))) => x,
+                _ => panic!("no frame in time")
+            };
+            decoder.push(&chunk.unwrap());
+            body = rest;
+        }
+    }
+
+    #[test]
+    fn repeated_downloads_replay_data() {
+        let remote = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = remote.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut conn = remote.accept().unwrap().0;
+            conn.write_all(&(0..3000).map(|x| x as u8).collect::<Vec<u8>>()).unwrap();
+            conn.read_to_end(&mut Vec::new()).ok();
+        });
+
+        let mut core = Core::new().unwrap();
+        let limits = Limits{max_chunk_size: 1000, ..Limits::default()};
+        let service = ServerBuilder::new().password("secret").remote(addr).limits(limits)
+            .build(&core.handle()).unwrap();
+        let uri = format!("/connect/{}/x", current_proof("secret"));
+        let id = run_request(&mut core, &service, Request::new(Method::Get, uri.parse().unwrap()));
+        let id = String::from_utf8(id).unwrap();
+
+        let download = |core: &mut Core, offset: u64| {
+            let uri = format!("/download/{}/x?offset={}", id, offset);
+            loop {
+                let body = run_request(core, &service, Request::new(Method::Get,
+                    uri.parse().unwrap()));
+                if body.len() > 1 {
+                    return body;
+                }
+                core.run(Timeout::new(Duration::from_millis(10), &core.handle()).unwrap())
+                    .unwrap();
+            }
+        };
+        let first = download(&mut core, 0);
+        assert_eq!(first[0], 1);
+        assert_eq!(download(&mut core, 0), first);
+        let received = (first.len() - 9) as u64;
+        assert_eq!(download(&mut core, received)[1..9], encode_offset(received));
+    }
+
+    #[test]
+    fn unlisted_targets_are_forbidden() {
+        let mut core = Core::new().unwrap();
+        let service = ServerBuilder::new().password("secret")
+            .targets(vec!["127.0.0.1:1".parse().unwrap()])
+            .build(&core.handle()).unwrap();
+        match core.run(service.open_remote(None, Some("127.0.0.1:2"))) {
+            Err(Error::Forbidden(_)) => (),
+            _ => panic!("unlisted target allowed")
+        }
+    }
+
+    #[test]
+    fn sessions_get_sealed_secrets() {
+        let remote = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = remote.local_addr().unwrap();
+        let mut core = Core::new().unwrap();
+        let limits = Limits{require_mac: true, ..Limits::default()};
+        let service = ServerBuilder::new().password("secret").remote(addr).limits(limits)
+            .build(&core.handle()).unwrap();
+        let uri = format!("/connect/{}/x", current_proof("secret"));
+
+        // Version 1 clients can't sign their requests.
+        let resp = core.run(service.call(Request::new(Method::Get, uri.parse().unwrap())))
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BadRequest);
+        assert!(resp.headers().get::<SessionSecret>().is_none());
+
+        let mut req = Request::new(Method::Get, uri.parse().unwrap());
+        req.headers_mut().set(ProtocolHeader(Hello::new(Features::all()).encode()));
+        let resp = core.run(service.call(req)).unwrap();
+        let sealed = resp.headers().get::<SessionSecret>().unwrap().0.clone();
+        let body = core.run(resp.body().concat2()).unwrap();
+        let id = String::from_utf8(ResponseFrame::decode(&body).unwrap().payload).unwrap();
+        assert!(OpenKey::Shared(b"secret".to_vec()).open(&id, &sealed).is_some());
+        assert!(OpenKey::Shared(b"wrong".to_vec()).open(&id, &sealed).is_none());
+
+        // Knowing the session ID isn't enough to use the session.
+        let uri = format!("/download/{}/x?v=2&offset=0", id);
+        let body = run_request(&mut core, &service, Request::new(Method::Get,
+            uri.parse().unwrap()));
+        assert_eq!(ResponseFrame::decode(&body).unwrap().status, Status::AuthFailed);
+    }
+
+    #[test]
+    fn challenges_count_as_logins() {
+        let mut core = Core::new().unwrap();
+        let limits = Limits{max_logins: Some(2), ..Limits::default()};
+        let service = ServerBuilder::new().password("secret").limits(limits)
+            .build(&core.handle()).unwrap();
+        for _ in 0..2 {
+            let req = Request::new(Method::Get, "/challenge/alice/x".parse().unwrap());
+            let resp = core.run(service.call(req)).unwrap();
+            assert_eq!(resp.status(), StatusCode::BadRequest);
+        }
+        let uri = format!("/connect/{}/x", current_proof("secret"));
+        let resp = core.run(service.call(Request::new(Method::Get, uri.parse().unwrap())))
+            .unwrap();
+        let body = core.run(resp.body().concat2()).unwrap();
+        assert_eq!(&body[..], b"server is busy");
+    }
+
+    #[test]
+    fn handles_only_api_requests() {
+        let core = Core::new().unwrap();
+        let service = ServerBuilder::new().build(&core.handle()).unwrap();
+        for path in &["/connect/x/y", "/time/now/y", "/download/id/y?offset=0"] {
+            assert!(service.handles(&Request::<Body>::new(Method::Get, path.parse().unwrap())));
+        }
+        for path in &["/", "/index.html", "/connect", "/static/app.js/y", "/ws/proof/y"] {
+            assert!(!service.handles(&Request::<Body>::new(Method::Get, path.parse().unwrap())),
+                "{}", path);
+        }
+    }
+
+    struct AliceAuth;
+
+    impl Authenticator for AliceAuth {
+        fn authenticate(&self, credential: &Credential) -> AuthFuture {
+            let login = match (credential.user.as_deref(), credential.proof.as_str()) {
+                (Some("alice"), "letmein") => {
+                    Ok(Login{user: Some("alice".to_owned()), seal: None})
+                },
+                _ => Err(Error::Auth("who are you?".to_owned()))
+            };
+            Box::new(login.into_future())
+        }
+    }
+
+    #[test]
+    fn custom_auth_and_resolver() {
+        let remote = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = remote.local_addr().unwrap();
+        let mut core = Core::new().unwrap();
+        let lookups = Rc::new(Cell::new(0));
+        let lookups_1 = lookups.clone();
+        let service = ServerBuilder::new().auth(AliceAuth).resolver(move |target| {
+            assert_eq!(target, None);
+            lookups_1.set(lookups_1.get() + 1);
+            Ok(addr)
+        }).build(&core.handle()).unwrap();
+        let connect = |core: &mut Core, proof: &str| {
+            let uri = format!("/connect/{}/x", proof);
+            let resp = core.run(service.call(Request::new(Method::Get, uri.parse().unwrap())))
+                .unwrap();
+            (resp.status(), core.run(resp.body().concat2()).unwrap().to_vec())
+        };
+
+        assert_ne!(connect(&mut core, "bob:letmein").0, StatusCode::Ok);
+        assert_eq!(lookups.get(), 0);
+        let (status, id) = connect(&mut core, "alice:letmein");
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(lookups.get(), 1);
+        remote.accept().unwrap();
+
+        // New settings apply to new sessions only.
+        service.reconfigure(ServerBuilder::new().auth(AliceAuth).resolver(|_| {
+            Err::<SocketAddr, _>(Error::Upstream("no hosts left".to_owned()))
+        })).unwrap();
+        assert_ne!(connect(&mut core, "alice:letmein").0, StatusCode::Ok);
+        assert_eq!(service.open_sessions(), 1);
+        let uri = format!("/close/{}/x", String::from_utf8(id).unwrap());
+        run_request(&mut core, &service, Request::new(Method::Get, uri.parse().unwrap()));
+    }
+
+    #[test]
+    fn chunks_larger_than_upload_buffer() {
+        let core = Core::new().unwrap();
+        let limits = Limits{max_chunk_size: 4096, max_buffered_upload: 1024, ..Limits::default()};
+        let builder = ServerBuilder::new().limits(limits);
+        assert!(builder.build(&core.handle()).is_err());
+        let service = ServerBuilder::new().build(&core.handle()).unwrap();
+        assert!(service.reconfigure(ServerBuilder::new().limits(limits)).is_err());
+        assert_eq!(service.limits().max_chunk_size, Limits::default().max_chunk_size);
+    }
+}
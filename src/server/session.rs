@@ -1,13 +1,25 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::io;
 use std::io::{Read, Write};
-use std::iter::repeat;
-use std::net::{Shutdown, SocketAddr};
+use std::net::Shutdown;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use futures::Future;
+use futures::task;
+use futures::task::Task;
 use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
 
-const CONNECTION_TIMEOUT: u64 = 30;
+use error::{Error, is_reset};
+use mac::SessionKey;
+use server::Limits;
+use server::metrics::Metrics;
+use server::policy::Ticket;
+use server::throttle::Throttled;
+
+/// How long to keep a finished session around, so that requests which were
+/// still in flight can see the EOF (or the reset).
+const FINISHED_LINGER: u64 = 5;
 
 /// The result of a non-blocking operation.
 pub enum NonBlocking<T> {
@@ -16,36 +28,116 @@ pub enum NonBlocking<T> {
     WouldBlock
 }
 
+/// Create the error for a failed read from or write to the remote host.
+///
+/// Errors from the user's policy keep their kind.
+pub fn upstream_error(what: &str, e: io::Error) -> Error {
+    match Error::from(e) {
+        Error::Io(ref e) if is_reset(e) => {
+            Error::Reset("remote host reset the connection".to_owned())
+        },
+        Error::Io(e) => Error::Upstream(format!("{} error: {}", what, e)),
+        e => e
+    }
+}
+
 /// A remote connection for a user.
 pub struct Session {
     pub id: String,
-    pub stream: TcpStream,
+    stream: Throttled<TcpStream>,
 
+    /// The key that requests on the session must be signed with, if any.
+    pub key: Option<SessionKey>,
+
+    limits: Limits,
+    metrics: Arc<Metrics>,
     sent_eof: bool,
     received_eof: bool,
-    last_used: Instant
+
+    /// Set once the connection is reset by either end, after which every
+    /// read and write fails.
+    reset: bool,
+    last_used: Instant,
+
+    // State for ordered (pipelined) transfers, where every chunk is tagged
+    // with the stream offset of its first byte.
+    upload_offset: u64,
+    pending_uploads: BTreeMap<u64, Vec<u8>>,
+    write_buffer: Vec<u8>,
+    eof_offset: Option<u64>,
+    download_offset: u64,
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    unacked_size: usize,
+
+    /// The task to wake once acknowledgements open up the download window.
+    ack_waiter: Option<Task>
 }
 
 impl Session {
-    /// Establish a new session.
-    pub fn connect(
+    /// Start a session on a connection to the remote host.
+    pub fn new(
         id: String,
-        addr: &SocketAddr
-    ) -> Box<Future<Item = Session, Error = io::Error>> {
-        Box::new(TcpStream::connect2(addr).map(|stream| {
-            Session{
-                id: id,
-                stream: stream,
-                sent_eof: false,
-                received_eof: false,
-                last_used: Instant::now()
-            }
-        }))
+        stream: TcpStream,
+        limits: Limits,
+        ticket: Arc<Ticket>,
+        metrics: Arc<Metrics>,
+        handle: &Handle
+    ) -> Session {
+        let remote = handle.remote().clone();
+        Session{
+            id,
+            stream: Throttled::new(stream, ticket, remote),
+            key: None,
+            limits,
+            metrics,
+            sent_eof: false,
+            received_eof: false,
+            reset: false,
+            last_used: Instant::now(),
+            upload_offset: 0,
+            pending_uploads: BTreeMap::new(),
+            write_buffer: Vec::new(),
+            eof_offset: None,
+            download_offset: 0,
+            unacked: VecDeque::new(),
+            unacked_size: 0,
+            ack_waiter: None
+        }
     }
 
-    /// Check if both directions have EOF'd.
+    /// Get the name of the user the session belongs to.
+    pub fn user(&self) -> &str {
+        self.stream.ticket().user()
+    }
+
+    /// Check if both directions have EOF'd, or the connection was reset.
     pub fn is_done(&self) -> bool {
-        self.sent_eof && self.received_eof
+        self.reset || (self.sent_eof && self.received_eof)
+    }
+
+    /// Check if the connection was reset by either end.
+    pub fn is_reset(&self) -> bool {
+        self.reset
+    }
+
+    /// Abort the connection to the remote host, which gets a reset rather
+    /// than an EOF once the session is dropped.
+    pub fn abort(&mut self) {
+        self.stream.get_ref().set_linger(Some(Duration::from_secs(0))).ok();
+        self.reset = true;
+    }
+
+    /// Note an error from the remote host, remembering whether it was a reset,
+    /// since a reset socket reads as EOF afterwards.
+    fn check_error(&mut self, e: io::Error) -> io::Error {
+        if is_reset(&e) {
+            self.reset = true;
+        }
+        e
+    }
+
+    fn reset_error() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, "connection reset")
     }
 
     /// Read a chunk of data from the session.
@@ -53,19 +145,23 @@ impl Session {
     /// Yields an empty chunk on EOF.
     pub fn read_chunk(&mut self, max_size: usize) -> NonBlocking<Vec<u8>> {
         self.last_used = Instant::now();
-        let mut buffer: Vec<u8> = repeat(0).take(max_size).collect();
+        if self.reset {
+            return NonBlocking::Err(Session::reset_error());
+        }
+        let mut buffer = vec![0u8; max_size];
         match self.stream.read(&mut buffer) {
             Ok(size) => {
                 if size == 0 {
                     self.received_eof = true;
                 }
+                self.metrics.downloaded(size);
                 NonBlocking::Success(buffer[..size].to_vec())
             },
             Err(e) => {
                 if e.kind() == io::ErrorKind::WouldBlock {
                     NonBlocking::WouldBlock
                 } else {
-                    NonBlocking::Err(e)
+                    NonBlocking::Err(self.check_error(e))
                 }
             }
         }
@@ -77,25 +173,316 @@ impl Session {
     /// If 0 bytes were written, it likely indicates an error.
     pub fn write_chunk(&mut self, chunk: &[u8]) -> NonBlocking<usize> {
         self.last_used = Instant::now();
+        if self.reset {
+            return NonBlocking::Err(Session::reset_error());
+        }
         match self.stream.write(chunk) {
-            Ok(size) => NonBlocking::Success(size),
+            Ok(size) => {
+                self.metrics.uploaded(size);
+                NonBlocking::Success(size)
+            },
             Err(e) => {
                 if e.kind() == io::ErrorKind::WouldBlock {
                     NonBlocking::WouldBlock
                 } else {
-                    NonBlocking::Err(e)
+                    NonBlocking::Err(self.check_error(e))
+                }
+            }
+        }
+    }
+
+    /// Read a chunk of data and tag it with its offset in the stream.
+    ///
+    /// Like read_chunk(), an empty chunk indicates EOF.
+    ///
+    /// The chunk is kept until it is acknowledged with ack(), and no more data
+    /// is read while too much of it is unacknowledged.
+    pub fn read_ordered_chunk(&mut self, max_size: usize) -> NonBlocking<(u64, Vec<u8>)> {
+        if self.unacked_size >= self.limits.max_unacked_download {
+            return NonBlocking::WouldBlock;
+        }
+        match self.read_chunk(max_size) {
+            NonBlocking::Success(data) => {
+                let offset = self.download_offset;
+                self.download_offset += data.len() as u64;
+                if !data.is_empty() {
+                    self.unacked_size += data.len();
+                    self.unacked.push_back((offset, data.clone()));
+                }
+                NonBlocking::Success((offset, data))
+            },
+            NonBlocking::Err(e) => NonBlocking::Err(e),
+            NonBlocking::WouldBlock => NonBlocking::WouldBlock
+        }
+    }
+
+    /// Acknowledge that the client has received everything before the given
+    /// stream offset.
+    pub fn ack(&mut self, offset: u64) {
+        self.last_used = Instant::now();
+        loop {
+            let size = match self.unacked.front() {
+                Some(&(start, ref data)) if start + data.len() as u64 <= offset => data.len(),
+                _ => break
+            };
+            self.unacked.pop_front();
+            self.unacked_size -= size;
+        }
+        if self.download_window() > 0 {
+            if let Some(task) = self.ack_waiter.take() {
+                task.notify();
+            }
+        }
+    }
+
+    /// Wake the current task once the download window opens up, if it is
+    /// full now. Only the last task to ask is woken.
+    pub fn notify_on_ack(&mut self) {
+        if self.download_window() == 0 {
+            self.ack_waiter = Some(task::current());
+        }
+    }
+
+    /// Get the stream offset of the next byte to be read.
+    pub fn download_offset(&self) -> u64 {
+        self.download_offset
+    }
+
+    /// Get the chunks which were read at or after the given offset.
+    pub fn unacked_from(&self, offset: u64) -> Vec<(u64, Vec<u8>)> {
+        self.unacked.iter().filter_map(|&(start, ref data)| {
+            let end = start + data.len() as u64;
+            if end <= offset {
+                None
+            } else if start >= offset {
+                Some((start, data.clone()))
+            } else {
+                Some((offset, data[(offset - start) as usize..].to_vec()))
+            }
+        }).collect()
+    }
+
+    /// Accept a chunk of data that starts at the given stream offset.
+    ///
+    /// Chunks may arrive out of order or more than once; they are buffered
+    /// until they can be written in order. Either the entire chunk is
+    /// accepted, or WouldBlock is returned because too much data is buffered.
+    pub fn write_ordered_chunk(&mut self, offset: u64, chunk: &[u8]) -> NonBlocking<usize> {
+        self.last_used = Instant::now();
+        let end = offset + chunk.len() as u64;
+        if end > self.upload_offset && !self.pending_uploads.contains_key(&offset) {
+            if self.buffered_upload_size() + chunk.len() > self.limits.max_buffered_upload {
+                return match self.flush_writes() {
+                    Ok(_) => NonBlocking::WouldBlock,
+                    Err(e) => NonBlocking::Err(e)
+                };
+            }
+            self.pending_uploads.insert(offset, chunk.to_vec());
+            self.reassemble_uploads();
+        }
+        match self.flush_writes() {
+            Ok(_) => NonBlocking::Success(chunk.len()),
+            Err(e) => NonBlocking::Err(e)
+        }
+    }
+
+    /// Write as much buffered upload data as the remote end will take, and
+    /// send a pending EOF once everything before it has been written.
+    pub fn flush_writes(&mut self) -> io::Result<()> {
+        if self.reset {
+            return Err(Session::reset_error());
+        }
+        while !self.write_buffer.is_empty() {
+            match self.stream.write(&self.write_buffer) {
+                Ok(size) => {
+                    self.write_buffer.drain(..size);
+                    self.metrics.uploaded(size);
+                },
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        return Ok(());
+                    }
+                    return Err(self.check_error(e));
                 }
             }
         }
+        if !self.sent_eof && self.eof_offset == Some(self.upload_offset) {
+            self.send_eof();
+        }
+        Ok(())
     }
 
     pub fn send_eof(&mut self) {
         self.last_used = Instant::now();
         self.sent_eof = true;
-        self.stream.shutdown(Shutdown::Write).ok();
+        self.stream.get_ref().shutdown(Shutdown::Write).ok();
+    }
+
+    /// Get the offset of the first upload byte that has not been received.
+    pub fn upload_offset(&self) -> u64 {
+        self.upload_offset
+    }
+
+    /// Send an EOF once all of the data before the given offset is written.
+    pub fn send_eof_at(&mut self, offset: u64) {
+        self.last_used = Instant::now();
+        self.eof_offset = Some(offset);
+        self.flush_writes().ok();
+    }
+
+    /// Get how much more upload data the session can buffer.
+    pub fn upload_window(&self) -> usize {
+        self.limits.max_buffered_upload.saturating_sub(self.buffered_upload_size())
+    }
+
+    /// Get how much more download data can be read before some of it must be
+    /// acknowledged.
+    pub fn download_window(&self) -> usize {
+        self.limits.max_unacked_download.saturating_sub(self.unacked_size)
+    }
+
+    fn buffered_upload_size(&self) -> usize {
+        self.write_buffer.len() + self.pending_uploads.values().map(|x| x.len()).sum::<usize>()
+    }
+
+    fn reassemble_uploads(&mut self) {
+        loop {
+            let offset = match self.pending_uploads.keys().next() {
+                Some(&offset) if offset <= self.upload_offset => offset,
+                _ => return
+            };
+            let chunk = self.pending_uploads.remove(&offset).unwrap();
+            let end = offset + chunk.len() as u64;
+            if end > self.upload_offset {
+                let skip = (self.upload_offset - offset) as usize;
+                self.write_buffer.extend_from_slice(&chunk[skip..]);
+                self.upload_offset = end;
+            }
+        }
     }
 
     pub fn is_timed_out(&self) -> bool {
-        self.last_used.elapsed() > Duration::from_secs(CONNECTION_TIMEOUT)
+        self.last_used.elapsed() > self.limits.session_timeout
+    }
+
+    /// Check if the session is done and no more requests are expected.
+    pub fn is_finished(&self) -> bool {
+        self.is_done() && self.last_used.elapsed() > Duration::from_secs(FINISHED_LINGER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net;
+
+    use futures::future::lazy;
+    use tokio_core::reactor::Core;
+
+    use server::policy::{Enforcer, Policies};
+    use super::*;
+
+    /// Start a session, along with the remote end of its connection.
+    fn session(core: &Core, limits: Limits) -> (Session, net::TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let remote = listener.accept().unwrap().0;
+        remote.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let enforcer = Enforcer::new(Policies::new(), None);
+        let addr = conn.peer_addr().unwrap();
+        let (_, ticket) = enforcer.admit(None, &addr.into(), vec![addr]).unwrap();
+        let stream = TcpStream::from_stream(conn, &core.handle()).unwrap();
+        let session = Session::new("id".to_owned(), stream, limits, ticket,
+            Arc::new(Metrics::default()), &core.handle());
+        (session, remote)
+    }
+
+    /// Write a chunk from within a task, as the service does.
+    fn write(core: &mut Core, session: &mut Session, offset: u64, chunk: &[u8]) -> Option<usize> {
+        core.run(lazy(|| {
+            match session.write_ordered_chunk(offset, chunk) {
+                NonBlocking::Success(size) => Ok(Some(size)),
+                NonBlocking::WouldBlock => Ok(None),
+                NonBlocking::Err(e) => Err(e)
+            }
+        })).unwrap()
+    }
+
+    /// Wait until everything buffered has been written to the remote host.
+    fn flush(core: &mut Core, session: &mut Session) {
+        while !session.write_buffer.is_empty() {
+            core.turn(Some(Duration::from_millis(10)));
+            core.run(lazy(|| session.flush_writes())).unwrap();
+        }
+    }
+
+    fn read(remote: &mut net::TcpStream, size: usize) -> Vec<u8> {
+        let mut data = vec![0; size];
+        remote.read_exact(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn out_of_order_uploads() {
+        let mut core = Core::new().unwrap();
+        let (mut session, mut remote) = session(&core, Limits::default());
+        assert_eq!(write(&mut core, &mut session, 5, b" world"), Some(6));
+        assert_eq!(session.upload_offset(), 0);
+        // Repeats of a chunk which is still waiting are ignored.
+        assert_eq!(write(&mut core, &mut session, 5, b" WORLD"), Some(6));
+        assert_eq!(write(&mut core, &mut session, 0, b"hello"), Some(5));
+        assert_eq!(session.upload_offset(), 11);
+        flush(&mut core, &mut session);
+        assert_eq!(read(&mut remote, 11), b"hello world");
+    }
+
+    #[test]
+    fn duplicate_and_overlapping_uploads() {
+        let mut core = Core::new().unwrap();
+        let (mut session, mut remote) = session(&core, Limits::default());
+        assert_eq!(write(&mut core, &mut session, 0, b"hello"), Some(5));
+        assert_eq!(write(&mut core, &mut session, 0, b"hello"), Some(5));
+        assert_eq!(write(&mut core, &mut session, 2, b"llo"), Some(3));
+        assert_eq!(session.upload_offset(), 5);
+        // Only the part after what was already received is used.
+        assert_eq!(write(&mut core, &mut session, 3, b"LO world"), Some(8));
+        assert_eq!(session.upload_offset(), 11);
+        flush(&mut core, &mut session);
+        assert_eq!(read(&mut remote, 11), b"hello world");
+    }
+
+    #[test]
+    fn uploads_over_the_limit() {
+        let mut core = Core::new().unwrap();
+        let limits = Limits{max_buffered_upload: 10, ..Limits::default()};
+        let (mut session, mut remote) = session(&core, limits);
+        assert_eq!(write(&mut core, &mut session, 4, b"abcdef"), Some(6));
+        assert_eq!(session.upload_window(), 4);
+        assert_eq!(write(&mut core, &mut session, 20, b"xxxxx"), None);
+        assert_eq!(session.upload_window(), 4);
+        assert_eq!(write(&mut core, &mut session, 0, b"0123"), Some(4));
+        // Data stops counting against the limit once it has been written.
+        flush(&mut core, &mut session);
+        assert_eq!(session.upload_window(), 10);
+        assert_eq!(read(&mut remote, 10), b"0123abcdef");
+    }
+
+    #[test]
+    fn eof_before_earlier_data() {
+        let mut core = Core::new().unwrap();
+        let (mut session, mut remote) = session(&core, Limits::default());
+        core.run(lazy(|| {
+            session.send_eof_at(5);
+            Ok::<(), ()>(())
+        })).unwrap();
+        assert!(!session.sent_eof);
+        assert_eq!(write(&mut core, &mut session, 2, b"llo"), Some(3));
+        flush(&mut core, &mut session);
+        assert!(!session.sent_eof);
+        assert_eq!(write(&mut core, &mut session, 0, b"he"), Some(2));
+        flush(&mut core, &mut session);
+        assert!(session.sent_eof);
+        let mut data = Vec::new();
+        remote.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello");
     }
 }
@@ -0,0 +1,253 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::{Async, Future, Poll, Stream};
+use hyper::Body;
+use tokio_core::reactor::{Handle, Timeout};
+
+use error::Error;
+use mac::SessionKey;
+use server::session::{NonBlocking, Session, upstream_error};
+use stream::{StreamDecoder, StreamFrame};
+
+/// How long one streaming response may last.
+///
+/// Ending responses periodically makes the client reconnect, which
+/// acknowledges the data it received and keeps the session alive.
+const STREAM_DURATION: u64 = 15;
+
+/// The most data to send in one streaming response.
+const STREAM_MAX_BYTES: usize = 1 << 20;
+
+/// How long to wait before retrying an upload that the session can't buffer.
+const UPLOAD_RETRY_MILLIS: u64 = 50;
+
+/// A stream of encoded StreamFrames carrying a session's downloads.
+pub struct DownloadStream {
+    sessions: Arc<RwLock<Vec<Session>>>,
+    id: String,
+    max_chunk_size: usize,
+    replay: VecDeque<StreamFrame>,
+    deadline: Timeout,
+    sent_size: usize,
+    done: bool
+}
+
+impl DownloadStream {
+    /// Start streaming the data at and after the given offset.
+    ///
+    /// Everything before the offset is treated as acknowledged, and anything
+    /// after it which was already read is sent again.
+    pub fn new(
+        sessions: Arc<RwLock<Vec<Session>>>,
+        id: String,
+        offset: u64,
+        max_chunk_size: usize,
+        handle: &Handle
+    ) -> DownloadStream {
+        let replay = {
+            let sessions: &mut Vec<Session> = &mut sessions.write().unwrap();
+            match sessions.iter_mut().find(|s| s.id == id) {
+                Some(sess) => {
+                    sess.ack(offset);
+                    sess.unacked_from(offset).into_iter()
+                        .map(|(start, data)| StreamFrame::Data(start, data))
+                        .collect()
+                },
+                None => vec![StreamFrame::Error("no session".to_owned())].into_iter().collect()
+            }
+        };
+        DownloadStream{
+            sessions,
+            id,
+            max_chunk_size,
+            replay,
+            deadline: Timeout::new(Duration::from_secs(STREAM_DURATION), handle).unwrap(),
+            sent_size: 0,
+            done: false
+        }
+    }
+
+    fn next_frame(&mut self) -> Option<StreamFrame> {
+        if let Some(frame) = self.replay.pop_front() {
+            return Some(frame);
+        }
+        let sessions: &mut Vec<Session> = &mut self.sessions.write().unwrap();
+        let sess = match sessions.iter_mut().find(|s| s.id == self.id) {
+            Some(sess) => sess,
+            None => return Some(StreamFrame::Error("no session".to_owned()))
+        };
+        sess.flush_writes().ok();
+        match sess.read_ordered_chunk(self.max_chunk_size) {
+            NonBlocking::Success((offset, data)) => {
+                Some(if data.is_empty() {
+                    StreamFrame::Eof(offset)
+                } else {
+                    StreamFrame::Data(offset, data)
+                })
+            },
+            NonBlocking::Err(_) if sess.is_reset() => Some(StreamFrame::Reset),
+            NonBlocking::Err(err) => Some(StreamFrame::Error(format!("io error: {}", err))),
+            // Reads only wake us up once data arrives, so make sure a full
+            // window doesn't leave us waiting forever.
+            NonBlocking::WouldBlock => {
+                sess.notify_on_ack();
+                None
+            }
+        }
+    }
+}
+
+impl Stream for DownloadStream {
+    type Item = Vec<u8>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.done || self.sent_size >= STREAM_MAX_BYTES {
+            return Ok(Async::Ready(None));
+        }
+        if let Ok(Async::Ready(_)) = self.deadline.poll() {
+            return Ok(Async::Ready(None));
+        }
+        match self.next_frame() {
+            Some(frame) => {
+                match frame {
+                    StreamFrame::Data(_, ref data) => self.sent_size += data.len(),
+                    _ => self.done = true
+                }
+                Ok(Async::Ready(Some(frame.encode())))
+            },
+            None => Ok(Async::NotReady)
+        }
+    }
+}
+
+/// A future which feeds the StreamFrames in a streaming request body into a
+/// session as they arrive.
+///
+/// The future yields the offset of the first byte that has not been received
+/// yet, which tells the client what it can stop holding on to, along with the
+/// session's upload window. If the body is cut off, everything which arrived
+/// before that is still used.
+///
+/// On sessions with a key, every data and EOF frame must follow a MAC frame
+/// which signs it.
+pub struct UploadStream {
+    sessions: Arc<RwLock<Vec<Session>>>,
+    id: String,
+    key: Option<SessionKey>,
+    mac: Option<Vec<u8>>,
+    body: Body,
+    body_done: bool,
+    decoder: StreamDecoder,
+    pending: Option<(u64, Vec<u8>)>,
+    retry: Option<Timeout>,
+    handle: Handle
+}
+
+impl UploadStream {
+    pub fn new(
+        sessions: Arc<RwLock<Vec<Session>>>,
+        id: String,
+        key: Option<SessionKey>,
+        body: Body,
+        max_frame_size: usize,
+        handle: &Handle
+    ) -> UploadStream {
+        UploadStream{
+            sessions,
+            id,
+            key,
+            mac: None,
+            body,
+            body_done: false,
+            decoder: StreamDecoder::new(max_frame_size),
+            pending: None,
+            retry: None,
+            handle: handle.clone()
+        }
+    }
+
+    fn with_session<F, R>(&self, f: F) -> Result<R, Error>
+        where F: FnOnce(&mut Session) -> R
+    {
+        let sessions: &mut Vec<Session> = &mut self.sessions.write().unwrap();
+        match sessions.iter_mut().find(|s| s.id == self.id) {
+            Some(sess) => Ok(f(sess)),
+            None => Err(Error::Session("no session".to_owned()))
+        }
+    }
+
+    /// Check the MAC of a frame on a session with a key.
+    fn check_mac(&mut self, frame: &StreamFrame) -> Result<(), Error> {
+        let key = match self.key {
+            Some(ref key) => key,
+            None => return Ok(())
+        };
+        match self.mac.take() {
+            Some(ref mac) if key.check_frame(&self.id, &frame.encode(), mac) => Ok(()),
+            _ => Err(Error::Auth("invalid MAC".to_owned()))
+        }
+    }
+}
+
+impl Future for UploadStream {
+    type Item = (u64, usize);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(mut retry) = self.retry.take() {
+                if let Ok(Async::NotReady) = retry.poll() {
+                    self.retry = Some(retry);
+                    return Ok(Async::NotReady);
+                }
+            }
+            if let Some((offset, data)) = self.pending.take() {
+                match self.with_session(|sess| sess.write_ordered_chunk(offset, &data))? {
+                    NonBlocking::Success(_) => (),
+                    NonBlocking::Err(e) => return Err(upstream_error("write", e)),
+                    NonBlocking::WouldBlock => {
+                        self.pending = Some((offset, data));
+                        let delay = Duration::from_millis(UPLOAD_RETRY_MILLIS);
+                        self.retry = Some(Timeout::new(delay, &self.handle).unwrap());
+                    }
+                }
+                continue;
+            }
+            let frame = self.decoder.next_frame()?;
+            if let Some(ref frame @ StreamFrame::Data(..)) = frame {
+                self.check_mac(frame)?;
+            } else if let Some(ref frame @ StreamFrame::Eof(_)) = frame {
+                self.check_mac(frame)?;
+            }
+            match frame {
+                Some(StreamFrame::Data(offset, data)) => self.pending = Some((offset, data)),
+                Some(StreamFrame::Eof(offset)) => {
+                    self.with_session(|sess| sess.send_eof_at(offset))?;
+                },
+                Some(StreamFrame::Error(msg)) => {
+                    return Err(Error::Protocol(format!("client error: {}", msg)));
+                },
+                Some(StreamFrame::Mac(mac)) => self.mac = Some(mac),
+                // Clients reset sessions with the reset API instead.
+                Some(StreamFrame::Reset) => {
+                    return Err(Error::Protocol("unexpected reset frame".to_owned()));
+                },
+                None => {
+                    if self.body_done {
+                        return self.with_session(|sess| {
+                            (sess.upload_offset(), sess.upload_window())
+                        }).map(Async::Ready);
+                    }
+                    match self.body.poll() {
+                        Ok(Async::Ready(Some(chunk))) => self.decoder.push(&chunk),
+                        Ok(Async::Ready(None)) | Err(_) => self.body_done = true,
+                        Ok(Async::NotReady) => return Ok(Async::NotReady)
+                    }
+                }
+            }
+        }
+    }
+}
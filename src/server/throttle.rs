@@ -0,0 +1,146 @@
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use futures::{Async, Future, Poll};
+use tokio_core::reactor::{Remote, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use server::policy::{Limited, Ticket};
+
+/// A connection whose traffic counts against a user's policy.
+///
+/// When the user runs out of bandwidth, reads and writes block until the
+/// current task is woken up to try again.
+pub struct Throttled<T> {
+    inner: T,
+    ticket: Arc<Ticket>,
+    remote: Remote,
+    delay: Option<Timeout>
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, ticket: Arc<Ticket>, remote: Remote) -> Throttled<T> {
+        Throttled{inner, ticket, remote, delay: None}
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn ticket(&self) -> &Ticket {
+        &self.ticket
+    }
+
+    /// Get the number of bytes which may be transferred right now.
+    fn allowance(&mut self, size: usize) -> io::Result<usize> {
+        if size == 0 {
+            return Ok(0);
+        }
+        loop {
+            if let Some(ref mut delay) = self.delay {
+                if let Async::NotReady = delay.poll()? {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+            }
+            self.delay = None;
+            match self.ticket.take(size) {
+                Ok(allowed) => return Ok(allowed),
+                Err(Limited::Wait(duration)) => {
+                    // Polling the timeout makes it wake up the current task.
+                    let handle = match self.remote.handle() {
+                        Some(handle) => handle,
+                        None => return Err(io::ErrorKind::WouldBlock.into())
+                    };
+                    self.delay = Some(Timeout::new(duration, &handle)?);
+                },
+                Err(Limited::Denied(e)) => return Err(e.into())
+            }
+        }
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let allowed = self.allowance(buf.len())?;
+        let res = self.inner.read(&mut buf[..allowed]);
+        self.ticket.refund(allowed - *res.as_ref().unwrap_or(&0));
+        res
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let allowed = self.allowance(buf.len())?;
+        let res = self.inner.write(&buf[..allowed]);
+        self.ticket.refund(allowed - *res.as_ref().unwrap_or(&0));
+        res
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Throttled<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Throttled<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::{Duration, Instant};
+
+    use futures::future::poll_fn;
+    use tokio_core::reactor::Core;
+
+    use server::policy::{Enforcer, Policies, Policy};
+    use super::*;
+
+    fn ticket(max_rate: u64) -> Arc<Ticket> {
+        let mut policies = Policies::new();
+        policies.set_default(Policy{max_rate: Some(max_rate), ..Policy::default()});
+        let enforcer = Enforcer::new(policies, None);
+        let remote = "127.0.0.1:22".parse().unwrap();
+        enforcer.admit(None, &remote, vec!["127.0.0.1:22".parse().unwrap()]).unwrap().1
+    }
+
+    #[test]
+    fn writes_wait_for_bandwidth() {
+        let mut core = Core::new().unwrap();
+        let mut writer = Throttled::new(Vec::new(), ticket(1000), core.remote());
+        let data = vec![1u8; 1500];
+        let mut written = 0;
+        let start = Instant::now();
+        core.run(poll_fn(|| {
+            while written < data.len() {
+                match writer.write(&data[written..]) {
+                    Ok(size) => written += size,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady);
+                    },
+                    Err(e) => return Err(e)
+                }
+            }
+            Ok(Async::Ready(()))
+        })).unwrap();
+        // The first second's worth goes through right away, and the rest has
+        // to wait for the bucket to refill.
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(writer.get_ref(), &data);
+    }
+
+    #[test]
+    fn unused_bandwidth_is_refunded() {
+        let core = Core::new().unwrap();
+        let mut reader = Throttled::new(Cursor::new(vec![1u8; 10]), ticket(1000), core.remote());
+        let mut buf = vec![0u8; 1000];
+        assert_eq!(reader.read(&mut buf).unwrap(), 10);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(reader.ticket().take(1000).ok(), Some(990));
+    }
+}
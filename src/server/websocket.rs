@@ -0,0 +1,204 @@
+use futures::{Async, Future, Poll};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use error::Error;
+use server::session::{NonBlocking, Session, upstream_error};
+use stream::{StreamDecoder, StreamFrame};
+use websocket::{MAX_MESSAGE_SIZE, Message, MessageDecoder};
+
+/// The most outgoing data to queue up before we stop reading from the session.
+const MAX_OUTGOING: usize = 1 << 18;
+
+/// A future which relays data between a session and a WebSocket connection.
+///
+/// Each binary message carries a single StreamFrame, just like the body of a
+/// streaming upload or download.
+pub struct WebSocketTunnel<T: AsyncRead + AsyncWrite> {
+    conn: T,
+    session: Session,
+    max_chunk_size: usize,
+    messages: MessageDecoder,
+    outgoing: Vec<u8>,
+    pending_upload: Option<(u64, Vec<u8>)>,
+    sent_eof: bool,
+    closing: bool,
+
+    /// Resolves when the server wants the session closed.
+    closed: Box<dyn Future<Item = (), Error = ()>>
+}
+
+impl<T: AsyncRead + AsyncWrite> WebSocketTunnel<T> {
+    /// Create a tunnel on a connection which has already been upgraded.
+    pub fn new(
+        conn: T,
+        session: Session,
+        max_chunk_size: usize,
+        closed: Box<dyn Future<Item = (), Error = ()>>
+    ) -> WebSocketTunnel<T> {
+        WebSocketTunnel{
+            conn,
+            session,
+            max_chunk_size,
+            messages: MessageDecoder::new(),
+            outgoing: Vec::new(),
+            pending_upload: None,
+            sent_eof: false,
+            closing: false,
+            closed
+        }
+    }
+
+    fn send(&mut self, message: Message) {
+        self.outgoing.extend(message.encode(false));
+    }
+
+    /// Write queued messages to the connection.
+    fn flush_outgoing(&mut self) -> Result<bool, Error> {
+        let mut progress = false;
+        while !self.outgoing.is_empty() {
+            match self.conn.poll_write(&self.outgoing)? {
+                Async::Ready(size) => {
+                    self.outgoing.drain(..size);
+                    progress = true;
+                },
+                Async::NotReady => break
+            }
+        }
+        Ok(progress)
+    }
+
+    /// Pass data from the client to the session.
+    fn upload(&mut self) -> Result<bool, Error> {
+        self.session.flush_writes().map_err(|e| upstream_error("write", e))?;
+        if let Some((offset, data)) = self.pending_upload.take() {
+            match self.session.write_ordered_chunk(offset, &data) {
+                NonBlocking::Success(_) => (),
+                NonBlocking::Err(e) => return Err(upstream_error("write", e)),
+                NonBlocking::WouldBlock => {
+                    self.pending_upload = Some((offset, data));
+                    return Ok(false);
+                }
+            }
+        }
+        let mut progress = false;
+        loop {
+            match self.messages.next_message()? {
+                Some(Message::Binary(data)) => {
+                    // Each message holds one frame, so the message size
+                    // limit already applies.
+                    let mut frames = StreamDecoder::new(MAX_MESSAGE_SIZE);
+                    frames.push(&data);
+                    match frames.next_frame()? {
+                        Some(StreamFrame::Data(offset, data)) => {
+                            self.pending_upload = Some((offset, data));
+                            return Ok(true);
+                        },
+                        Some(StreamFrame::Eof(offset)) => self.session.send_eof_at(offset),
+                        Some(StreamFrame::Error(msg)) => {
+                            return Err(Error::Protocol(format!("client error: {}", msg)));
+                        },
+                        Some(StreamFrame::Mac(_)) => {
+                            return Err(Error::Protocol("unexpected MAC frame".to_owned()));
+                        },
+                        Some(StreamFrame::Reset) => {
+                            self.session.abort();
+                            self.send(Message::Close);
+                            self.closing = true;
+                            return Ok(true);
+                        },
+                        None => return Err(Error::Protocol("truncated frame".to_owned()))
+                    }
+                },
+                Some(Message::Ping(data)) => self.send(Message::Pong(data)),
+                Some(Message::Close) => {
+                    self.closing = true;
+                    return Ok(true);
+                },
+                Some(_) => (),
+                None => break
+            }
+            progress = true;
+        }
+        let mut buf = [0u8; 16384];
+        match self.conn.poll_read(&mut buf)? {
+            Async::Ready(0) => {
+                self.closing = true;
+                Ok(true)
+            },
+            Async::Ready(size) => {
+                self.messages.push(&buf[..size]);
+                Ok(true)
+            },
+            Async::NotReady => Ok(progress)
+        }
+    }
+
+    /// Pass data from the session to the client.
+    fn download(&mut self) -> Result<bool, Error> {
+        if self.sent_eof || self.outgoing.len() >= MAX_OUTGOING {
+            return Ok(false);
+        }
+        match self.session.read_ordered_chunk(self.max_chunk_size) {
+            NonBlocking::Success((offset, data)) => {
+                // The connection is reliable, so nothing needs to be resent.
+                self.session.ack(offset + data.len() as u64);
+                let frame = if data.is_empty() {
+                    self.sent_eof = true;
+                    StreamFrame::Eof(offset)
+                } else {
+                    StreamFrame::Data(offset, data)
+                };
+                self.send(Message::Binary(frame.encode()));
+                Ok(true)
+            },
+            NonBlocking::Err(e) => Err(upstream_error("io", e)),
+            NonBlocking::WouldBlock => Ok(false)
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Future for WebSocketTunnel<T> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        loop {
+            let mut progress = self.flush_outgoing()?;
+            if !self.closing && self.closed.poll() != Ok(Async::NotReady) {
+                // Pass along what the client already sent before the EOF.
+                self.session.flush_writes().ok();
+                self.session.send_eof();
+                self.send(Message::Close);
+                self.closing = true;
+                continue;
+            }
+            if self.closing {
+                if self.outgoing.is_empty() {
+                    return Ok(Async::Ready(()));
+                } else if !progress {
+                    return Ok(Async::NotReady);
+                }
+                continue;
+            }
+            if self.session.is_done() && self.sent_eof {
+                self.send(Message::Close);
+                self.closing = true;
+                continue;
+            }
+            match self.upload().and_then(|x| Ok(x | self.download()?)) {
+                Ok(x) => progress |= x,
+                // Pass a reset on to the client rather than failing.
+                Err(Error::Reset(_)) => {
+                    self.send(Message::Binary(StreamFrame::Reset.encode()));
+                    self.send(Message::Close);
+                    self.closing = true;
+                    continue;
+                },
+                Err(e) => return Err(e)
+            }
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use error::Error;
+
+/// A frame in a streaming download or upload body.
+///
+/// Proxies are free to split or merge the chunks of a streaming response, so
+/// every frame carries its own length.
+#[derive(Debug, PartialEq)]
+pub enum StreamFrame {
+    /// Data starting at the given stream offset.
+    Data(u64, Vec<u8>),
+
+    /// An EOF at the given stream offset.
+    Eof(u64),
+
+    /// A fatal error for the session.
+    Error(String),
+
+    /// The MAC of the frame after this one, on sessions which sign their
+    /// requests.
+    Mac(Vec<u8>),
+
+    /// The connection was reset on the sender's side, so the session is
+    /// over and undelivered data is thrown away.
+    Reset
+}
+
+const HEADER_SIZE: usize = 13;
+
+impl StreamFrame {
+    /// Encode the frame as a type byte, an 8-byte offset, a 4-byte length,
+    /// and the payload.
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, offset, payload) = match *self {
+            StreamFrame::Data(offset, ref data) => (1, offset, &data[..]),
+            StreamFrame::Eof(offset) => (0, offset, &[][..]),
+            StreamFrame::Error(ref msg) => (2, 0, msg.as_bytes()),
+            StreamFrame::Mac(ref mac) => (3, 0, &mac[..]),
+            StreamFrame::Reset => (4, 0, &[][..])
+        };
+        let mut res = Vec::with_capacity(HEADER_SIZE + payload.len());
+        res.push(kind);
+        res.extend_from_slice(&encode_offset(offset));
+        res.extend_from_slice(&encode_offset(payload.len() as u64)[4..]);
+        res.extend_from_slice(payload);
+        res
+    }
+}
+
+/// Incrementally decode StreamFrames from arbitrary chunks of a body.
+pub struct StreamDecoder {
+    buffer: Vec<u8>,
+    max_frame_size: usize
+}
+
+impl StreamDecoder {
+    /// Create a decoder which refuses frames with payloads larger than
+    /// `max_frame_size`, rather than buffering them.
+    pub fn new(max_frame_size: usize) -> StreamDecoder {
+        StreamDecoder{buffer: Vec::new(), max_frame_size}
+    }
+
+    /// Add more bytes from the body.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Get the next complete frame, if there is one.
+    pub fn next_frame(&mut self) -> Result<Option<StreamFrame>, Error> {
+        if self.buffer.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let offset = decode_offset(&self.buffer[1..9]);
+        let size = decode_offset(&self.buffer[9..HEADER_SIZE]) as usize;
+        if size > self.max_frame_size {
+            return Err(Error::Protocol(format!("frame of {} bytes is too large", size)));
+        }
+        if self.buffer.len() < HEADER_SIZE + size {
+            return Ok(None);
+        }
+        let payload = self.buffer[HEADER_SIZE..HEADER_SIZE + size].to_vec();
+        let kind = self.buffer[0];
+        self.buffer.drain(..HEADER_SIZE + size);
+        match kind {
+            0 => Ok(Some(StreamFrame::Eof(offset))),
+            1 => Ok(Some(StreamFrame::Data(offset, payload))),
+            2 => Ok(Some(StreamFrame::Error(String::from_utf8_lossy(&payload).into_owned()))),
+            3 => Ok(Some(StreamFrame::Mac(payload))),
+            4 => Ok(Some(StreamFrame::Reset)),
+            _ => Err(Error::Protocol(format!("unknown frame type: {}", kind)))
+        }
+    }
+}
+
+/// Encode a stream offset as 8 big-endian bytes.
+pub fn encode_offset(offset: u64) -> [u8; 8] {
+    let mut res = [0u8; 8];
+    for (i, b) in res.iter_mut().enumerate() {
+        *b = (offset >> (8 * (7 - i))) as u8;
+    }
+    res
+}
+
+/// Decode a big-endian integer of up to 8 bytes.
+pub fn decode_offset(data: &[u8]) -> u64 {
+    data.iter().fold(0u64, |acc, &b| (acc << 8) | u64::from(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames() -> Vec<StreamFrame> {
+        vec![
+            StreamFrame::Data(1 << 40, b"hello".to_vec()),
+            StreamFrame::Data(3, Vec::new()),
+            StreamFrame::Eof(12345),
+            StreamFrame::Error("upstream failed".to_owned()),
+            StreamFrame::Mac(vec![7; 32]),
+            StreamFrame::Reset
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut decoder = StreamDecoder::new(1024);
+        for frame in frames() {
+            decoder.push(&frame.encode());
+            assert_eq!(decoder.next_frame().unwrap(), Some(frame));
+        }
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn split_and_merged_chunks() {
+        let data = frames().iter().flat_map(StreamFrame::encode).collect::<Vec<_>>();
+        let mut decoder = StreamDecoder::new(1024);
+        let mut decoded = Vec::new();
+        for chunk in data.chunks(5) {
+            decoder.push(chunk);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded, frames());
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let data = StreamFrame::Data(0, b"hello".to_vec()).encode();
+        let mut decoder = StreamDecoder::new(1024);
+        decoder.push(&data[..HEADER_SIZE - 1]);
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        decoder.push(&data[HEADER_SIZE - 1..data.len() - 1]);
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        decoder.push(&data[data.len() - 1..]);
+        assert_eq!(decoder.next_frame().unwrap(), Some(StreamFrame::Data(0, b"hello".to_vec())));
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let data = StreamFrame::Data(0, vec![0; 6]).encode();
+        let mut decoder = StreamDecoder::new(5);
+        decoder.push(&data[..HEADER_SIZE]);
+        assert!(decoder.next_frame().is_err());
+
+        let mut decoder = StreamDecoder::new(6);
+        decoder.push(&data);
+        assert_eq!(decoder.next_frame().unwrap(), Some(StreamFrame::Data(0, vec![0; 6])));
+    }
+
+    #[test]
+    fn unknown_frame_type() {
+        let mut data = StreamFrame::Eof(0).encode();
+        data[0] = 9;
+        let mut decoder = StreamDecoder::new(1024);
+        decoder.push(&data);
+        assert!(decoder.next_frame().is_err());
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(encode_offset(0x0102030405060708), [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(decode_offset(&[1, 2, 3, 4, 5, 6, 7, 8]), 0x0102030405060708);
+        assert_eq!(decode_offset(&[1, 2]), 0x102);
+        assert_eq!(decode_offset(&encode_offset(u64::MAX)), u64::MAX);
+    }
+}
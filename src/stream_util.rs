@@ -0,0 +1,39 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::rc::Rc;
+
+use futures::{Async, Poll};
+use tokio_core::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// A shared handle to a connection.
+///
+/// Unlike a TcpStream, shutting it down actually closes the write side of the
+/// connection.
+pub struct SharedStream(pub Rc<TcpStream>);
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+impl AsyncRead for SharedStream {}
+
+impl AsyncWrite for SharedStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.0.shutdown(Shutdown::Write)?;
+        Ok(Async::Ready(()))
+    }
+}
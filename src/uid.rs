@@ -1,14 +1,18 @@
-use std::fmt::Write;
+use rand::{OsRng, Rng};
 
-use rand::thread_rng;
-use rand::distributions::{Range, IndependentSample};
+use keys::to_hex;
 
+/// Create a random 32-character hex ID.
+///
+/// Session IDs are all it takes to use a session, so they come from the
+/// operating system's secure random number generator.
 pub fn generate_session_id() -> String {
-    let mut res = String::new();
-    let mut rng = thread_rng();
-    let range = Range::new(0u8, 16u8);
-    for _ in 0..32 {
-        write!(res, "{:x}", range.ind_sample(&mut rng)).unwrap();
-    }
-    res
+    let mut bytes = [0u8; 16];
+    random_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Fill a buffer from the operating system's secure random number generator.
+pub fn random_bytes(bytes: &mut [u8]) {
+    OsRng::new().expect("no secure random number generator").fill_bytes(bytes);
 }
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+
+use rand::{Rng, thread_rng};
+use sha1::Sha1;
+
+use error::Error;
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The largest message we are willing to buffer.
+pub const MAX_MESSAGE_SIZE: usize = 1 << 24;
+
+/// A complete WebSocket message.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Binary(Vec<u8>),
+    Text(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close
+}
+
+impl Message {
+    /// Encode the message as a single frame.
+    ///
+    /// Clients must mask their frames, while servers must not.
+    pub fn encode(&self, masked: bool) -> Vec<u8> {
+        let (opcode, payload): (u8, &[u8]) = match *self {
+            Message::Text(ref x) => (1, x),
+            Message::Binary(ref x) => (2, x),
+            Message::Close => (8, &[]),
+            Message::Ping(ref x) => (9, x),
+            Message::Pong(ref x) => (10, x)
+        };
+        let mut res = vec![0x80 | opcode];
+        let mask_bit = if masked { 0x80 } else { 0 };
+        if payload.len() < 126 {
+            res.push(mask_bit | payload.len() as u8);
+        } else if payload.len() < 65536 {
+            res.push(mask_bit | 126);
+            res.push((payload.len() >> 8) as u8);
+            res.push(payload.len() as u8);
+        } else {
+            res.push(mask_bit | 127);
+            for i in (0..8).rev() {
+                res.push((payload.len() as u64 >> (8 * i)) as u8);
+            }
+        }
+        if masked {
+            let mut mask = [0u8; 4];
+            thread_rng().fill_bytes(&mut mask);
+            res.extend_from_slice(&mask);
+            res.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        } else {
+            res.extend_from_slice(payload);
+        }
+        res
+    }
+}
+
+/// Incrementally decode WebSocket messages from a byte stream.
+#[derive(Default)]
+pub struct MessageDecoder {
+    buffer: Vec<u8>,
+    fragments: Option<(u8, Vec<u8>)>
+}
+
+impl MessageDecoder {
+    pub fn new() -> MessageDecoder {
+        MessageDecoder{buffer: Vec::new(), fragments: None}
+    }
+
+    /// Add more bytes from the connection.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Get the next complete message, if there is one.
+    pub fn next_message(&mut self) -> Result<Option<Message>, Error> {
+        loop {
+            let (fin, opcode, payload) = match self.next_frame()? {
+                Some(x) => x,
+                None => return Ok(None)
+            };
+            let (opcode, payload) = if opcode == 0 {
+                let (first_opcode, mut data) = self.fragments.take()
+                    .ok_or_else(|| Error::Protocol("unexpected continuation frame".to_owned()))?;
+                data.extend(payload);
+                if data.len() > MAX_MESSAGE_SIZE {
+                    return Err(Error::Protocol("message too large".to_owned()));
+                }
+                if !fin {
+                    self.fragments = Some((first_opcode, data));
+                    continue;
+                }
+                (first_opcode, data)
+            } else if !fin && opcode < 8 {
+                self.fragments = Some((opcode, payload));
+                continue;
+            } else {
+                (opcode, payload)
+            };
+            return match opcode {
+                1 => Ok(Some(Message::Text(payload))),
+                2 => Ok(Some(Message::Binary(payload))),
+                8 => Ok(Some(Message::Close)),
+                9 => Ok(Some(Message::Ping(payload))),
+                10 => Ok(Some(Message::Pong(payload))),
+                _ => Err(Error::Protocol(format!("unknown opcode: {}", opcode)))
+            };
+        }
+    }
+
+    fn next_frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>, Error> {
+        if self.buffer.len() < 2 {
+            return Ok(None);
+        }
+        let fin = self.buffer[0] & 0x80 != 0;
+        let opcode = self.buffer[0] & 0xf;
+        let masked = self.buffer[1] & 0x80 != 0;
+        let (size, mut header_size) = match self.buffer[1] & 0x7f {
+            126 => {
+                if self.buffer.len() < 4 {
+                    return Ok(None);
+                }
+                ((usize::from(self.buffer[2]) << 8) | usize::from(self.buffer[3]), 4)
+            },
+            127 => {
+                if self.buffer.len() < 10 {
+                    return Ok(None);
+                }
+                let size = self.buffer[2..10].iter()
+                    .fold(0u64, |acc, &b| (acc << 8) | u64::from(b));
+                if size > MAX_MESSAGE_SIZE as u64 {
+                    return Err(Error::Protocol("message too large".to_owned()));
+                }
+                (size as usize, 10)
+            },
+            x => (x as usize, 2)
+        };
+        let mut mask = None;
+        if masked {
+            if self.buffer.len() < header_size + 4 {
+                return Ok(None);
+            }
+            mask = Some([self.buffer[header_size], self.buffer[header_size + 1],
+                self.buffer[header_size + 2], self.buffer[header_size + 3]]);
+            header_size += 4;
+        }
+        if self.buffer.len() < header_size + size {
+            return Ok(None);
+        }
+        let mut payload = self.buffer[header_size..header_size + size].to_vec();
+        self.buffer.drain(..header_size + size);
+        if let Some(mask) = mask {
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+        }
+        Ok(Some((fin, opcode, payload)))
+    }
+}
+
+/// The start line and headers of an HTTP request or response.
+pub struct HttpHead {
+    pub start_line: String,
+    headers: HashMap<String, String>
+}
+
+impl HttpHead {
+    /// Parse the head at the start of a buffer.
+    ///
+    /// Returns the head and its size, or None if the head is incomplete.
+    pub fn parse(data: &[u8]) -> Option<(HttpHead, usize)> {
+        let end = data.windows(4).position(|x| x == b"\r\n\r\n")?;
+        let text = String::from_utf8_lossy(&data[..end]).into_owned();
+        let mut lines = text.split("\r\n");
+        let start_line = lines.next()?.to_owned();
+        let headers = lines.filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next()?.trim().to_lowercase();
+            let value = parts.next()?.trim().to_owned();
+            Some((name, value))
+        }).collect();
+        Some((HttpHead{start_line, headers}, end + 4))
+    }
+
+    /// Look up a header by its case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|x| x.as_str())
+    }
+
+    /// Check if a header contains a comma-separated token.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name).map(|value| {
+            value.split(',').any(|x| x.trim().eq_ignore_ascii_case(token))
+        }).unwrap_or(false)
+    }
+
+    /// Check if this is a request to upgrade to a WebSocket.
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.has_token("upgrade", "websocket") && self.header("sec-websocket-key").is_some()
+    }
+
+    /// Get the path of a request, without the scheme and host of an
+    /// absolute-form request target.
+    pub fn path(&self) -> Option<&str> {
+        let target = self.start_line.split(' ').nth(1)?;
+        if target.starts_with('/') {
+            Some(target)
+        } else {
+            let rest = target.split_once("://")?.1;
+            rest.find('/').map(|i| &rest[i..])
+        }
+    }
+
+    /// Get the status code of a response.
+    pub fn status(&self) -> Option<u16> {
+        self.start_line.split(' ').nth(1)?.parse().ok()
+    }
+}
+
+/// Generate a random Sec-WebSocket-Key.
+pub fn websocket_key() -> String {
+    let mut key = [0u8; 16];
+    thread_rng().fill_bytes(&mut key);
+    base64_encode(&key)
+}
+
+/// Compute the Sec-WebSocket-Accept value for a Sec-WebSocket-Key.
+pub fn websocket_accept(key: &str) -> String {
+    let mut sh = Sha1::new();
+    sh.update(key.as_bytes());
+    sh.update(HANDSHAKE_GUID.as_bytes());
+    base64_encode(&sh.digest().bytes())
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut res = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |acc, (i, &b)| {
+            acc | (u32::from(b) << (16 - 8 * i))
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                res.push(BASE64_CHARS[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                res.push('=');
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Binary(vec![1, 2, 3]),
+            Message::Text(b"hello".to_vec()),
+            Message::Binary(vec![5; 300]),
+            Message::Binary(vec![6; 70000]),
+            Message::Ping(b"ping".to_vec()),
+            Message::Pong(Vec::new()),
+            Message::Close
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for &masked in &[false, true] {
+            let mut decoder = MessageDecoder::new();
+            for message in messages() {
+                decoder.push(&message.encode(masked));
+                assert_eq!(decoder.next_message().unwrap(), Some(message));
+            }
+            assert_eq!(decoder.next_message().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn truncated_input() {
+        for message in messages() {
+            let data = message.encode(true);
+            let mut decoder = MessageDecoder::new();
+            for b in &data[..data.len() - 1] {
+                decoder.push(&[*b]);
+                assert_eq!(decoder.next_message().unwrap(), None);
+            }
+            decoder.push(&data[data.len() - 1..]);
+            assert_eq!(decoder.next_message().unwrap(), Some(message));
+        }
+    }
+
+    #[test]
+    fn fragmented_message() {
+        let mut decoder = MessageDecoder::new();
+        decoder.push(&[0x02, 2, 1, 2]);
+        // Control frames may come between fragments.
+        decoder.push(&Message::Ping(b"x".to_vec()).encode(false));
+        decoder.push(&[0x00, 1, 3, 0x80, 1, 4]);
+        assert_eq!(decoder.next_message().unwrap(), Some(Message::Ping(b"x".to_vec())));
+        assert_eq!(decoder.next_message().unwrap(), Some(Message::Binary(vec![1, 2, 3, 4])));
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let mut decoder = MessageDecoder::new();
+        decoder.push(&[0x80, 1, 0]);
+        assert!(decoder.next_message().is_err());
+
+        let mut decoder = MessageDecoder::new();
+        decoder.push(&[0x83, 0]);
+        assert!(decoder.next_message().is_err());
+
+        let mut decoder = MessageDecoder::new();
+        decoder.push(&[0x82, 127, 0, 0, 0, 0, 0x10, 0, 0, 0]);
+        assert!(decoder.next_message().is_err());
+    }
+
+    #[test]
+    fn accept_key() {
+        // The example from RFC 6455.
+        assert_eq!(websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(websocket_key().len(), 24);
+    }
+}